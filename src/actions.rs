@@ -0,0 +1,206 @@
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use reqwest::Client;
+
+use crate::telegram_api::{
+    ban_chat_member, delete_message, lift_chat_member_restrictions, restrict_chat_member, send_message_with_keyboard,
+    unban_chat_member,
+};
+
+/// Префикс callback_data кнопки «Не спам» под предупреждением: `fp:<chat_id>:<message_id>:<user_id>`
+const FALSE_POSITIVE_CALLBACK_PREFIX: &str = "fp";
+
+/// Действие, применяемое к сообщению, признанному спамом
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpamAction {
+    /// Ответить в чат предупреждением «СПАМ (N%)»
+    Warn,
+    /// Удалить сообщение
+    Delete,
+    /// Запретить отправителю писать на `mute_minutes` минут
+    Mute,
+    /// Забанить отправителя навсегда
+    Ban,
+    /// Удалить отправителя из чата без постоянного бана
+    Kick,
+}
+
+impl FromStr for SpamAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "warn" => Ok(SpamAction::Warn),
+            "delete" => Ok(SpamAction::Delete),
+            "mute" => Ok(SpamAction::Mute),
+            "ban" => Ok(SpamAction::Ban),
+            "kick" => Ok(SpamAction::Kick),
+            other => anyhow::bail!("Неизвестное действие '{other}' (допустимо: warn, delete, mute, ban, kick)"),
+        }
+    }
+}
+
+/// Разбирает список действий через запятую, например `delete,mute`.
+pub fn parse_action_list(value: &str) -> Result<Vec<SpamAction>> {
+    value
+        .split(',')
+        .filter(|a| !a.trim().is_empty())
+        .map(SpamAction::from_str)
+        .collect()
+}
+
+/// Самый долгий мьют: более долгое ограничение Telegram считает бессрочным
+const MAX_MUTE_MINUTES: u32 = 366 * 24 * 60;
+
+/// Разбирает длительность мьюта в минутах. Ноль отклоняется: Telegram считает ограничение
+/// меньше 30 секунд бессрочным, и мьют превратился бы в вечный.
+pub fn parse_mute_minutes(value: &str) -> Result<u32> {
+    let minutes: u32 = value.trim().parse()?;
+    if minutes == 0 || minutes > MAX_MUTE_MINUTES {
+        anyhow::bail!("Длительность мьюта должна быть от 1 до {MAX_MUTE_MINUTES} минут (366 дней), получено {minutes}");
+    }
+    Ok(minutes)
+}
+
+/// Спам-сообщение, к которому применяются действия
+#[derive(Debug, Clone, Copy)]
+pub struct SpamTarget {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub user_id: i64,
+}
+
+/// Куда отправить кнопку «Не спам», если предупреждение в сам чат не публикуется
+#[derive(Debug, Clone, Copy)]
+pub struct UndoNotice<'a> {
+    pub chat_id: i64,
+    /// Текст с названием чата и сообщением: вне исходного чата без них непонятно, что отменять
+    pub text: &'a str,
+}
+
+/// Применяет к спам-сообщению действия из политики чата.
+/// Если боту не хватает прав хотя бы на одно действие, откатывается к предупреждению в чат,
+/// как это было до появления политики действий. Без предупреждения кнопка «Не спам»
+/// отправляется по `undo`, чтобы ошибочное срабатывание можно было отменить.
+pub async fn apply_spam_actions(
+    client: &Client,
+    base_url: &str,
+    target: SpamTarget,
+    actions: &[SpamAction],
+    mute_minutes: u32,
+    warn_text: &str,
+    undo: Option<UndoNotice<'_>>,
+) -> Result<()> {
+    let SpamTarget { chat_id, message_id, user_id } = target;
+    let mut failed: bool = false;
+
+    for action in actions {
+        let result: Result<()> = match action {
+            SpamAction::Warn => continue,
+            SpamAction::Delete => delete_message(client, base_url, chat_id, message_id).await,
+            SpamAction::Mute => {
+                let until: i64 = unix_now() + i64::from(mute_minutes) * 60;
+                restrict_chat_member(client, base_url, chat_id, user_id, until).await
+            }
+            SpamAction::Ban => ban_chat_member(client, base_url, chat_id, user_id, false).await,
+            SpamAction::Kick => {
+                match ban_chat_member(client, base_url, chat_id, user_id, false).await {
+                    Ok(()) => unban_chat_member(client, base_url, chat_id, user_id).await,
+                    Err(err) => Err(err),
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => log::info!("Действие {action:?} применено к пользователю {user_id} в чате {chat_id}"),
+            Err(err) => {
+                log::warn!("Не удалось применить {action:?} в чате {chat_id}: {err:?}");
+                failed = true;
+            }
+        }
+    }
+
+    let keyboard: serde_json::Value = serde_json::json!([[{
+        "text": "Не спам",
+        "callback_data": format!("{FALSE_POSITIVE_CALLBACK_PREFIX}:{chat_id}:{message_id}:{user_id}"),
+    }]]);
+    if failed || actions.contains(&SpamAction::Warn) {
+        send_message_with_keyboard(client, base_url, chat_id, warn_text, keyboard, Some(message_id)).await?;
+    } else if let Some(undo) = undo {
+        send_message_with_keyboard(client, base_url, undo.chat_id, undo.text, keyboard, None).await?;
+    }
+
+    Ok(())
+}
+
+/// Отменяет обратимые действия после ошибочного срабатывания: снимает мьют и бан.
+/// Удалённое сообщение восстановить нельзя.
+pub async fn revert_spam_actions(client: &Client, base_url: &str, target: SpamTarget, actions: &[SpamAction]) {
+    let SpamTarget { chat_id, user_id, .. } = target;
+    for action in actions {
+        let result: Result<()> = match action {
+            SpamAction::Mute => lift_chat_member_restrictions(client, base_url, chat_id, user_id).await,
+            SpamAction::Ban => unban_chat_member(client, base_url, chat_id, user_id).await,
+            SpamAction::Warn | SpamAction::Delete | SpamAction::Kick => continue,
+        };
+        if let Err(err) = result {
+            log::warn!("Не удалось отменить {action:?} для пользователя {user_id} в чате {chat_id}: {err:?}");
+        }
+    }
+}
+
+/// Разбирает callback_data кнопки «Не спам» под предупреждением.
+pub fn parse_false_positive_callback(data: &str) -> Option<SpamTarget> {
+    let mut parts = data.split(':');
+    if parts.next()? != FALSE_POSITIVE_CALLBACK_PREFIX {
+        return None;
+    }
+    let chat_id: i64 = parts.next()?.parse().ok()?;
+    let message_id: i64 = parts.next()?.parse().ok()?;
+    let user_id: i64 = parts.next()?.parse().ok()?;
+    Some(SpamTarget { chat_id, message_id, user_id })
+}
+
+/// Текущее время в секундах unix time
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mute_minutes_must_be_a_real_duration() {
+        assert_eq!(parse_mute_minutes("60").unwrap(), 60);
+        assert_eq!(parse_mute_minutes(" 1 ").unwrap(), 1);
+        assert_eq!(parse_mute_minutes("527040").unwrap(), MAX_MUTE_MINUTES);
+        assert!(parse_mute_minutes("0").is_err());
+        assert!(parse_mute_minutes("527041").is_err());
+        assert!(parse_mute_minutes("-5").is_err());
+        assert!(parse_mute_minutes("час").is_err());
+    }
+
+    #[test]
+    fn action_list_is_parsed_in_order() {
+        assert_eq!(parse_action_list("delete, Mute,").unwrap(), vec![SpamAction::Delete, SpamAction::Mute]);
+        assert!(parse_action_list("").unwrap().is_empty());
+        assert!(parse_action_list("delete,shout").is_err());
+    }
+
+    #[test]
+    fn false_positive_callback_round_trip() {
+        let target: SpamTarget = parse_false_positive_callback("fp:-1001234567890:42:777").unwrap();
+        assert_eq!((target.chat_id, target.message_id, target.user_id), (-1001234567890, 42, 777));
+        assert!(parse_false_positive_callback("cp:-100:42:777").is_none());
+        assert!(parse_false_positive_callback("fp:-100:42").is_none());
+        assert!(parse_false_positive_callback("fp:-100:x:777").is_none());
+    }
+}
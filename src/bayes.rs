@@ -0,0 +1,365 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    sync::{Mutex, RwLock},
+};
+
+use crate::spam_checker::{ClassifyInput, SpamClassifier, SpamVerdict};
+
+/// Как статистический классификатор используется вместе с LLM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BayesMode {
+    /// Не используется, но продолжает обучаться на вердиктах модераторов
+    Off,
+    /// Уверенные оценки выносятся без LLM, сомнительные уходят в LLM
+    Prefilter,
+    /// Используется, только если LLM не ответила
+    Fallback,
+    /// Единственный классификатор
+    Only,
+}
+
+impl FromStr for BayesMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(BayesMode::Off),
+            "prefilter" => Ok(BayesMode::Prefilter),
+            "fallback" => Ok(BayesMode::Fallback),
+            "only" => Ok(BayesMode::Only),
+            other => anyhow::bail!("Неизвестный режим '{other}' (допустимо: off, prefilter, fallback, only)"),
+        }
+    }
+}
+
+/// Модель наивного Байеса по словам и символьным триграммам.
+/// Каждый токен учитывается один раз на сообщение, чтобы длинные сообщения не перевешивали.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct BayesModel {
+    spam_docs: u32,
+    ham_docs: u32,
+    spam_tokens: HashMap<String, u32>,
+    ham_tokens: HashMap<String, u32>,
+}
+
+impl BayesModel {
+    /// Учитывает одно размеченное сообщение
+    pub fn train(&mut self, text: &str, is_spam: bool) {
+        let (docs, tokens) = if is_spam {
+            (&mut self.spam_docs, &mut self.spam_tokens)
+        } else {
+            (&mut self.ham_docs, &mut self.ham_tokens)
+        };
+        *docs += 1;
+        for token in tokenize(text) {
+            *tokens.entry(token).or_insert(0) += 1;
+        }
+    }
+
+    /// Вероятность спама от 0 до 1 или `None`, пока в каком-то классе меньше `min_examples` примеров.
+    pub fn spam_probability(&self, text: &str, min_examples: u32) -> Option<f64> {
+        if self.spam_docs < min_examples || self.ham_docs < min_examples {
+            return None;
+        }
+
+        let spam_docs: f64 = f64::from(self.spam_docs);
+        let ham_docs: f64 = f64::from(self.ham_docs);
+        let mut log_odds: f64 = (spam_docs / ham_docs).ln();
+
+        // Бернуллиевская модель со сглаживанием Лапласа по присутствию токена
+        for token in tokenize(text) {
+            let in_spam: f64 = f64::from(self.spam_tokens.get(&token).copied().unwrap_or(0));
+            let in_ham: f64 = f64::from(self.ham_tokens.get(&token).copied().unwrap_or(0));
+            if in_spam == 0.0 && in_ham == 0.0 {
+                continue;
+            }
+            let p_spam: f64 = (in_spam + 1.0) / (spam_docs + 2.0);
+            let p_ham: f64 = (in_ham + 1.0) / (ham_docs + 2.0);
+            log_odds += (p_spam / p_ham).ln();
+        }
+
+        Some(1.0 / (1.0 + (-log_odds).exp()))
+    }
+
+    pub fn examples(&self) -> (u32, u32) {
+        (self.spam_docs, self.ham_docs)
+    }
+}
+
+/// Разбивает текст на слова (`w:`) и символьные триграммы внутри слов (`c:`)
+fn tokenize(text: &str) -> HashSet<String> {
+    let lower: String = text.to_lowercase();
+    let mut tokens: HashSet<String> = HashSet::new();
+    for word in lower.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+        tokens.insert(format!("w:{word}"));
+        let chars: Vec<char> = format!(" {word} ").chars().collect();
+        for gram in chars.windows(3) {
+            tokens.insert(format!("c:{}", gram.iter().collect::<String>()));
+        }
+    }
+    tokens
+}
+
+/// Статистический классификатор с моделью, сохраняемой на диск после каждого обучения
+pub struct BayesClassifier {
+    model: RwLock<BayesModel>,
+    /// Держится от обучения до переименования файла: параллельные сохранения не пишут в один
+    /// временный файл, и на диске остаётся самая новая модель
+    save_lock: Mutex<()>,
+    path: PathBuf,
+    min_examples: u32,
+}
+
+impl BayesClassifier {
+    /// Загружает модель из файла или начинает с пустой, если файла нет или он повреждён.
+    pub async fn load(path: PathBuf, min_examples: u32) -> Self {
+        let model: BayesModel = match fs::read_to_string(&path).await {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                log::warn!("Не удалось разобрать модель {}: {}. Начинаю с пустой.", path.display(), e);
+                BayesModel::default()
+            }),
+            Err(_) => BayesModel::default(),
+        };
+        let (spam, ham) = model.examples();
+        log::info!("Статистическая модель: {spam} спам / {ham} не-спам примеров");
+        Self { model: RwLock::new(model), save_lock: Mutex::new(()), path, min_examples }
+    }
+
+    /// Дообучает модель на подтверждённом вердикте и сохраняет её на диск.
+    pub async fn train(&self, text: &str, is_spam: bool) -> Result<()> {
+        let _saving: tokio::sync::MutexGuard<'_, ()> = self.save_lock.lock().await;
+        let serialized: String = {
+            let mut model: tokio::sync::RwLockWriteGuard<'_, BayesModel> = self.model.write().await;
+            model.train(text, is_spam);
+            serde_json::to_string(&*model)?
+        };
+
+        // Пишем во временный файл и переименовываем, чтобы не оставить повреждённую модель
+        let tmp: PathBuf = self.path.with_extension("tmp");
+        fs::write(&tmp, serialized).await?;
+        fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+
+    pub async fn spam_probability(&self, text: &str) -> Option<f64> {
+        self.model.read().await.spam_probability(text, self.min_examples)
+    }
+
+    /// Число обучающих примеров: (спам, не-спам)
+    pub async fn examples(&self) -> (u32, u32) {
+        self.model.read().await.examples()
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for BayesClassifier {
+    fn name(&self) -> &str {
+        "bayes"
+    }
+
+    async fn classify(&self, input: &ClassifyInput) -> Result<SpamVerdict> {
+        let p: f64 = self
+            .spam_probability(&input.text)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Статистическая модель ещё не обучена"))?;
+        Ok(SpamVerdict {
+            spam_score: (p * 100.0).round() as u8,
+            notes: format!("статистика: p={p:.2}"),
+            classifier: self.name().to_string(),
+            prompt_version: None,
+        })
+    }
+}
+
+/// Связка статистического классификатора с LLM согласно `BayesMode`
+pub struct StatisticalClassifier {
+    pub bayes: Arc<BayesClassifier>,
+    pub llm: Option<Box<dyn SpamClassifier>>,
+    pub mode: BayesMode,
+    /// Оценки статистики не выше этой считаются уверенным не-спамом в режиме prefilter
+    pub ham_below: u8,
+    /// Оценки статистики не ниже этой считаются уверенным спамом в режиме prefilter
+    pub spam_above: u8,
+}
+
+#[async_trait]
+impl SpamClassifier for StatisticalClassifier {
+    fn name(&self) -> &str {
+        "statistical"
+    }
+
+    async fn classify(&self, input: &ClassifyInput) -> Result<SpamVerdict> {
+        let Some(llm) = self.llm.as_ref().filter(|_| self.mode != BayesMode::Only) else {
+            return self.bayes.classify(input).await;
+        };
+
+        match self.mode {
+            BayesMode::Prefilter => {
+                if let Ok(v) = self.bayes.classify(input).await
+                    && (v.spam_score <= self.ham_below || v.spam_score >= self.spam_above)
+                {
+                    return Ok(v);
+                }
+                llm.classify(input).await
+            }
+            BayesMode::Fallback => match llm.classify(input).await {
+                Ok(v) => Ok(v),
+                Err(err) => {
+                    log::warn!("{} не ответил, использую статистику: {err:?}", llm.name());
+                    self.bayes.classify(input).await
+                }
+            },
+            BayesMode::Off | BayesMode::Only => llm.classify(input).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPAM: &[&str] = &[
+        "Заработок в крипте от 500$ в день, пиши в лс",
+        "Лёгкий заработок на крипте, пиши в лс",
+        "Крипта даёт заработок каждый день, пиши",
+    ];
+    const HAM: &[&str] = &[
+        "Кто-нибудь знает, во сколько завтра встреча?",
+        "Спасибо, встреча перенесена на завтра",
+        "Завтра встреча в офисе, не опаздывайте",
+    ];
+
+    fn trained() -> BayesModel {
+        let mut model: BayesModel = BayesModel::default();
+        SPAM.iter().for_each(|text| model.train(text, true));
+        HAM.iter().for_each(|text| model.train(text, false));
+        model
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tg_anti_spam_{}_{name}.json", std::process::id()))
+    }
+
+    async fn classifier(name: &str) -> Arc<BayesClassifier> {
+        let path: PathBuf = temp_path(name);
+        let _ = std::fs::remove_file(&path);
+        let bayes: BayesClassifier = BayesClassifier::load(path.clone(), 1).await;
+        for text in SPAM {
+            bayes.train(text, true).await.unwrap();
+        }
+        for text in HAM {
+            bayes.train(text, false).await.unwrap();
+        }
+        let _ = std::fs::remove_file(&path);
+        Arc::new(bayes)
+    }
+
+    /// Классификатор с заранее заданной оценкой; `None` — ошибка
+    struct Fixed(Option<u8>);
+
+    #[async_trait]
+    impl SpamClassifier for Fixed {
+        fn name(&self) -> &str {
+            "llm"
+        }
+
+        async fn classify(&self, _input: &ClassifyInput) -> Result<SpamVerdict> {
+            let score: u8 = self.0.ok_or_else(|| anyhow::anyhow!("нет ответа"))?;
+            Ok(SpamVerdict { spam_score: score, notes: String::new(), classifier: self.name().to_string(), prompt_version: None })
+        }
+    }
+
+    fn statistical(bayes: Arc<BayesClassifier>, llm: Option<u8>, mode: BayesMode) -> StatisticalClassifier {
+        StatisticalClassifier { bayes, llm: Some(Box::new(Fixed(llm))), mode, ham_below: 10, spam_above: 90 }
+    }
+
+    async fn classified_by(classifier: &StatisticalClassifier, text: &str) -> String {
+        classifier.classify(&ClassifyInput::for_tests(text)).await.unwrap().classifier
+    }
+
+    #[test]
+    fn tokenizes_words_and_trigrams_once() {
+        let tokens: HashSet<String> = tokenize("Да, ДА да!");
+        let expected: HashSet<String> = ["w:да", "c: да", "c:да "].into_iter().map(String::from).collect();
+        assert_eq!(tokens, expected);
+        assert!(tokenize(" ,.! ").is_empty());
+        assert!(tokenize("спам123").contains("w:спам123"));
+    }
+
+    #[test]
+    fn needs_min_examples_in_both_classes() {
+        let mut model: BayesModel = BayesModel::default();
+        model.train(SPAM[0], true);
+        model.train(SPAM[1], true);
+        model.train(HAM[0], false);
+        assert_eq!(model.spam_probability(SPAM[0], 2), None);
+        assert!(model.spam_probability(SPAM[0], 1).is_some());
+        assert_eq!(model.examples(), (2, 1));
+    }
+
+    #[test]
+    fn separates_trained_classes() {
+        let model: BayesModel = trained();
+        assert!(model.spam_probability("Заработок на крипте, пиши в лс", 3).unwrap() > 0.9);
+        assert!(model.spam_probability("Встреча завтра?", 3).unwrap() < 0.1);
+        // Незнакомые токены не сдвигают априорную вероятность
+        assert!((model.spam_probability("ёжик", 3).unwrap() - 0.5).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn saves_and_loads_model() {
+        let path: PathBuf = temp_path("bayes_roundtrip");
+        let _ = std::fs::remove_file(&path);
+        let bayes: BayesClassifier = BayesClassifier::load(path.clone(), 1).await;
+        assert_eq!(bayes.examples().await, (0, 0));
+        bayes.train(SPAM[0], true).await.unwrap();
+        bayes.train(HAM[0], false).await.unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let loaded: BayesClassifier = BayesClassifier::load(path.clone(), 1).await;
+        assert_eq!(loaded.examples().await, (1, 1));
+        assert_eq!(loaded.spam_probability(SPAM[1]).await, bayes.spam_probability(SPAM[1]).await);
+
+        std::fs::write(&path, "не json").unwrap();
+        let damaged: BayesClassifier = BayesClassifier::load(path.clone(), 1).await;
+        assert_eq!(damaged.examples().await, (0, 0));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn prefilter_decides_only_confident_scores() {
+        let classifier: StatisticalClassifier = statistical(classifier("bayes_prefilter").await, Some(50), BayesMode::Prefilter);
+        assert_eq!(classified_by(&classifier, "Заработок на крипте, пиши в лс").await, "bayes");
+        assert_eq!(classified_by(&classifier, "Встреча завтра?").await, "bayes");
+        assert_eq!(classified_by(&classifier, "ёжик").await, "llm");
+
+        // Необученная модель не мешает LLM
+        let path: PathBuf = temp_path("bayes_untrained");
+        let untrained: Arc<BayesClassifier> = Arc::new(BayesClassifier::load(path, 1).await);
+        let classifier: StatisticalClassifier = statistical(untrained, Some(50), BayesMode::Prefilter);
+        assert_eq!(classified_by(&classifier, "Заработок на крипте").await, "llm");
+    }
+
+    #[tokio::test]
+    async fn fallback_and_only_modes() {
+        let bayes: Arc<BayesClassifier> = classifier("bayes_modes").await;
+        let answering: StatisticalClassifier = statistical(bayes.clone(), Some(50), BayesMode::Fallback);
+        assert_eq!(classified_by(&answering, "Заработок на крипте").await, "llm");
+        let failing: StatisticalClassifier = statistical(bayes.clone(), None, BayesMode::Fallback);
+        assert_eq!(classified_by(&failing, "Заработок на крипте").await, "bayes");
+        let only: StatisticalClassifier = statistical(bayes.clone(), Some(50), BayesMode::Only);
+        assert_eq!(classified_by(&only, "Заработок на крипте").await, "bayes");
+        let off: StatisticalClassifier = statistical(bayes, Some(50), BayesMode::Off);
+        assert_eq!(classified_by(&off, "Заработок на крипте").await, "llm");
+    }
+}
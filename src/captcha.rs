@@ -0,0 +1,367 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use rand::{seq::SliceRandom, Rng};
+use reqwest::Client;
+
+use crate::{
+    actions::unix_now,
+    chat_settings::ChatConfig,
+    config::Config,
+    dispatcher::Dispatcher,
+    state::{chat_config, is_user_whitelisted, record_moderation, AppState},
+    storage::{ModerationEvent, PendingCaptcha},
+    telegram_api::{
+        answer_callback_query, ban_chat_member, delete_message, lift_chat_member_restrictions, restrict_chat_member,
+        send_message_with_keyboard, unban_chat_member, CallbackQuery, User,
+    },
+};
+
+/// Префикс callback_data кнопок проверки: `cp:<user_id>:<ответ>`
+const CAPTCHA_CALLBACK_PREFIX: &str = "cp";
+
+/// Как часто проверяются просроченные проверки
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Через сколько секунд повторить завершение проверки, если Telegram вернул ошибку
+const FINISH_RETRY_DELAY_SECS: i64 = 60;
+
+/// Эмодзи для проверки «выберите картинку» с названием в винительном падеже
+const EMOJIS: &[(&str, &str)] = &[
+    ("🍎", "яблоко"),
+    ("🐱", "кошку"),
+    ("🚗", "машину"),
+    ("🌵", "кактус"),
+    ("⚽", "мяч"),
+    ("🎸", "гитару"),
+    ("🐟", "рыбу"),
+    ("🔑", "ключ"),
+];
+
+/// Вид проверки новых участников
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptchaMode {
+    /// Проверка выключена
+    Off,
+    /// Нажать кнопку «Я не бот»
+    Button,
+    /// Решить пример на сложение
+    Arithmetic,
+    /// Выбрать названный эмодзи
+    Emoji,
+}
+
+impl FromStr for CaptchaMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(CaptchaMode::Off),
+            "button" => Ok(CaptchaMode::Button),
+            "arithmetic" => Ok(CaptchaMode::Arithmetic),
+            "emoji" => Ok(CaptchaMode::Emoji),
+            other => anyhow::bail!("Неизвестный режим проверки '{other}' (допустимо: off, button, arithmetic, emoji)"),
+        }
+    }
+}
+
+/// Вопрос проверки с вариантами ответа
+struct Challenge {
+    question: String,
+    options: Vec<String>,
+    answer: String,
+}
+
+impl Challenge {
+    fn generate(mode: CaptchaMode) -> Option<Self> {
+        let mut rng = rand::thread_rng();
+        let mut challenge: Challenge = match mode {
+            CaptchaMode::Off => return None,
+            CaptchaMode::Button => Challenge {
+                question: "нажмите кнопку ниже".to_string(),
+                options: vec!["Я не бот".to_string()],
+                answer: "Я не бот".to_string(),
+            },
+            CaptchaMode::Arithmetic => {
+                let (a, b): (u32, u32) = (rng.gen_range(1..10), rng.gen_range(1..10));
+                let answer: u32 = a + b;
+                let mut options: Vec<u32> = vec![answer];
+                while options.len() < 4 {
+                    let option: u32 = rng.gen_range(2..19);
+                    if !options.contains(&option) {
+                        options.push(option);
+                    }
+                }
+                Challenge {
+                    question: format!("сколько будет {a} + {b}?"),
+                    options: options.iter().map(u32::to_string).collect(),
+                    answer: answer.to_string(),
+                }
+            }
+            CaptchaMode::Emoji => {
+                let picked: Vec<&(&str, &str)> = EMOJIS.choose_multiple(&mut rng, 4).collect();
+                let (answer, name) = *picked[0];
+                Challenge {
+                    question: format!("выберите {name}"),
+                    options: picked.iter().map(|(emoji, _)| emoji.to_string()).collect(),
+                    answer: answer.to_string(),
+                }
+            }
+        };
+        challenge.options.shuffle(&mut rng);
+        Some(challenge)
+    }
+}
+
+/// Начинает проверку нового участника: ограничивает его и отправляет вопрос с кнопками.
+/// Пользователи из вайтлиста, боты и те, у кого проверка уже идёт, пропускаются.
+pub async fn start_captcha(client: &Client, base_url: &str, state: &AppState, chat: &ChatConfig, user: &User) -> Result<()> {
+    if user.is_bot || is_user_whitelisted(user.id, state).await? {
+        return Ok(());
+    }
+    let Some(challenge) = Challenge::generate(chat.captcha_mode) else {
+        return Ok(());
+    };
+
+    let mut pending: PendingCaptcha = PendingCaptcha {
+        chat_id: chat.chat_id,
+        user_id: user.id,
+        message_id: 0,
+        answer: challenge.answer.clone(),
+        expires_at: unix_now() + i64::from(chat.captcha_timeout_secs),
+        passed: false,
+    };
+    // Вступление приходит и служебным сообщением, и обновлением chat_member — проверка одна
+    {
+        let mut captchas = state.captchas.write().await;
+        if captchas.contains_key(&(chat.chat_id, user.id)) {
+            return Ok(());
+        }
+        captchas.insert((chat.chat_id, user.id), pending.clone());
+    }
+
+    if let Err(err) = restrict_chat_member(client, base_url, chat.chat_id, user.id, 0).await {
+        log::warn!("Не удалось ограничить нового участника {} в чате {}: {err:?}", user.id, chat.chat_id);
+        state.captchas.write().await.remove(&(chat.chat_id, user.id));
+        return Ok(());
+    }
+
+    let mention: String = user
+        .username
+        .as_ref()
+        .map(|u| format!("@{u}"))
+        .unwrap_or_else(|| user.first_name.clone());
+    let text: String = format!(
+        "{mention}, подтвердите, что вы не бот: {}. На ответ {} секунд.",
+        challenge.question, chat.captcha_timeout_secs
+    );
+    let keyboard: serde_json::Value = serde_json::json!([challenge
+        .options
+        .iter()
+        .map(|option| serde_json::json!({
+            "text": option,
+            "callback_data": format!("{CAPTCHA_CALLBACK_PREFIX}:{}:{option}", user.id),
+        }))
+        .collect::<Vec<_>>()]);
+    pending.message_id = match send_message_with_keyboard(client, base_url, chat.chat_id, &text, keyboard, None).await {
+        Ok(message_id) => message_id,
+        Err(err) => {
+            // Без вопроса участник не сможет пройти проверку — не оставляем его ограниченным
+            state.captchas.write().await.remove(&(chat.chat_id, user.id));
+            lift_chat_member_restrictions(client, base_url, chat.chat_id, user.id).await.ok();
+            return Err(err);
+        }
+    };
+
+    state.captchas.write().await.insert((chat.chat_id, user.id), pending.clone());
+    state.storage.save_captcha(pending).await?;
+    log::info!("Проверка нового участника {} в чате {}", user.id, chat.chat_id);
+    Ok(())
+}
+
+/// Разбирает callback_data кнопки проверки: id проверяемого пользователя и выбранный ответ
+pub fn parse_captcha_callback(data: &str) -> Option<(i64, &str)> {
+    let rest: &str = data.strip_prefix(CAPTCHA_CALLBACK_PREFIX)?.strip_prefix(':')?;
+    let (user_id, answer) = rest.split_once(':')?;
+    Some((user_id.parse().ok()?, answer))
+}
+
+/// Обрабатывает ответ на проверку. Отвечать может только сам проверяемый пользователь.
+pub async fn handle_captcha_callback(
+    client: &Client,
+    base_url: &str,
+    cq: &CallbackQuery,
+    user_id: i64,
+    answer: &str,
+    state: &AppState,
+) -> Result<()> {
+    let Some(chat_id) = cq.message.as_ref().map(|m| m.chat.id) else {
+        return Ok(());
+    };
+    if cq.from.id != user_id {
+        answer_callback_query(client, base_url, &cq.id, "Эта проверка не для вас").await.ok();
+        return Ok(());
+    }
+    let Some(pending) = state.captchas.read().await.get(&(chat_id, user_id)).cloned() else {
+        answer_callback_query(client, base_url, &cq.id, "Проверка уже завершена").await.ok();
+        return Ok(());
+    };
+
+    // После верного ответа, который не удалось применить, повторное нажатие только повторяет попытку
+    let passed: bool = pending.passed || pending.answer == answer;
+    answer_callback_query(client, base_url, &cq.id, if passed { "Добро пожаловать!" } else { "Неверно" }).await.ok();
+    finish_captcha(client, base_url, state, &pending, passed, if passed { "верный ответ" } else { "неверный ответ" })
+        .await
+}
+
+/// Завершает проверку: снимает ограничения или удаляет участника из чата.
+/// На время запросов проверка убирается из состояния, поэтому ответ и истечение времени не завершат её
+/// дважды. Из базы она удаляется только после успешного запроса, а при ошибке возвращается в состояние
+/// и завершается с тем же итогом через `FINISH_RETRY_DELAY_SECS` секунд.
+async fn finish_captcha(
+    client: &Client,
+    base_url: &str,
+    state: &AppState,
+    pending: &PendingCaptcha,
+    passed: bool,
+    reason: &str,
+) -> Result<()> {
+    let PendingCaptcha { chat_id, user_id, message_id, .. } = *pending;
+    let Some(mut claimed) = state.captchas.write().await.remove(&(chat_id, user_id)) else {
+        return Ok(());
+    };
+
+    let result: Result<()> = if passed {
+        lift_chat_member_restrictions(client, base_url, chat_id, user_id).await
+    } else {
+        match ban_chat_member(client, base_url, chat_id, user_id, false).await {
+            Ok(()) => unban_chat_member(client, base_url, chat_id, user_id).await,
+            Err(err) => Err(err),
+        }
+    };
+    if let Err(err) = result {
+        claimed.passed = passed;
+        claimed.expires_at = unix_now() + FINISH_RETRY_DELAY_SECS;
+        state.captchas.write().await.insert((chat_id, user_id), claimed.clone());
+        state.storage.save_captcha(claimed).await?;
+        return Err(err);
+    }
+    state.storage.remove_captcha(chat_id, user_id).await?;
+
+    if message_id != 0 {
+        delete_message(client, base_url, chat_id, message_id).await.ok();
+    }
+
+    log::info!(
+        "Проверка участника {user_id} в чате {chat_id}: {} ({reason})",
+        if passed { "пройдена" } else { "не пройдена, удалён" }
+    );
+    record_moderation(state, ModerationEvent {
+        chat_id,
+        user_id,
+        action: if passed { "captcha_passed" } else { "captcha_failed" }.to_string(),
+        notes: Some(reason.to_string()),
+        actor: Some("captcha".to_string()),
+        ..Default::default()
+    }).await;
+    Ok(())
+}
+
+/// Фоновая задача: удаляет из чатов участников, не ответивших на проверку вовремя.
+/// Проверки хранятся в базе, поэтому истёкшие во время простоя бота обрабатываются после запуска.
+pub async fn run_expiry_loop(dispatcher: Arc<Dispatcher>) {
+    loop {
+        tokio::time::sleep(EXPIRY_CHECK_INTERVAL).await;
+
+        let now: i64 = unix_now();
+        let expired: Vec<PendingCaptcha> = dispatcher
+            .state()
+            .captchas
+            .read()
+            .await
+            .values()
+            .filter(|c| c.expires_at <= now && c.message_id != 0)
+            .cloned()
+            .collect();
+
+        for pending in expired {
+            let state: &AppState = dispatcher.state();
+            let reason: &str = if pending.passed { "верный ответ, повтор" } else { "время вышло" };
+            if let Err(err) =
+                finish_captcha(dispatcher.client(), dispatcher.base_url(), state, &pending, pending.passed, reason).await
+            {
+                log::warn!("Не удалось завершить проверку участника {}: {err:?}", pending.user_id);
+            }
+        }
+    }
+}
+
+/// Обрабатывает вступивших участников: отсчитывает их испытательный срок заново
+/// и запускает проверку, если она включена в чате.
+pub async fn screen_new_members(
+    client: &Client,
+    base_url: &str,
+    state: &AppState,
+    config: &Config,
+    chat_id: i64,
+    users: &[&User],
+) -> Result<()> {
+    for user in users.iter().filter(|u| !u.is_bot) {
+        state.storage.mark_joined(user.id).await?;
+    }
+    let chat: ChatConfig = chat_config(state, config, chat_id).await;
+    if chat.captcha_mode == CaptchaMode::Off {
+        return Ok(());
+    }
+    for user in users {
+        start_captcha(client, base_url, state, &chat, user).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn parses_callback_data() {
+        assert_eq!(parse_captcha_callback("cp:42:Я не бот"), Some((42, "Я не бот")));
+        assert_eq!(parse_captcha_callback("cp:-1:🍎"), Some((-1, "🍎")));
+        assert_eq!(parse_captcha_callback("cp:42:"), Some((42, "")));
+    }
+
+    #[test]
+    fn rejects_foreign_callback_data() {
+        assert_eq!(parse_captcha_callback("fp:42:1"), None);
+        assert_eq!(parse_captcha_callback("cpx:42:1"), None);
+        assert_eq!(parse_captcha_callback("cp:abc:1"), None);
+        assert_eq!(parse_captcha_callback("cp:42"), None);
+    }
+
+    #[test]
+    fn challenges_contain_answer_and_fit_callback_data() {
+        assert!(Challenge::generate(CaptchaMode::Off).is_none());
+        for mode in [CaptchaMode::Button, CaptchaMode::Arithmetic, CaptchaMode::Emoji] {
+            for _ in 0..50 {
+                let challenge: Challenge = Challenge::generate(mode).unwrap();
+                assert!(challenge.options.contains(&challenge.answer));
+                let unique: HashSet<&String> = challenge.options.iter().collect();
+                assert_eq!(unique.len(), challenge.options.len());
+                for option in &challenge.options {
+                    // Telegram принимает callback_data не длиннее 64 байт
+                    let data: String = format!("{CAPTCHA_CALLBACK_PREFIX}:{}:{option}", i64::MIN);
+                    assert!(data.len() <= 64, "{data}");
+                    assert_eq!(parse_captcha_callback(&data), Some((i64::MIN, option.as_str())));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parses_captcha_mode() {
+        assert_eq!(" Emoji ".parse::<CaptchaMode>().unwrap(), CaptchaMode::Emoji);
+        assert!("math".parse::<CaptchaMode>().is_err());
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::{
+    actions::{parse_action_list, parse_mute_minutes, SpamAction},
+    captcha::CaptchaMode,
+    config::Config,
+    entities::parse_domain_list,
+    probation::ProbationMode,
+};
+
+/// Ключи настроек, которые можно переопределить для чата командой `/set`
+pub const SETTING_KEYS: &[&str] = &[
+    "spam_threshold",
+    "review_threshold",
+    "review_chat",
+    "ham_threshold",
+    "actions",
+    "mute_minutes",
+    "model",
+    "template",
+    "topic",
+    "prompt",
+    "language",
+    "notify",
+    "tag",
+    "allow_domains",
+    "block_domains",
+    "captcha",
+    "captcha_timeout",
+    "probation_messages",
+    "probation_hours",
+    "probation_mode",
+    "probation_threshold",
+];
+
+/// Переопределения настроек одного чата. `None` — используется глобальное значение.
+#[derive(Debug, Clone, Default)]
+pub struct ChatSettings {
+    pub spam_threshold: Option<u8>,
+    pub review_threshold: Option<u8>,
+    pub review_chat_id: Option<i64>,
+    pub ham_threshold: Option<u32>,
+    pub spam_actions: Option<Vec<SpamAction>>,
+    pub mute_minutes: Option<u32>,
+    pub model: Option<String>,
+    /// Шаблон промпта из `PROMPTS_DIR`
+    pub template: Option<String>,
+    /// Тема чата для подстановки `{topic}` шаблона
+    pub topic: Option<String>,
+    /// Дополнительные указания для LLM, например тематика чата
+    pub prompt: Option<String>,
+    /// Язык, на котором LLM пишет причину
+    pub language: Option<String>,
+    pub notify_user_id: Option<i64>,
+    pub tag_username: Option<String>,
+    pub allowed_domains: Option<Vec<String>>,
+    pub blocked_domains: Option<Vec<String>>,
+    pub captcha_mode: Option<CaptchaMode>,
+    pub captcha_timeout_secs: Option<u32>,
+    pub probation_messages: Option<u32>,
+    pub probation_hours: Option<u32>,
+    pub probation_mode: Option<ProbationMode>,
+    pub probation_threshold: Option<u8>,
+}
+
+impl ChatSettings {
+    /// Устанавливает значение по ключу из `SETTING_KEYS`, проверяя его корректность.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let value: &str = value.trim();
+        match key {
+            "spam_threshold" => self.spam_threshold = Some(parse_percent(value)?),
+            "review_threshold" => self.review_threshold = Some(parse_percent(value)?),
+            "review_chat" => self.review_chat_id = Some(value.parse()?),
+            "ham_threshold" => self.ham_threshold = Some(value.parse()?),
+            "actions" => self.spam_actions = Some(parse_action_list(value)?),
+            "mute_minutes" => self.mute_minutes = Some(parse_mute_minutes(value)?),
+            "model" => self.model = Some(non_empty(value)?),
+            "template" => self.template = Some(non_empty(value)?),
+            "topic" => self.topic = Some(non_empty(value)?),
+            "prompt" => self.prompt = Some(non_empty(value)?),
+            "language" => self.language = Some(non_empty(value)?),
+            "notify" => self.notify_user_id = Some(value.parse()?),
+            "tag" => self.tag_username = Some(non_empty(value.trim_start_matches('@'))?),
+            "allow_domains" => self.allowed_domains = Some(parse_domain_list(value)),
+            "block_domains" => self.blocked_domains = Some(parse_domain_list(value)),
+            "captcha" => self.captcha_mode = Some(value.parse()?),
+            "captcha_timeout" => self.captcha_timeout_secs = Some(value.parse()?),
+            "probation_messages" => self.probation_messages = Some(value.parse()?),
+            "probation_hours" => self.probation_hours = Some(value.parse()?),
+            "probation_mode" => self.probation_mode = Some(value.parse()?),
+            "probation_threshold" => self.probation_threshold = Some(parse_percent(value)?),
+            other => anyhow::bail!("Неизвестная настройка '{other}'. Доступны: {}", SETTING_KEYS.join(", ")),
+        }
+        Ok(())
+    }
+
+    /// Сбрасывает переопределение, возвращая глобальное значение.
+    pub fn unset(&mut self, key: &str) -> Result<()> {
+        match key {
+            "spam_threshold" => self.spam_threshold = None,
+            "review_threshold" => self.review_threshold = None,
+            "review_chat" => self.review_chat_id = None,
+            "ham_threshold" => self.ham_threshold = None,
+            "actions" => self.spam_actions = None,
+            "mute_minutes" => self.mute_minutes = None,
+            "model" => self.model = None,
+            "template" => self.template = None,
+            "topic" => self.topic = None,
+            "prompt" => self.prompt = None,
+            "language" => self.language = None,
+            "notify" => self.notify_user_id = None,
+            "tag" => self.tag_username = None,
+            "allow_domains" => self.allowed_domains = None,
+            "block_domains" => self.blocked_domains = None,
+            "captcha" => self.captcha_mode = None,
+            "captcha_timeout" => self.captcha_timeout_secs = None,
+            "probation_messages" => self.probation_messages = None,
+            "probation_hours" => self.probation_hours = None,
+            "probation_mode" => self.probation_mode = None,
+            "probation_threshold" => self.probation_threshold = None,
+            other => anyhow::bail!("Неизвестная настройка '{other}'. Доступны: {}", SETTING_KEYS.join(", ")),
+        }
+        Ok(())
+    }
+}
+
+/// Действующие настройки чата: глобальный `Config` с применёнными переопределениями
+#[derive(Debug, Clone)]
+pub struct ChatConfig {
+    pub chat_id: i64,
+    pub spam_threshold: u8,
+    pub review_threshold: Option<u8>,
+    pub review_chat_id: Option<i64>,
+    pub ham_threshold: u32,
+    pub spam_actions: Vec<SpamAction>,
+    pub mute_minutes: u32,
+    /// Модель LLM для чата; `None` — модель бэкенда по умолчанию
+    pub model: Option<String>,
+    /// Шаблон промпта; `None` — `PROMPT_TEMPLATE`
+    pub template: Option<String>,
+    pub topic: Option<String>,
+    pub prompt: Option<String>,
+    pub language: Option<String>,
+    pub notify_user_id: Option<i64>,
+    pub tag_username: Option<String>,
+    pub allowed_domains: Vec<String>,
+    pub blocked_domains: Vec<String>,
+    pub captcha_mode: CaptchaMode,
+    pub captcha_timeout_secs: u32,
+    pub probation_messages: u32,
+    pub probation_hours: u32,
+    pub probation_mode: ProbationMode,
+    pub probation_threshold: u8,
+}
+
+/// Полоса, в которую попала оценка спама
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreBand {
+    Spam,
+    Review,
+    Ham,
+}
+
+impl ChatConfig {
+    /// Собирает настройки чата: глобальная конфигурация, затем раздел чата из файла конфигурации,
+    /// затем переопределения из базы.
+    pub fn resolve(config: &Config, chat_id: i64, overrides: Option<&ChatSettings>) -> Self {
+        let base: ChatConfig = ChatConfig {
+            chat_id,
+            spam_threshold: config.spam_threshold,
+            review_threshold: config.review_threshold,
+            review_chat_id: config.review_chat_id,
+            ham_threshold: config.ham_threshold,
+            spam_actions: config
+                .chat_spam_actions
+                .get(&chat_id)
+                .unwrap_or(&config.spam_actions)
+                .clone(),
+            mute_minutes: config.mute_minutes,
+            model: None,
+            template: None,
+            topic: None,
+            prompt: None,
+            language: None,
+            notify_user_id: config.notify_user_id,
+            tag_username: config.tag_username.clone(),
+            allowed_domains: config.allowed_domains.clone(),
+            blocked_domains: config.blocked_domains.clone(),
+            captcha_mode: config.captcha_mode,
+            captcha_timeout_secs: config.captcha_timeout_secs,
+            probation_messages: config.probation_messages,
+            probation_hours: config.probation_hours,
+            probation_mode: config.probation_mode,
+            probation_threshold: config.probation_threshold,
+        };
+        [config.chat_overrides.get(&chat_id), overrides]
+            .into_iter()
+            .flatten()
+            .fold(base, |base, o| base.with_overrides(o))
+    }
+
+    fn with_overrides(self, o: &ChatSettings) -> Self {
+        let base: ChatConfig = self;
+        ChatConfig {
+            chat_id: base.chat_id,
+            spam_threshold: o.spam_threshold.unwrap_or(base.spam_threshold),
+            review_threshold: o.review_threshold.or(base.review_threshold),
+            review_chat_id: o.review_chat_id.or(base.review_chat_id),
+            ham_threshold: o.ham_threshold.unwrap_or(base.ham_threshold),
+            spam_actions: o.spam_actions.clone().unwrap_or(base.spam_actions),
+            mute_minutes: o.mute_minutes.unwrap_or(base.mute_minutes),
+            model: o.model.clone().or(base.model),
+            template: o.template.clone().or(base.template),
+            topic: o.topic.clone().or(base.topic),
+            prompt: o.prompt.clone().or(base.prompt),
+            language: o.language.clone().or(base.language),
+            notify_user_id: o.notify_user_id.or(base.notify_user_id),
+            tag_username: o.tag_username.clone().or(base.tag_username),
+            allowed_domains: o.allowed_domains.clone().unwrap_or(base.allowed_domains),
+            blocked_domains: o.blocked_domains.clone().unwrap_or(base.blocked_domains),
+            captcha_mode: o.captcha_mode.unwrap_or(base.captcha_mode),
+            captcha_timeout_secs: o.captcha_timeout_secs.unwrap_or(base.captcha_timeout_secs),
+            probation_messages: o.probation_messages.unwrap_or(base.probation_messages),
+            probation_hours: o.probation_hours.unwrap_or(base.probation_hours),
+            probation_mode: o.probation_mode.unwrap_or(base.probation_mode),
+            probation_threshold: o.probation_threshold.unwrap_or(base.probation_threshold),
+        }
+    }
+
+    /// Противоречие порогов: порог проверки не ниже порога спама оставил бы полосу проверки пустой
+    pub fn threshold_problem(&self) -> Option<String> {
+        let review: u8 = self.review_threshold?;
+        (review >= self.spam_threshold).then(|| {
+            format!("порог проверки ({review}%) должен быть ниже порога спама ({}%)", self.spam_threshold)
+        })
+    }
+
+    /// Куда бот отправляет кнопку «Не спам», если не предупреждает в самом чате:
+    /// чат модераторов, а без него — получатель уведомлений
+    pub fn undo_chat_id(&self) -> Option<i64> {
+        self.review_chat_id.or(self.notify_user_id)
+    }
+
+    /// Определяет полосу оценки: автоматическое действие, ручная проверка или пропуск.
+    /// Полоса проверки работает только если заданы и порог, и чат модераторов.
+    pub fn score_band(&self, score: u8) -> ScoreBand {
+        if score >= self.spam_threshold {
+            return ScoreBand::Spam;
+        }
+        match (self.review_threshold, self.review_chat_id) {
+            (Some(review), Some(_)) if score >= review => ScoreBand::Review,
+            _ => ScoreBand::Ham,
+        }
+    }
+
+    /// Полоса оценки на испытательном сроке: спамом считается всё от `probation_threshold`
+    pub fn probation_band(&self, score: u8) -> ScoreBand {
+        if score >= self.probation_threshold.min(self.spam_threshold) {
+            return ScoreBand::Spam;
+        }
+        self.score_band(score)
+    }
+
+    /// Человекочитаемое описание действующих настроек для команды `/settings`
+    pub fn describe(&self) -> String {
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "—".to_string());
+        let list = |v: &[String]| (!v.is_empty()).then(|| v.join(","));
+        let actions: String = self
+            .spam_actions
+            .iter()
+            .map(|a| format!("{a:?}").to_lowercase())
+            .collect::<Vec<_>>()
+            .join(",");
+        [
+            format!("spam_threshold: {}", self.spam_threshold),
+            format!("review_threshold: {}", opt(self.review_threshold.map(|v| v.to_string()))),
+            format!("review_chat: {}", opt(self.review_chat_id.map(|v| v.to_string()))),
+            format!("ham_threshold: {}", self.ham_threshold),
+            format!("actions: {actions}"),
+            format!("mute_minutes: {}", self.mute_minutes),
+            format!("model: {}", opt(self.model.clone())),
+            format!("template: {}", opt(self.template.clone())),
+            format!("topic: {}", opt(self.topic.clone())),
+            format!("prompt: {}", opt(self.prompt.clone())),
+            format!("language: {}", opt(self.language.clone())),
+            format!("notify: {}", opt(self.notify_user_id.map(|v| v.to_string()))),
+            format!("tag: {}", opt(self.tag_username.clone())),
+            format!("allow_domains: {}", opt(list(&self.allowed_domains))),
+            format!("block_domains: {}", opt(list(&self.blocked_domains))),
+            format!("captcha: {}", format!("{:?}", self.captcha_mode).to_lowercase()),
+            format!("captcha_timeout: {}", self.captcha_timeout_secs),
+            format!("probation_messages: {}", self.probation_messages),
+            format!("probation_hours: {}", self.probation_hours),
+            format!("probation_mode: {}", format!("{:?}", self.probation_mode).to_lowercase()),
+            format!("probation_threshold: {}", self.probation_threshold),
+        ]
+        .join("\n")
+    }
+}
+
+/// Противоречия порогов в сохранённых в базе переопределениях чатов при конфигурации `config`.
+/// Чат мог задать свой порог под прежние глобальные значения, и после их смены полосы
+/// перестают идти по порядку.
+pub fn stored_threshold_problems(config: &Config, stored: &HashMap<i64, ChatSettings>) -> Vec<String> {
+    let mut chat_ids: Vec<&i64> = stored.keys().collect();
+    chat_ids.sort();
+    chat_ids
+        .into_iter()
+        .filter_map(|chat_id| {
+            ChatConfig::resolve(config, *chat_id, stored.get(chat_id))
+                .threshold_problem()
+                .map(|problem| format!("чат {chat_id}: {problem}"))
+        })
+        .collect()
+}
+
+fn parse_percent(value: &str) -> Result<u8> {
+    let v: u8 = value.parse()?;
+    if v > 100 {
+        anyhow::bail!("Значение должно быть от 0 до 100");
+    }
+    Ok(v)
+}
+
+fn non_empty(value: &str) -> Result<String> {
+    if value.is_empty() {
+        anyhow::bail!("Значение не может быть пустым");
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> Config {
+        Config::from_toml(&format!("telegram_bot_token = \"x\"\n{extra}")).unwrap()
+    }
+
+    fn settings(pairs: &[(&str, &str)]) -> ChatSettings {
+        let mut settings: ChatSettings = ChatSettings::default();
+        for (key, value) in pairs {
+            settings.set(key, value).unwrap();
+        }
+        settings
+    }
+
+    fn resolved(pairs: &[(&str, &str)]) -> ChatConfig {
+        ChatConfig::resolve(&config(""), -100, Some(&settings(pairs)))
+    }
+
+    #[test]
+    fn sets_and_unsets_values() {
+        let mut settings: ChatSettings = settings(&[("spam_threshold", " 80 "), ("review_chat", "-1001"), ("tag", "@admin")]);
+        assert_eq!(settings.spam_threshold, Some(80));
+        assert_eq!(settings.review_chat_id, Some(-1001));
+        assert_eq!(settings.tag_username.as_deref(), Some("admin"));
+
+        settings.unset("spam_threshold").unwrap();
+        assert_eq!(settings.spam_threshold, None);
+        assert_eq!(settings.review_chat_id, Some(-1001));
+        for key in SETTING_KEYS {
+            settings.unset(key).unwrap();
+        }
+        assert_eq!(settings.review_chat_id, None);
+        assert_eq!(settings.tag_username, None);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut settings: ChatSettings = ChatSettings::default();
+        assert!(settings.set("spam_threshold", "101").is_err());
+        assert!(settings.set("spam_threshold", "-1").is_err());
+        assert!(settings.set("notify", "admin").is_err());
+        assert!(settings.set("model", "  ").is_err());
+        assert!(settings.set("tag", "@").is_err());
+        assert!(settings.set("captcha", "maybe").is_err());
+        assert!(settings.set("spam_treshold", "80").is_err());
+        assert!(settings.unset("spam_treshold").is_err());
+        assert_eq!(settings.spam_threshold, None);
+    }
+
+    #[test]
+    fn resolves_config_then_file_section_then_database() {
+        let config: Config = config(
+            "spam_threshold = 70\nreview_threshold = 50\n[chats.-100]\nspam_threshold = 80\nmodel = \"file-model\"",
+        );
+        let other: ChatConfig = ChatConfig::resolve(&config, -200, None);
+        assert_eq!((other.spam_threshold, other.review_threshold, other.model), (70, Some(50), None));
+
+        let file: ChatConfig = ChatConfig::resolve(&config, -100, None);
+        assert_eq!((file.spam_threshold, file.review_threshold), (80, Some(50)));
+        assert_eq!(file.model.as_deref(), Some("file-model"));
+
+        let stored: ChatSettings = settings(&[("spam_threshold", "90"), ("review_threshold", "60")]);
+        let chat: ChatConfig = ChatConfig::resolve(&config, -100, Some(&stored));
+        assert_eq!((chat.spam_threshold, chat.review_threshold), (90, Some(60)));
+        assert_eq!(chat.model.as_deref(), Some("file-model"));
+    }
+
+    #[test]
+    fn review_threshold_must_be_below_spam_threshold() {
+        assert_eq!(resolved(&[("spam_threshold", "70")]).threshold_problem(), None);
+        assert_eq!(resolved(&[("spam_threshold", "70"), ("review_threshold", "69")]).threshold_problem(), None);
+        assert!(resolved(&[("spam_threshold", "70"), ("review_threshold", "70")]).threshold_problem().is_some());
+        assert!(resolved(&[("spam_threshold", "70"), ("review_threshold", "90")]).threshold_problem().is_some());
+    }
+
+    #[test]
+    fn score_band_boundaries() {
+        let chat: ChatConfig = resolved(&[("spam_threshold", "70"), ("review_threshold", "50"), ("review_chat", "-1001")]);
+        assert_eq!(chat.score_band(100), ScoreBand::Spam);
+        assert_eq!(chat.score_band(70), ScoreBand::Spam);
+        assert_eq!(chat.score_band(69), ScoreBand::Review);
+        assert_eq!(chat.score_band(50), ScoreBand::Review);
+        assert_eq!(chat.score_band(49), ScoreBand::Ham);
+        assert_eq!(chat.score_band(0), ScoreBand::Ham);
+
+        // Без чата модераторов или порога проверки полосы проверки нет
+        let no_chat: ChatConfig = resolved(&[("spam_threshold", "70"), ("review_threshold", "50")]);
+        assert_eq!(no_chat.score_band(69), ScoreBand::Ham);
+        let no_threshold: ChatConfig = resolved(&[("spam_threshold", "70"), ("review_chat", "-1001")]);
+        assert_eq!(no_threshold.score_band(69), ScoreBand::Ham);
+        assert_eq!(no_threshold.score_band(70), ScoreBand::Spam);
+    }
+
+    #[test]
+    fn probation_band_boundaries() {
+        let chat: ChatConfig = resolved(&[
+            ("spam_threshold", "70"),
+            ("review_threshold", "30"),
+            ("review_chat", "-1001"),
+            ("probation_threshold", "40"),
+        ]);
+        assert_eq!(chat.probation_band(40), ScoreBand::Spam);
+        assert_eq!(chat.probation_band(39), ScoreBand::Review);
+        assert_eq!(chat.probation_band(29), ScoreBand::Ham);
+
+        // Порог испытательного срока выше порога спама не ослабляет проверку
+        let lenient: ChatConfig = resolved(&[("spam_threshold", "70"), ("probation_threshold", "90")]);
+        assert_eq!(lenient.probation_band(70), ScoreBand::Spam);
+        assert_eq!(lenient.probation_band(69), ScoreBand::Ham);
+    }
+
+    #[test]
+    fn stored_thresholds_checked_against_new_config() {
+        let stored: HashMap<i64, ChatSettings> = HashMap::from([
+            (-2, settings(&[("review_threshold", "60")])),
+            (-1, settings(&[("spam_threshold", "90"), ("review_threshold", "60")])),
+        ]);
+        assert!(stored_threshold_problems(&config("spam_threshold = 70"), &stored).is_empty());
+
+        let problems: Vec<String> = stored_threshold_problems(&config("spam_threshold = 50"), &stored);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("чат -2:"), "{problems:?}");
+    }
+}
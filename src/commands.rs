@@ -0,0 +1,359 @@
+use anyhow::Result;
+use reqwest::Client;
+
+use crate::{
+    chat_settings::{ChatConfig, SETTING_KEYS},
+    config::Config,
+    feedback::report_spam,
+    state::{
+        add_user_to_whitelist, chat_config, record_moderation, revoke_whitelist, update_chat_settings, AppState,
+    },
+    storage::{ModerationEvent, WhitelistEntry},
+    telegram_api::{get_chat, get_chat_member_status, send_message, Message},
+};
+
+/// Разобранная команда бота: имя без `/` и `@bot` и аргументы
+struct Command<'a> {
+    name: &'a str,
+    args: &'a str,
+}
+
+/// Разбирает `/cmd@bot аргументы`. Команды, адресованные другому боту, игнорируются.
+fn parse_command<'a>(text: &'a str, bot_username: Option<&str>) -> Option<Command<'a>> {
+    let text: &str = text.trim().strip_prefix('/')?;
+    let (head, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let (name, addressee) = head.split_once('@').map_or((head, None), |(n, a)| (n, Some(a)));
+    if let (Some(addressee), Some(bot)) = (addressee, bot_username)
+        && !addressee.eq_ignore_ascii_case(bot)
+    {
+        return None;
+    }
+    Some(Command { name, args: args.trim() })
+}
+
+/// Проверяет, что отправитель — администратор чата.
+/// Анонимные администраторы пишут от имени самого чата и тоже считаются администраторами.
+pub async fn is_chat_admin(client: &Client, base_url: &str, msg: &Message) -> bool {
+    if msg.sender_chat.as_ref().is_some_and(|c| c.id == msg.chat.id) {
+        return true;
+    }
+    let Some(user) = msg.from.as_ref() else {
+        return false;
+    };
+    is_admin(client, base_url, msg.chat.id, user.id).await
+}
+
+/// Проверяет через getChatMember, что пользователь — создатель или администратор чата
+pub async fn is_admin(client: &Client, base_url: &str, chat_id: i64, user_id: i64) -> bool {
+    match get_chat_member_status(client, base_url, chat_id, user_id).await {
+        Ok(status) => status == "creator" || status == "administrator",
+        Err(err) => {
+            log::warn!("getChatMember error: {err:?}");
+            false
+        }
+    }
+}
+
+/// Команды, доступные только администраторам чата
+const ADMIN_COMMANDS: &[&str] = &["settings", "set", "unset", "whitelist", "unwhitelist", "status", "threshold"];
+
+/// Обрабатывает команды администраторов. Возвращает `true`, только если команду выполнил администратор:
+/// всё остальное, включая жалобы `/spam` и команды от участников, проверяется как обычное сообщение,
+/// иначе команда в начале текста позволяла бы обойти фильтр.
+pub async fn handle_command(
+    client: &Client,
+    base_url: &str,
+    msg: &Message,
+    text: &str,
+    state: &AppState,
+    config: &Config,
+) -> Result<bool> {
+    let Some(cmd) = parse_command(text, state.bot_username.get().map(String::as_str)) else {
+        return Ok(false);
+    };
+    if cmd.name != "spam" && !ADMIN_COMMANDS.contains(&cmd.name) {
+        return Ok(false);
+    }
+
+    let chat_id: i64 = msg.chat.id;
+    let reply = |text: String| async move {
+        send_message(client, base_url, chat_id, &text, Some(msg.message_id)).await
+    };
+
+    let admin: bool = is_chat_admin(client, base_url, msg).await;
+
+    // Пожаловаться на спам может любой участник, решение для не-администраторов принимают модераторы
+    if cmd.name == "spam" {
+        let Some(response) = report_spam(client, base_url, msg, admin, state, config).await? else {
+            return Ok(false);
+        };
+        reply(response).await?;
+        return Ok(admin);
+    }
+
+    // Участникам бот не отвечает, чтобы его нельзя было использовать для повтора текста
+    if !admin {
+        return Ok(false);
+    }
+
+    let response: String = match cmd.name {
+        "settings" => {
+            let chat: ChatConfig = chat_config(state, config, chat_id).await;
+            format!("Настройки чата:\n{}", chat.describe())
+        }
+        "set" => match cmd.args.split_once(char::is_whitespace) {
+            Some(("template", name)) if !config.prompts.contains(name.trim()) => {
+                format!("Шаблон промпта '{}' не найден. Доступны: {}", name.trim(), config.prompts.names())
+            }
+            Some((key @ ("review_chat" | "notify"), value)) => match unreachable_recipient(client, base_url, value).await {
+                Some(problem) => format!("Не удалось изменить {key}: {problem}"),
+                None => set_setting(state, config, chat_id, key, value).await,
+            },
+            Some((key, value)) => set_setting(state, config, chat_id, key, value).await,
+            None => format!("Использование: /set <настройка> <значение>\nНастройки: {}", SETTING_KEYS.join(", ")),
+        },
+        "unset" if !cmd.args.is_empty() => match update_chat_settings(state, config, chat_id, &[(cmd.args, None)]).await {
+            Ok(()) => format!("Настройка {} сброшена к глобальному значению", cmd.args),
+            Err(err) => format!("Не удалось сбросить {}: {err}", cmd.args),
+        },
+        "unset" => "Использование: /unset <настройка>".to_string(),
+        // Белый список общий для всех чатов, поэтому администратора одного чата недостаточно
+        "whitelist" | "unwhitelist" if !msg.from.as_ref().is_some_and(|u| config.bot_owners.contains(&u.id)) => {
+            "Белый список общий для всех чатов: менять его могут только владельцы бота из BOT_OWNERS".to_string()
+        }
+        "whitelist" | "unwhitelist" => {
+            let add: bool = cmd.name == "whitelist";
+            let usage: String = format!(
+                "Использование: ответьте /{} на сообщение пользователя или укажите его id{}",
+                cmd.name,
+                if add { "; срок — например, 30d" } else { "" }
+            );
+            match parse_whitelist_args(cmd.args, add) {
+                Ok((user_id, ttl_days)) => match user_id.or_else(|| replied_user(msg)) {
+                    Some(user_id) => change_whitelist(state, msg, user_id, add, ttl_days).await?,
+                    None => usage,
+                },
+                Err(err) => format!("{err}\n{usage}"),
+            }
+        }
+        "status" => status_text(state, config, chat_id).await,
+        "threshold" => set_thresholds(state, config, chat_id, cmd.args).await,
+        _ => return Ok(false),
+    };
+
+    log::info!("Команда /{} {} в чате {chat_id}", cmd.name, cmd.args);
+    reply(response).await?;
+    Ok(true)
+}
+
+/// Разбирает аргументы `/whitelist [id] [срок]` и `/unwhitelist [id]`. Срок задаётся отдельным
+/// аргументом вида `30d`, чтобы не путать его с id пользователя. Непонятные аргументы — ошибка,
+/// а не молчаливый пропуск: опечатка не должна внести в список не того пользователя.
+fn parse_whitelist_args(args: &str, allow_ttl: bool) -> Result<(Option<i64>, Option<u32>), String> {
+    let (mut user_id, mut ttl_days): (Option<i64>, Option<u32>) = (None, None);
+    for arg in args.split_whitespace() {
+        if let Ok(id) = arg.parse::<i64>()
+            && user_id.is_none()
+        {
+            user_id = Some(id);
+        } else if let Some(Ok(days)) = arg.strip_suffix('d').map(str::parse::<u32>)
+            && allow_ttl
+            && ttl_days.is_none()
+            && days > 0
+        {
+            ttl_days = Some(days);
+        } else {
+            return Err(format!("Непонятный аргумент '{arg}'"));
+        }
+    }
+    Ok((user_id, ttl_days))
+}
+
+/// Автор сообщения, на которое ответили командой
+fn replied_user(msg: &Message) -> Option<i64> {
+    msg.reply_to_message
+        .as_ref()
+        .and_then(|m| m.from.as_ref())
+        .filter(|u| !u.is_bot)
+        .map(|u| u.id)
+}
+
+/// Добавляет пользователя в вайтлист (бессрочно или на `ttl_days` дней) или удаляет из него
+/// по команде администратора
+async fn change_whitelist(
+    state: &AppState,
+    msg: &Message,
+    user_id: i64,
+    add: bool,
+    ttl_days: Option<u32>,
+) -> Result<String> {
+    let admin: String = msg
+        .from
+        .as_ref()
+        .and_then(|u| u.username.as_ref())
+        .map(|u| format!("@{u}"))
+        .unwrap_or_else(|| "admin".to_string());
+
+    if !add {
+        return Ok(if revoke_whitelist(state, msg.chat.id, user_id, &admin, "по команде администратора").await? {
+            format!("Пользователь {user_id} удалён из белого списка")
+        } else {
+            format!("Пользователя {user_id} нет в белом списке")
+        });
+    }
+
+    let term: String = ttl_days.map(|d| format!("на {d} дн.")).unwrap_or_else(|| "бессрочно".to_string());
+    let added: bool = add_user_to_whitelist(state, WhitelistEntry::new(user_id, "manual", &admin, ttl_days)).await?;
+    record_moderation(state, ModerationEvent {
+        chat_id: msg.chat.id,
+        user_id,
+        action: "whitelist".to_string(),
+        notes: Some(term.clone()),
+        actor: Some(admin),
+        ..Default::default()
+    }).await;
+
+    Ok(if added {
+        format!("Пользователь {user_id} добавлен в белый список {term}")
+    } else {
+        format!("Запись пользователя {user_id} в белом списке обновлена: {term}")
+    })
+}
+
+/// Состояние бота и действующие пороги чата для команды `/status`
+async fn status_text(state: &AppState, config: &Config, chat_id: i64) -> String {
+    let chat: ChatConfig = chat_config(state, config, chat_id).await;
+    let (whitelisted, temporary) = {
+        let whitelist = state.whitelist_cache.read().await;
+        (whitelist.len(), whitelist.values().filter(|e| e.expires_at.is_some()).count())
+    };
+    let (spam_examples, ham_examples) = state.bayes.examples().await;
+    let review: String = match (chat.review_threshold, chat.review_chat_id) {
+        (Some(threshold), Some(review_chat)) => format!("от {threshold}% в чат {review_chat}"),
+        _ => "выключена".to_string(),
+    };
+    [
+        format!("Версия: {}", env!("CARGO_PKG_VERSION")),
+        format!("Классификатор: {}", state.classifier().name()),
+        format!("Порог спама: {}%", chat.spam_threshold),
+        format!("Ручная проверка: {review}"),
+        format!("Сообщений до вайтлиста: {}", chat.ham_threshold),
+        format!("В белом списке: {whitelisted} (со сроком: {temporary})"),
+        format!("Статистическая модель: {spam_examples} спам / {ham_examples} не-спам примеров"),
+    ]
+    .join("\n")
+}
+
+/// `/threshold` показывает пороги, `/threshold <спам> [проверка]` меняет их для чата
+async fn set_thresholds(state: &AppState, config: &Config, chat_id: i64, args: &str) -> String {
+    let mut values = args.split_whitespace();
+    let Some(spam) = values.next() else {
+        let chat: ChatConfig = chat_config(state, config, chat_id).await;
+        let review: String = chat.review_threshold.map(|v| format!("{v}%")).unwrap_or_else(|| "—".to_string());
+        return format!(
+            "Порог спама: {}%, порог проверки: {review}\nИзменить: /threshold <спам> [проверка]",
+            chat.spam_threshold
+        );
+    };
+    let review: Option<&str> = values.next();
+
+    // Оба порога меняются вместе и проверяются против действующих значений чата
+    let mut changes: Vec<(&str, Option<&str>)> = vec![("spam_threshold", Some(spam))];
+    changes.extend(review.map(|review| ("review_threshold", Some(review))));
+    if let Err(err) = update_chat_settings(state, config, chat_id, &changes).await {
+        return format!("Не удалось изменить пороги: {err}");
+    }
+
+    match review {
+        Some(review) => format!("Порог спама: {spam}%, порог проверки: {review}%"),
+        None => format!("Порог спама: {spam}%"),
+    }
+}
+
+async fn set_setting(state: &AppState, config: &Config, chat_id: i64, key: &str, value: &str) -> String {
+    match update_chat_settings(state, config, chat_id, &[(key, Some(value))]).await {
+        Ok(()) => format!("Готово: {key} = {}", value.trim()),
+        Err(err) => format!("Не удалось изменить {key}: {err}"),
+    }
+}
+
+/// Проверяет получателя `/set review_chat` и `/set notify` через getChat до сохранения,
+/// чтобы предупреждения и кнопки не уходили в чат, куда бот не может писать.
+/// Некорректный id пропускается: его отклонит разбор настройки.
+async fn unreachable_recipient(client: &Client, base_url: &str, value: &str) -> Option<String> {
+    let recipient: i64 = value.trim().parse().ok()?;
+    let err: anyhow::Error = get_chat(client, base_url, recipient).await.err()?;
+    log::warn!("getChat {recipient} error: {err:?}");
+    Some(format!(
+        "бот не может писать в {recipient}: добавьте его в чат модераторов или попросите пользователя начать с ним диалог"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(text: &str) -> Option<(&str, &str)> {
+        parse_command(text, Some("AntiSpamBot")).map(|c| (c.name, c.args))
+    }
+
+    fn message(from: Option<i64>, sender_chat: Option<i64>) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "from": from.map(|id| serde_json::json!({"id": id, "is_bot": false})),
+            "sender_chat": sender_chat.map(|id| serde_json::json!({"id": id, "type": "channel"})),
+            "chat": {"id": -100, "type": "supergroup"},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_command_name_and_args() {
+        assert_eq!(command("/status"), Some(("status", "")));
+        assert_eq!(command("  /set  spam_threshold   80 "), Some(("set", "spam_threshold   80")));
+        assert_eq!(command("/whitelist@antispambot 42 30d"), Some(("whitelist", "42 30d")));
+        assert_eq!(command("status"), None);
+    }
+
+    #[test]
+    fn ignores_commands_for_other_bots() {
+        assert_eq!(command("/status@OtherBot"), None);
+        assert_eq!(parse_command("/status@OtherBot", None).map(|c| c.name), Some("status"));
+    }
+
+    #[test]
+    fn parses_whitelist_args() {
+        assert_eq!(parse_whitelist_args("", true), Ok((None, None)));
+        assert_eq!(parse_whitelist_args("42", false), Ok((Some(42), None)));
+        assert_eq!(parse_whitelist_args("30d 42", true), Ok((Some(42), Some(30))));
+    }
+
+    #[test]
+    fn rejects_unclear_whitelist_args() {
+        assert!(parse_whitelist_args("42 30d", false).is_err());
+        assert!(parse_whitelist_args("0d", true).is_err());
+        assert!(parse_whitelist_args("42 43", true).is_err());
+        assert!(parse_whitelist_args("7d 30d", true).is_err());
+        assert!(parse_whitelist_args("@user", true).is_err());
+        assert!(parse_whitelist_args("30 дней", true).is_err());
+    }
+
+    #[tokio::test]
+    async fn admin_check_without_api() {
+        let client: Client = Client::new();
+        // Анонимный администратор пишет от имени самого чата
+        assert!(is_chat_admin(&client, "http://127.0.0.1:9", &message(None, Some(-100))).await);
+        assert!(!is_chat_admin(&client, "http://127.0.0.1:9", &message(None, Some(-200))).await);
+        // Ошибка getChatMember не даёт прав администратора
+        assert!(!is_chat_admin(&client, "http://127.0.0.1:9", &message(Some(7), None)).await);
+    }
+
+    #[tokio::test]
+    async fn recipient_is_checked_before_saving() {
+        let client: Client = Client::new();
+        let problem: Option<String> = unreachable_recipient(&client, "http://127.0.0.1:9", " -1001234567890").await;
+        assert!(problem.is_some_and(|p| p.contains("-1001234567890")));
+        // Некорректный id не проверяется через API, ошибку вернёт разбор настройки
+        assert_eq!(unreachable_recipient(&client, "http://127.0.0.1:9", "модераторы").await, None);
+    }
+}
@@ -1,59 +1,848 @@
-use std::path::PathBuf;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    actions::{parse_action_list, parse_mute_minutes, SpamAction},
+    bayes::BayesMode,
+    captcha::CaptchaMode,
+    chat_settings::{stored_threshold_problems, ChatConfig, ChatSettings},
+    dispatcher::{DispatchOrdering, Dispatcher},
+    entities::parse_domain_list,
+    probation::ProbationMode,
+    prompts::{PromptLibrary, DEFAULT_TEMPLATE},
+    recheck::RecheckAction,
+    resilience::{parse_fallbacks, FailurePolicy, FallbackEndpoint},
+    spam_checker::{ClassifierKind, EnsemblePolicy, SpamClassifier},
+};
+
+/// Как часто проверяется, не изменился ли файл конфигурации
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
 
 /// Конфигурация приложения
-/// Значения берутся из переменных окружения и дефолтов
+/// Значения берутся из переменных окружения, файла `CONFIG_FILE` и дефолтов
 #[derive(Debug)]
 pub struct Config {
     pub bot_token: String,
+    /// Старый файл вайтлиста; импортируется в базу один раз при первом запуске
     pub whitelist_path: PathBuf,
+    pub database_path: PathBuf,
     pub spam_threshold: u8,
+    /// Нижняя граница полосы ручной проверки: оценки от неё до `spam_threshold` уходят модераторам
+    pub review_threshold: Option<u8>,
+    /// Чат модераторов, куда отправляются сообщения на проверку
+    pub review_chat_id: Option<i64>,
     pub ham_threshold: u32,
+    /// Сколько дней действует автоматическая запись в вайтлисте; `None` — бессрочно
+    pub whitelist_ttl_days: Option<u32>,
+    /// Какой процент сообщений пользователей из вайтлиста перепроверяется в фоне
+    pub whitelist_recheck_percent: u8,
+    /// Перепроверять все сообщения пользователей из вайтлиста со ссылками
+    pub whitelist_recheck_links: bool,
+    /// Оценка повторной проверки, начиная с которой срабатывает `whitelist_recheck_action`
+    pub whitelist_recheck_threshold: u8,
+    pub whitelist_recheck_action: RecheckAction,
+    /// За сколько минут помнятся сообщения для поиска повторов; `0` — поиск выключен
+    pub duplicate_window_minutes: u32,
+    /// Сколько бит SimHash могут различаться у повторов (из 64)
+    pub duplicate_distance: u32,
+    /// Сколько похожих сообщений в окне считается флудом; `0` — не искать флуд
+    pub duplicate_flood_count: usize,
+    /// Более короткие сообщения не сравниваются
+    pub duplicate_min_length: usize,
     pub tag_username: Option<String>,
+    /// Классификаторы в порядке опроса
+    pub classifiers: Vec<ClassifierKind>,
+    /// Как объединять оценки нескольких классификаторов
+    pub ensemble_policy: EnsemblePolicy,
+    /// Веса классификаторов по имени для политики `weighted` и `vote`
+    pub ensemble_weights: HashMap<String, f64>,
+    /// Пользователи с id не меньше этого считаются новыми аккаунтами
+    pub new_account_id_from: i64,
+    /// Файл правил с регулярными выражениями, фразами и условиями на ссылки и отправителя
+    pub rules_path: Option<PathBuf>,
+    /// Шаблоны промптов LLM: встроенный и файлы из `PROMPTS_DIR`
+    pub prompts: Arc<PromptLibrary>,
+    pub ollama_url: String,
     pub ollama_model: String,
+    /// Запасные модели и серверы Ollama в порядке опроса
+    pub ollama_fallbacks: Vec<FallbackEndpoint>,
+    pub openai_base_url: Option<String>,
+    pub openai_model: String,
+    pub openai_api_key: Option<String>,
+    /// Полное время ответа LLM, включая генерацию
+    pub llm_timeout_secs: u64,
+    pub llm_connect_timeout_secs: u64,
+    /// Сколько раз повторять запрос к бэкенду после временной ошибки
+    pub llm_retries: u32,
+    /// Пауза перед первым повтором; каждая следующая вдвое длиннее
+    pub llm_retry_backoff_ms: u64,
+    /// После скольких отказов подряд бэкенд временно отключается; `0` — не отключать
+    pub llm_breaker_failures: u32,
+    pub llm_breaker_cooldown_secs: u64,
+    /// Что делать с сообщением, если классификатор не ответил
+    pub failure_policy: FailurePolicy,
+    /// Просить LLM исправить ответ, который не удалось разобрать
+    pub llm_repair: bool,
     pub notify_user_id: Option<i64>,
+    /// Владельцы бота: только они меняют общий для всех чатов белый список командами
+    pub bot_owners: Vec<i64>,
+    /// Режим статистического классификатора
+    pub bayes_mode: BayesMode,
+    pub bayes_model_path: PathBuf,
+    /// Минимум размеченных примеров каждого класса, прежде чем модель начнёт давать оценки
+    pub bayes_min_examples: u32,
+    pub bayes_ham_below: u8,
+    pub bayes_spam_above: u8,
+    /// Сколько оценок LLM хранится в кэше; `0` — кэш выключен
+    pub verdict_cache_size: usize,
+    pub verdict_cache_ttl_minutes: u32,
+    /// Файл, в котором кэш оценок переживает перезапуск; без него кэш только в памяти
+    pub verdict_cache_path: Option<PathBuf>,
+    /// Действия со спамом по умолчанию
+    pub spam_actions: Vec<SpamAction>,
+    /// Переопределения действий для отдельных чатов из `CHAT_SPAM_ACTIONS`; настройки из базы важнее
+    pub chat_spam_actions: HashMap<i64, Vec<SpamAction>>,
+    /// Настройки отдельных чатов из раздела `[chats.<id>]` файла конфигурации; настройки из базы важнее
+    pub chat_overrides: HashMap<i64, ChatSettings>,
+    pub mute_minutes: u32,
+    /// Домены, ссылки на которые не считаются признаком спама
+    pub allowed_domains: Vec<String>,
+    /// Домены, ссылка на которые сразу делает сообщение спамом
+    pub blocked_domains: Vec<String>,
+    /// Проверка новых участников
+    pub captcha_mode: CaptchaMode,
+    /// Сколько секунд даётся на ответ, прежде чем участник будет удалён
+    pub captcha_timeout_secs: u32,
+    /// Сколько не-СПАМ сообщений длится испытательный срок; `0` — выключен
+    pub probation_messages: u32,
+    /// Через сколько часов после первого появления срок заканчивается в любом случае
+    pub probation_hours: u32,
+    pub probation_mode: ProbationMode,
+    /// Порог спама для рискованных сообщений на испытательном сроке
+    pub probation_threshold: u8,
+    /// Сколько обновлений обрабатывается одновременно
+    pub max_concurrent_updates: usize,
+    /// Ключ, внутри которого сохраняется порядок обработки
+    pub dispatch_ordering: DispatchOrdering,
+    /// Сколько обновлений может ждать в очереди одного чата или пользователя
+    pub max_queued_updates: usize,
+    /// Публичный адрес вебхука, который регистрируется в Telegram
+    pub webhook_url: Option<String>,
+    /// Адрес, на котором слушает HTTP-сервер вебхука
+    pub webhook_listen: SocketAddr,
+    pub webhook_path: String,
+    /// Секрет, который Telegram присылает в `X-Telegram-Bot-Api-Secret-Token`
+    pub webhook_secret: Option<String>,
+    /// Снимать вебхук при остановке. Выключается, если за прокси работают другие экземпляры бота
+    /// с тем же вебхуком: иначе остановка одного сняла бы его для всех
+    pub webhook_delete_on_shutdown: bool,
 }
 
 impl Config {
-    pub fn from_env() -> anyhow::Result<Self> {
-        let bot_token: String = std::env::var("TELEGRAM_BOT_TOKEN")
+    /// Загружает конфигурацию: переменные окружения важнее файла `CONFIG_FILE`, файл — значений по умолчанию.
+    /// Некорректные значения, неизвестные параметры файла и противоречивые настройки — ошибка,
+    /// а не молчаливый откат к значению по умолчанию.
+    pub fn load() -> anyhow::Result<Self> {
+        Self::from_source(Source::load(config_path().as_deref())?)
+    }
+
+    /// Конфигурация только из текста файла, без переменных окружения
+    #[cfg(test)]
+    pub fn from_toml(content: &str) -> anyhow::Result<Self> {
+        Self::from_source(Source::from_toml(content, false)?)
+    }
+
+    fn from_source(source: Source) -> anyhow::Result<Self> {
+        let bot_token: String = source
+            .optional_string("TELEGRAM_BOT_TOKEN")
             .context("Отсутствует переменная окружения TELEGRAM_BOT_TOKEN")?;
 
-        let whitelist_path: PathBuf = std::env::var("WHITE_USER_FILE")
-            .map(PathBuf::from)
-            .unwrap_or_else(|_| PathBuf::from("white_user.txt"));
+        let whitelist_path: PathBuf = PathBuf::from(source.string("WHITE_USER_FILE", "white_user.txt"));
 
-        let spam_threshold: u8 = std::env::var("SPAM_THRESHOLD")
-            .unwrap_or("70".to_string())
-            .parse()
-            .unwrap_or(70);
+        let database_path: PathBuf = PathBuf::from(source.string("DATABASE_PATH", "anti_spam.db"));
 
-        let ham_threshold: u32 = std::env::var("HAM_WHITELIST_THRESHOLD")
-            .unwrap_or("15".to_string())
-            .parse()
-            .unwrap_or(15);
+        let spam_threshold: u8 = source.percent("SPAM_THRESHOLD", 70)?;
+
+        let review_threshold: Option<u8> = source.optional_percent("REVIEW_THRESHOLD")?;
+
+        let review_chat_id: Option<i64> = source.optional("REVIEW_CHAT_ID")?;
+
+        let ham_threshold: u32 = source.parse("HAM_WHITELIST_THRESHOLD", 15)?;
+
+        let whitelist_ttl_days: Option<u32> = source
+            .optional::<u32>("WHITELIST_TTL_DAYS")?
+            .filter(|days| *days > 0);
+
+        let whitelist_recheck_percent: u8 = source.percent("WHITELIST_RECHECK_PERCENT", 0)?;
+
+        let whitelist_recheck_links: bool = source.flag("WHITELIST_RECHECK_LINKS", false)?;
+
+        let whitelist_recheck_threshold: u8 = source.percent("WHITELIST_RECHECK_THRESHOLD", 90)?;
+
+        let whitelist_recheck_action: RecheckAction = source.parse("WHITELIST_RECHECK_ACTION", RecheckAction::Alert)?;
+
+        let duplicate_window_minutes: u32 = source.parse("DUPLICATE_WINDOW_MINUTES", 60)?;
+
+        let duplicate_distance: u32 = source.parse("DUPLICATE_DISTANCE", 8)?;
+
+        let duplicate_flood_count: usize = source.parse("DUPLICATE_FLOOD_COUNT", 5)?;
 
-        let tag_username: Option<String> = std::env::var("TEG_USERNAME")
-            .ok()
-            .filter(|v| !v.trim().is_empty())
+        let duplicate_min_length: usize = source.parse("DUPLICATE_MIN_LENGTH", 30)?;
+
+        let tag_username: Option<String> = source
+            .optional_string("TEG_USERNAME")
             .map(|v| v.trim_start_matches('@').to_string());
 
-        let ollama_model: String = std::env::var("OLLAMA_MODEL")
-            .unwrap_or("llama3.2:3b".to_string());
+        let classifiers: Vec<ClassifierKind> = source.parse_with("CLASSIFIERS", "ollama", |v| {
+            v.split(',')
+                .filter(|c| !c.trim().is_empty())
+                .map(|c| c.parse::<ClassifierKind>())
+                .collect()
+        })?;
+
+        let ensemble_policy: EnsemblePolicy = source.parse("ENSEMBLE_POLICY", EnsemblePolicy::Max)?;
+
+        let ensemble_weights: HashMap<String, f64> = source.parse_with("ENSEMBLE_WEIGHTS", "", parse_weights)?;
+
+        let new_account_id_from: i64 = source.parse("NEW_ACCOUNT_ID_FROM", 7_000_000_000)?;
+
+        let rules_path: Option<PathBuf> = source.optional_string("RULES_FILE").map(PathBuf::from);
+
+        let prompts_dir: Option<PathBuf> = source.optional_string("PROMPTS_DIR").map(PathBuf::from);
+
+        let prompts: Arc<PromptLibrary> = Arc::new(PromptLibrary::load(
+            prompts_dir.as_deref(),
+            &source.string("PROMPT_TEMPLATE", DEFAULT_TEMPLATE),
+        )?);
+
+        let ollama_url: String = source.string("OLLAMA_URL", "http://127.0.0.1:11434");
+
+        let ollama_model: String = source.string("OLLAMA_MODEL", "llama3.2:3b");
+
+        let ollama_fallbacks: Vec<FallbackEndpoint> = source.parse_with("OLLAMA_FALLBACKS", "", parse_fallbacks)?;
+
+        let openai_base_url: Option<String> = source.optional_string("OPENAI_BASE_URL");
+
+        let openai_model: String = source.string("OPENAI_MODEL", &ollama_model);
+
+        let openai_api_key: Option<String> = source.optional_string("OPENAI_API_KEY");
+
+        let llm_timeout_secs: u64 = source.parse("LLM_TIMEOUT_SECONDS", 120)?;
+
+        let llm_connect_timeout_secs: u64 = source.parse("LLM_CONNECT_TIMEOUT_SECONDS", 10)?;
+
+        let llm_retries: u32 = source.parse("LLM_RETRIES", 2)?;
+
+        let llm_retry_backoff_ms: u64 = source.parse("LLM_RETRY_BACKOFF_MS", 500)?;
+
+        let llm_breaker_failures: u32 = source.parse("LLM_BREAKER_FAILURES", 5)?;
+
+        let llm_breaker_cooldown_secs: u64 = source.parse("LLM_BREAKER_COOLDOWN_SECONDS", 60)?;
 
-        let notify_user_id: Option<i64> = std::env::var("NOTIFY_USER_ID")
-            .ok()
-            .and_then(|v| v.trim().parse::<i64>().ok());
+        let failure_policy: FailurePolicy = source.parse("FAILURE_POLICY", FailurePolicy::Open)?;
 
-        Ok(Config {
+        let llm_repair: bool = source.flag("LLM_REPAIR", false)?;
+
+        let notify_user_id: Option<i64> = source.optional("NOTIFY_USER_ID")?;
+
+        let bot_owners: Vec<i64> = source.parse_with("BOT_OWNERS", "", |v| {
+            v.split(',')
+                .filter(|id| !id.trim().is_empty())
+                .map(|id| id.trim().parse::<i64>().with_context(|| format!("Некорректный id владельца '{id}'")))
+                .collect()
+        })?;
+
+        let bayes_mode: BayesMode = source.parse("BAYES_MODE", BayesMode::Fallback)?;
+
+        let bayes_model_path: PathBuf = PathBuf::from(source.string("BAYES_MODEL_FILE", "bayes_model.json"));
+
+        let bayes_min_examples: u32 = source.parse("BAYES_MIN_EXAMPLES", 20)?;
+
+        let bayes_ham_below: u8 = source.percent("BAYES_HAM_BELOW", 10)?;
+
+        let bayes_spam_above: u8 = source.percent("BAYES_SPAM_ABOVE", 95)?;
+
+        let verdict_cache_size: usize = source.parse("VERDICT_CACHE_SIZE", 1000)?;
+
+        let verdict_cache_ttl_minutes: u32 = source.parse("VERDICT_CACHE_TTL_MINUTES", 1440)?;
+
+        let verdict_cache_path: Option<PathBuf> = source.optional_string("VERDICT_CACHE_FILE").map(PathBuf::from);
+
+        let spam_actions: Vec<SpamAction> = source.parse_with("SPAM_ACTIONS", "delete,mute", parse_action_list)?;
+
+        let chat_spam_actions: HashMap<i64, Vec<SpamAction>> =
+            source.parse_with("CHAT_SPAM_ACTIONS", "", parse_chat_actions)?;
+
+        let mute_minutes: u32 = source.parse_with("MUTE_MINUTES", "60", parse_mute_minutes)?;
+
+        let allowed_domains: Vec<String> = source.parse_with("ALLOWED_DOMAINS", "", |v| Ok(parse_domain_list(v)))?;
+
+        let blocked_domains: Vec<String> = source.parse_with("BLOCKED_DOMAINS", "", |v| Ok(parse_domain_list(v)))?;
+
+        let captcha_mode: CaptchaMode = source.parse("CAPTCHA", CaptchaMode::Off)?;
+
+        let captcha_timeout_secs: u32 = source.parse("CAPTCHA_TIMEOUT", 120)?;
+
+        let probation_messages: u32 = source.parse("PROBATION_MESSAGES", 0)?;
+
+        let probation_hours: u32 = source.parse("PROBATION_HOURS", 24)?;
+
+        let probation_mode: ProbationMode = source.parse("PROBATION_MODE", ProbationMode::Strict)?;
+
+        let probation_threshold: u8 = source.percent("PROBATION_THRESHOLD", 40)?;
+
+        let max_concurrent_updates: usize = source.parse("MAX_CONCURRENT_UPDATES", 4)?;
+
+        let dispatch_ordering: DispatchOrdering = source.parse("DISPATCH_ORDERING", DispatchOrdering::Chat)?;
+
+        let max_queued_updates: usize = source.parse("MAX_QUEUED_UPDATES", 100)?;
+
+        let webhook_url: Option<String> = source.optional_string("WEBHOOK_URL");
+
+        let webhook_listen: SocketAddr = source.parse("WEBHOOK_LISTEN", SocketAddr::from(([0, 0, 0, 0], 8080)))?;
+
+        let webhook_path: String = source.string("WEBHOOK_PATH", "/telegram");
+
+        let webhook_secret: Option<String> = source.optional_string("WEBHOOK_SECRET");
+
+        let webhook_delete_on_shutdown: bool = source.flag("WEBHOOK_DELETE_ON_SHUTDOWN", true)?;
+
+        let config: Config = Config {
             bot_token,
             whitelist_path,
+            database_path,
             spam_threshold,
+            review_threshold,
+            review_chat_id,
             ham_threshold,
+            whitelist_ttl_days,
+            whitelist_recheck_percent,
+            whitelist_recheck_links,
+            whitelist_recheck_threshold,
+            whitelist_recheck_action,
+            duplicate_window_minutes,
+            duplicate_distance,
+            duplicate_flood_count,
+            duplicate_min_length,
             tag_username,
+            classifiers,
+            ensemble_policy,
+            ensemble_weights,
+            new_account_id_from,
+            rules_path,
+            prompts,
+            ollama_url,
             ollama_model,
+            ollama_fallbacks,
+            openai_base_url,
+            openai_model,
+            openai_api_key,
+            llm_timeout_secs,
+            llm_connect_timeout_secs,
+            llm_retries,
+            llm_retry_backoff_ms,
+            llm_breaker_failures,
+            llm_breaker_cooldown_secs,
+            failure_policy,
+            llm_repair,
             notify_user_id,
-        })
+            bot_owners,
+            bayes_mode,
+            bayes_model_path,
+            bayes_min_examples,
+            bayes_ham_below,
+            bayes_spam_above,
+            verdict_cache_size,
+            verdict_cache_ttl_minutes,
+            verdict_cache_path,
+            spam_actions,
+            chat_spam_actions,
+            chat_overrides: source.chats.clone(),
+            mute_minutes,
+            allowed_domains,
+            blocked_domains,
+            captcha_mode,
+            captcha_timeout_secs,
+            probation_messages,
+            probation_hours,
+            probation_mode,
+            probation_threshold,
+            max_concurrent_updates,
+            dispatch_ordering,
+            max_queued_updates,
+            webhook_url,
+            webhook_listen,
+            webhook_path,
+            webhook_secret,
+            webhook_delete_on_shutdown,
+        };
+
+        // Неизвестные параметры файла — скорее всего опечатки, которые иначе молча игнорировались бы
+        let mut problems: Vec<String> = source
+            .unused()
+            .into_iter()
+            .map(|path| format!("неизвестный параметр «{path}» в файле конфигурации"))
+            .collect();
+        problems.extend(config.problems());
+        if !problems.is_empty() {
+            anyhow::bail!("Ошибки конфигурации:\n- {}", problems.join("\n- "));
+        }
+        Ok(config)
+    }
+
+    /// Противоречия между параметрами, которые не видны при разборе каждого по отдельности
+    fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if let Some(review) = self.review_threshold
+            && review >= self.spam_threshold
+        {
+            problems.push(format!(
+                "REVIEW_THRESHOLD ({review}) должен быть меньше SPAM_THRESHOLD ({})",
+                self.spam_threshold
+            ));
+        }
+        if self.bayes_ham_below >= self.bayes_spam_above {
+            problems.push(format!(
+                "BAYES_HAM_BELOW ({}) должен быть меньше BAYES_SPAM_ABOVE ({})",
+                self.bayes_ham_below, self.bayes_spam_above
+            ));
+        }
+        if self.classifiers.is_empty() {
+            problems.push("список CLASSIFIERS пуст".to_string());
+        }
+        if self.classifiers.contains(&ClassifierKind::OpenAi) && self.openai_base_url.is_none() {
+            problems.push("для классификатора openai нужен OPENAI_BASE_URL".to_string());
+        }
+        if self.duplicate_distance > 64 {
+            problems.push(format!("DUPLICATE_DISTANCE ({}) не может быть больше 64", self.duplicate_distance));
+        }
+        if self.llm_timeout_secs == 0 || self.llm_connect_timeout_secs == 0 {
+            problems.push("LLM_TIMEOUT_SECONDS и LLM_CONNECT_TIMEOUT_SECONDS должны быть больше 0".to_string());
+        }
+        if self.max_concurrent_updates == 0 {
+            problems.push("MAX_CONCURRENT_UPDATES должен быть больше 0".to_string());
+        }
+        if self.max_queued_updates == 0 {
+            problems.push("MAX_QUEUED_UPDATES должен быть больше 0".to_string());
+        }
+        for (chat_id, settings) in &self.chat_overrides {
+            if let Some(problem) = ChatConfig::resolve(self, *chat_id, None).threshold_problem() {
+                problems.push(format!("чат {chat_id}: {problem}"));
+            }
+            if let Some(template) = settings.template.as_deref()
+                && !self.prompts.contains(template)
+            {
+                problems.push(format!(
+                    "шаблон промпта '{template}' чата {chat_id} не найден. Доступны: {}",
+                    self.prompts.names()
+                ));
+            }
+        }
+        problems
     }
+
+    /// Параметры, изменение которых вступает в силу только после перезапуска:
+    /// они используются один раз при запуске
+    fn restart_required(&self, old: &Config) -> Vec<&'static str> {
+        [
+            ("TELEGRAM_BOT_TOKEN", self.bot_token != old.bot_token),
+            ("WHITE_USER_FILE", self.whitelist_path != old.whitelist_path),
+            ("DATABASE_PATH", self.database_path != old.database_path),
+            ("RULES_FILE", self.rules_path != old.rules_path),
+            ("BAYES_MODEL_FILE", self.bayes_model_path != old.bayes_model_path),
+            ("BAYES_MIN_EXAMPLES", self.bayes_min_examples != old.bayes_min_examples),
+            ("VERDICT_CACHE_SIZE", self.verdict_cache_size != old.verdict_cache_size),
+            ("VERDICT_CACHE_TTL_MINUTES", self.verdict_cache_ttl_minutes != old.verdict_cache_ttl_minutes),
+            ("VERDICT_CACHE_FILE", self.verdict_cache_path != old.verdict_cache_path),
+            ("MAX_CONCURRENT_UPDATES", self.max_concurrent_updates != old.max_concurrent_updates),
+            ("DISPATCH_ORDERING", self.dispatch_ordering != old.dispatch_ordering),
+            ("MAX_QUEUED_UPDATES", self.max_queued_updates != old.max_queued_updates),
+            ("WEBHOOK_URL", self.webhook_url != old.webhook_url),
+            ("WEBHOOK_LISTEN", self.webhook_listen != old.webhook_listen),
+            ("WEBHOOK_PATH", self.webhook_path != old.webhook_path),
+            ("WEBHOOK_SECRET", self.webhook_secret != old.webhook_secret),
+            ("WEBHOOK_DELETE_ON_SHUTDOWN", self.webhook_delete_on_shutdown != old.webhook_delete_on_shutdown),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+/// Путь к файлу конфигурации из `CONFIG_FILE`
+fn config_path() -> Option<PathBuf> {
+    std::env::var("CONFIG_FILE")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .map(PathBuf::from)
+}
+
+/// Значение параметра из файла конфигурации и его место в файле для сообщений об ошибках
+struct FileValue {
+    value: String,
+    path: String,
+}
+
+/// Источник значений параметров: переменная окружения важнее файла конфигурации.
+/// Запоминает запрошенные имена, чтобы найти в файле неизвестные параметры.
+struct Source {
+    /// Параметры файла по имени переменной окружения: `[ollama] model` — это `OLLAMA_MODEL`
+    file: HashMap<String, FileValue>,
+    /// Настройки чатов из разделов `[chats.<id>]`
+    chats: HashMap<i64, ChatSettings>,
+    requested: RefCell<HashSet<String>>,
+    /// Читать ли переменные окружения
+    env: bool,
+}
+
+impl Source {
+    fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(path) = path else {
+            return Self::from_toml("", true);
+        };
+        let content: String = std::fs::read_to_string(path)
+            .with_context(|| format!("Не удалось прочитать файл конфигурации {}", path.display()))?;
+        Self::from_toml(&content, true).with_context(|| format!("Некорректный файл конфигурации {}", path.display()))
+    }
+
+    fn from_toml(content: &str, env: bool) -> anyhow::Result<Self> {
+        let mut source: Source = Source {
+            file: HashMap::new(),
+            chats: HashMap::new(),
+            requested: RefCell::new(HashSet::new()),
+            env,
+        };
+        let table: toml::Table = toml::from_str(content)?;
+        for (key, value) in &table {
+            if key == "chats" {
+                source.chats = parse_chat_sections(value)?;
+            } else {
+                flatten(key, value, &mut source.file)?;
+            }
+        }
+        Ok(source)
+    }
+
+    /// Значение и его происхождение для сообщений об ошибках. Пустая переменная окружения не задаёт значение.
+    fn get(&self, name: &str) -> Option<(String, String)> {
+        self.requested.borrow_mut().insert(name.to_string());
+        if self.env
+            && let Ok(value) = std::env::var(name)
+            && !value.trim().is_empty()
+        {
+            return Some((value, name.to_string()));
+        }
+        self.file
+            .get(name)
+            .map(|f| (f.value.clone(), format!("«{}» ({name})", f.path)))
+    }
+
+    fn string(&self, name: &str, default: &str) -> String {
+        self.optional_string(name).unwrap_or_else(|| default.to_string())
+    }
+
+    fn optional_string(&self, name: &str) -> Option<String> {
+        self.get(name)
+            .map(|(value, _)| value.trim().to_string())
+            .filter(|v| !v.is_empty())
+    }
+
+    fn optional<T: FromStr>(&self, name: &str) -> anyhow::Result<Option<T>>
+    where
+        T::Err: Display,
+    {
+        let Some((value, origin)) = self.get(name) else {
+            return Ok(None);
+        };
+        value
+            .trim()
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Некорректное значение {origin} '{value}': {e}"))
+    }
+
+    fn parse<T: FromStr>(&self, name: &str, default: T) -> anyhow::Result<T>
+    where
+        T::Err: Display,
+    {
+        Ok(self.optional(name)?.unwrap_or(default))
+    }
+
+    /// Значение со своим разбором; значение по умолчанию разбирается так же
+    fn parse_with<T>(&self, name: &str, default: &str, parse: impl Fn(&str) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        match self.get(name) {
+            Some((value, origin)) => {
+                parse(value.trim()).with_context(|| format!("Некорректное значение {origin} '{value}'"))
+            }
+            None => parse(default),
+        }
+    }
+
+    /// Проценты от 0 до 100
+    fn optional_percent(&self, name: &str) -> anyhow::Result<Option<u8>> {
+        let value: Option<u8> = self.optional(name)?;
+        if let Some(v) = value
+            && v > 100
+        {
+            anyhow::bail!("Значение {name} ({v}) должно быть от 0 до 100");
+        }
+        Ok(value)
+    }
+
+    fn percent(&self, name: &str, default: u8) -> anyhow::Result<u8> {
+        Ok(self.optional_percent(name)?.unwrap_or(default))
+    }
+
+    /// `true` или `false`
+    fn flag(&self, name: &str, default: bool) -> anyhow::Result<bool> {
+        self.parse(name, default)
+    }
+
+    /// Параметры файла, которые ни разу не запрашивались
+    fn unused(&self) -> Vec<String> {
+        let requested = self.requested.borrow();
+        let mut unused: Vec<String> = self
+            .file
+            .iter()
+            .filter(|(name, _)| !requested.contains(*name))
+            .map(|(_, f)| f.path.clone())
+            .collect();
+        unused.sort();
+        unused
+    }
+}
+
+/// Раскладывает таблицы файла в имена переменных окружения: `[whitelist] ttl_days` — `WHITELIST_TTL_DAYS`
+fn flatten(path: &str, value: &toml::Value, out: &mut HashMap<String, FileValue>) -> anyhow::Result<()> {
+    if let toml::Value::Table(table) = value {
+        for (key, value) in table {
+            flatten(&format!("{path}.{key}"), value, out)?;
+        }
+        return Ok(());
+    }
+    let name: String = path.replace('.', "_").to_uppercase();
+    let value: String = value_to_string(value).with_context(|| format!("Параметр «{path}»"))?;
+    if out.insert(name, FileValue { value, path: path.to_string() }).is_some() {
+        anyhow::bail!("Параметр «{path}» задан в файле конфигурации дважды");
+    }
+    Ok(())
+}
+
+/// Разбирает разделы `[chats.<id>]` теми же правилами, что и команда `/set`
+fn parse_chat_sections(value: &toml::Value) -> anyhow::Result<HashMap<i64, ChatSettings>> {
+    let toml::Value::Table(chats) = value else {
+        anyhow::bail!("Раздел «chats» должен состоять из таблиц [chats.<id>]");
+    };
+    let mut result: HashMap<i64, ChatSettings> = HashMap::new();
+    for (chat, settings) in chats {
+        let chat_id: i64 = chat
+            .parse()
+            .with_context(|| format!("Некорректный chat_id «chats.{chat}»"))?;
+        let toml::Value::Table(settings) = settings else {
+            anyhow::bail!("«chats.{chat}» должен быть таблицей настроек");
+        };
+        let chat_settings: &mut ChatSettings = result.entry(chat_id).or_default();
+        for (key, value) in settings {
+            let value: String = value_to_string(value).with_context(|| format!("Параметр «chats.{chat}.{key}»"))?;
+            chat_settings
+                .set(key, &value)
+                .with_context(|| format!("Параметр «chats.{chat}.{key}»"))?;
+        }
+    }
+    Ok(result)
+}
+
+/// Значение файла в виде строки, как в переменной окружения; списки — через запятую
+fn value_to_string(value: &toml::Value) -> anyhow::Result<String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Array(items) => Ok(items
+            .iter()
+            .map(value_to_string)
+            .collect::<anyhow::Result<Vec<String>>>()?
+            .join(",")),
+        _ => anyhow::bail!("неподдерживаемый тип значения"),
+    }
+}
+
+/// Фоновая задача: перечитывает конфигурацию по SIGHUP и после изменения файла `CONFIG_FILE`.
+/// Обновления, обработка которых уже началась, заканчиваются со старыми настройками.
+/// Некорректная новая версия отклоняется целиком, и продолжают действовать прежние настройки.
+pub async fn run_reload_loop<F>(dispatcher: Arc<Dispatcher>, build_classifier: F)
+where
+    F: Fn(&Config) -> anyhow::Result<Box<dyn SpamClassifier>> + Send + 'static,
+{
+    let Some(path) = config_path() else {
+        return;
+    };
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+    let mut modified: Option<SystemTime> = modified_at(&path).await;
+
+    loop {
+        let signalled = async {
+            #[cfg(unix)]
+            if let Some(sig) = hangup.as_mut() {
+                sig.recv().await;
+                return;
+            }
+            std::future::pending::<()>().await
+        };
+        tokio::select! {
+            _ = signalled => log::info!("Получен SIGHUP, перечитываю конфигурацию"),
+            _ = tokio::time::sleep(RELOAD_INTERVAL) => {
+                if modified_at(&path).await == modified {
+                    continue;
+                }
+            }
+        }
+        modified = modified_at(&path).await;
+        reload(&dispatcher, &build_classifier).await;
+    }
+}
+
+async fn modified_at(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+/// Загружает новую конфигурацию и пересобирает классификатор под неё.
+/// Файлы читаются в отдельном потоке, чтобы не задерживать обработку обновлений.
+/// Конфигурация, с которой переопределения порогов из базы противоречат друг другу, отклоняется.
+async fn reload<F>(dispatcher: &Dispatcher, build_classifier: &F)
+where
+    F: Fn(&Config) -> anyhow::Result<Box<dyn SpamClassifier>>,
+{
+    let loaded: anyhow::Result<Config> = tokio::task::spawn_blocking(Config::load)
+        .await
+        .unwrap_or_else(|err| Err(anyhow::anyhow!("Чтение конфигурации прервано: {err}")));
+    let config: Config = match loaded {
+        Ok(config) => config,
+        Err(err) => {
+            log::warn!("Конфигурация не перезагружена, действуют прежние настройки: {err:?}");
+            return;
+        }
+    };
+    let stored: Vec<String> = {
+        let settings: tokio::sync::RwLockReadGuard<'_, HashMap<i64, ChatSettings>> =
+            dispatcher.state().chat_settings.read().await;
+        stored_threshold_problems(&config, &settings)
+    };
+    if !stored.is_empty() {
+        log::warn!(
+            "Конфигурация не перезагружена, действуют прежние настройки: с новыми порогами противоречат настройки чатов ({}); поправьте их командами /threshold или /unset",
+            stored.join("; ")
+        );
+        return;
+    }
+    let classifier: Box<dyn SpamClassifier> = match build_classifier(&config) {
+        Ok(classifier) => classifier,
+        Err(err) => {
+            log::warn!("Конфигурация не перезагружена, действуют прежние настройки: {err:?}");
+            return;
+        }
+    };
+    let restart: Vec<&str> = config.restart_required(&dispatcher.config());
+    if !restart.is_empty() {
+        log::warn!("Изменения вступят в силу только после перезапуска: {}", restart.join(", "));
+    }
+    dispatcher.state().set_classifier(classifier);
+    dispatcher.set_config(config);
+    log::info!("Конфигурация перезагружена");
+}
+
+/// Разбирает веса в формате `имя:вес,имя:вес`, например `ollama:1.0,links:0.3`.
+fn parse_weights(value: &str) -> anyhow::Result<HashMap<String, f64>> {
+    let mut result: HashMap<String, f64> = HashMap::new();
+    for entry in value.split(',').filter(|e| !e.trim().is_empty()) {
+        let (name, weight) = entry
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Ожидается 'имя:вес', получено '{entry}'"))?;
+        let weight: f64 = weight.trim().parse()
+            .with_context(|| format!("Некорректный вес '{weight}'"))?;
+        if weight < 0.0 {
+            anyhow::bail!("Вес '{name}' не может быть отрицательным");
+        }
+        result.insert(name.trim().to_lowercase(), weight);
+    }
+    Ok(result)
+}
+
+/// Разбирает политики чатов в формате `chat_id:действие,действие;chat_id:действие`.
+fn parse_chat_actions(value: &str) -> anyhow::Result<HashMap<i64, Vec<SpamAction>>> {
+    let mut result: HashMap<i64, Vec<SpamAction>> = HashMap::new();
+    for entry in value.split(';').filter(|e| !e.trim().is_empty()) {
+        let (chat, actions) = entry
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Ожидается 'chat_id:действия', получено '{entry}'"))?;
+        let chat_id: i64 = chat.trim().parse()
+            .with_context(|| format!("Некорректный chat_id '{chat}'"))?;
+        result.insert(chat_id, parse_action_list(actions)?);
+    }
+    Ok(result)
 }
 
 use anyhow::Context;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(extra: &str) -> anyhow::Result<Config> {
+        Config::from_toml(&format!("telegram_bot_token = \"x\"\n{extra}"))
+    }
+
+    fn error(extra: &str) -> String {
+        format!("{:#}", load(extra).unwrap_err())
+    }
+
+    #[test]
+    fn defaults_and_sections() {
+        let config: Config = load("spam_threshold = 80\n[ollama]\nmodel = \"qwen2.5:7b\"").unwrap();
+        assert_eq!(config.spam_threshold, 80);
+        assert_eq!(config.review_threshold, None);
+        assert_eq!(config.mute_minutes, 60);
+        assert_eq!(config.ollama_model, "qwen2.5:7b");
+        assert!(config.webhook_delete_on_shutdown);
+    }
+
+    #[test]
+    fn token_is_required() {
+        assert!(Config::from_toml("").is_err());
+    }
+
+    #[test]
+    fn unknown_parameters_are_rejected() {
+        assert!(error("spam_treshold = 80").contains("spam_treshold"));
+    }
+
+    #[test]
+    fn review_threshold_must_be_below_spam_threshold() {
+        assert!(error("spam_threshold = 70\nreview_threshold = 70").contains("REVIEW_THRESHOLD"));
+        assert!(load("spam_threshold = 70\nreview_threshold = 69").is_ok());
+    }
+
+    #[test]
+    fn chat_sections_are_checked_against_effective_thresholds() {
+        let message: String = error("spam_threshold = 70\n[chats.-100]\nreview_threshold = 75");
+        assert!(message.contains("чат -100"), "{message}");
+        assert!(load("spam_threshold = 70\n[chats.-100]\nspam_threshold = 90\nreview_threshold = 75").is_ok());
+    }
+
+    #[test]
+    fn zero_mute_is_rejected() {
+        assert!(error("mute_minutes = 0").contains("MUTE_MINUTES"));
+        assert!(load("[chats.-100]\nmute_minutes = 0").is_err());
+    }
+}
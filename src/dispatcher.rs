@@ -0,0 +1,427 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+
+use crate::{
+    config::Config,
+    handlers,
+    state::AppState,
+    telegram_api::{get_me, TgUpdate},
+};
+
+/// Сколько простаивает очередь чата, прежде чем её воркер завершится
+const IDLE_WORKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// По какому ключу сохраняется порядок обработки обновлений
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatchOrdering {
+    Chat,
+    User,
+}
+
+impl FromStr for DispatchOrdering {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "chat" => Ok(DispatchOrdering::Chat),
+            "user" => Ok(DispatchOrdering::User),
+            other => anyhow::bail!("Неизвестный порядок '{other}' (допустимо: chat, user)"),
+        }
+    }
+}
+
+/// Обновление в очереди вместе с каналом, по которому сообщается о завершении обработки
+struct Job {
+    upd: TgUpdate,
+    done: oneshot::Sender<()>,
+}
+
+/// Обработка одного обновления: обработчики бота, в тестах — подставная
+type UpdateHandler = Box<dyn Fn(Arc<Dispatcher>, TgUpdate) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Параллельная обработка обновлений с общим лимитом одновременных обработчиков.
+/// Обновления с одним ключом (чат или пользователь) обрабатываются строго по очереди,
+/// поэтому медленный ответ LLM задерживает только свой чат. Очередь ключа ограничена:
+/// когда она заполнена, приём новых обновлений ждёт.
+pub struct Dispatcher {
+    client: Client,
+    base_url: String,
+    state: AppState,
+    /// Действующая конфигурация; заменяется при перезагрузке, начатая обработка держит свою копию
+    config: RwLock<Arc<Config>>,
+    ordering: DispatchOrdering,
+    permits: Semaphore,
+    queue_size: usize,
+    queues: Mutex<HashMap<i64, mpsc::Sender<Job>>>,
+    handler: UpdateHandler,
+}
+
+impl Dispatcher {
+    pub fn new(client: Client, base_url: String, state: AppState, config: Config) -> Arc<Self> {
+        Self::with_handler(client, base_url, state, config, Box::new(|dispatcher, upd| {
+            Box::pin(async move {
+                let config: Arc<Config> = dispatcher.config();
+                if let Err(err) =
+                    handlers::handle_update(&dispatcher.client, &dispatcher.base_url, &upd, &dispatcher.state, &config).await
+                {
+                    log::error!("handler error: {err:?}");
+                }
+            })
+        }))
+    }
+
+    fn with_handler(client: Client, base_url: String, state: AppState, config: Config, handler: UpdateHandler) -> Arc<Self> {
+        let permits: Semaphore = Semaphore::new(config.max_concurrent_updates.max(1));
+        let ordering: DispatchOrdering = config.dispatch_ordering;
+        let queue_size: usize = config.max_queued_updates.max(1);
+        Arc::new(Self {
+            client,
+            base_url,
+            state,
+            config: RwLock::new(Arc::new(config)),
+            ordering,
+            permits,
+            queue_size,
+            queues: Mutex::new(HashMap::new()),
+            handler,
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Заменяет конфигурацию для обновлений, обработка которых ещё не началась
+    pub fn set_config(&self, config: Config) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    /// Запрашивает getMe и запоминает username бота для разбора команд `/cmd@bot`
+    pub async fn identify(&self) {
+        match get_me(&self.client, &self.base_url).await {
+            Ok(Some(username)) => {
+                self.state.bot_username.set(username).ok();
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("getMe error: {err:?}"),
+        }
+    }
+
+    /// Принимает обновление от Telegram: сохраняет его в базе, ставит в очередь и удаляет из базы
+    /// после обработки. Telegram не присылает подтверждённые обновления повторно, поэтому прерванная
+    /// остановкой обработка повторяется из базы при запуске (`resume_pending`).
+    /// Ошибка означает, что обновление не сохранено и подтверждать его Telegram нельзя.
+    /// Неразборчивое обновление пропускается: повторная доставка его не исправит.
+    pub async fn accept(self: &Arc<Self>, payload: serde_json::Value) -> Result<()> {
+        let upd: TgUpdate = match serde_json::from_value::<TgUpdate>(payload.clone()) {
+            Ok(upd) => upd,
+            Err(err) => {
+                log::warn!("Не удалось разобрать обновление: {err}");
+                return Ok(());
+            }
+        };
+        let id: i64 = upd.update_id;
+        self.state
+            .storage
+            .save_pending_update(id, payload.to_string())
+            .await
+            .with_context(|| format!("Не удалось сохранить обновление {id}"))?;
+        self.track(upd).await;
+        Ok(())
+    }
+
+    /// Ставит в очередь обновления, обработка которых не закончилась до остановки.
+    /// Возвращает наибольший `update_id` среди них.
+    pub async fn resume_pending(self: &Arc<Self>) -> Option<i64> {
+        let pending: Vec<(i64, String)> = match self.state.storage.load_pending_updates().await {
+            Ok(pending) => pending,
+            Err(err) => {
+                log::warn!("Не удалось загрузить необработанные обновления: {err:?}");
+                return None;
+            }
+        };
+        if !pending.is_empty() {
+            log::info!("Повторяю обработку {} обновлений, прерванных остановкой", pending.len());
+        }
+        let mut last: Option<i64> = None;
+        for (id, payload) in pending {
+            last = Some(id);
+            match serde_json::from_str::<TgUpdate>(&payload) {
+                Ok(upd) => self.track(upd).await,
+                Err(err) => {
+                    log::warn!("Сохранённое обновление {id} не разобрано: {err}");
+                    self.state.storage.remove_pending_update(id).await.ok();
+                }
+            }
+        }
+        last
+    }
+
+    /// Ставит обновление в очередь и удаляет его из базы, когда обработка закончится
+    async fn track(self: &Arc<Self>, upd: TgUpdate) {
+        let id: i64 = upd.update_id;
+        let finished: oneshot::Receiver<()> = self.submit(upd).await;
+        let dispatcher: Arc<Dispatcher> = self.clone();
+        tokio::spawn(async move {
+            finished.await.ok();
+            if let Err(err) = dispatcher.state.storage.remove_pending_update(id).await {
+                log::warn!("Не удалось отметить обновление {id} обработанным: {err:?}");
+            }
+        });
+    }
+
+    /// Ставит обновление в очередь его чата, дожидаясь места в ней. Возвращённый канал закрывается,
+    /// когда обработка закончена.
+    pub async fn submit(self: &Arc<Self>, upd: TgUpdate) -> oneshot::Receiver<()> {
+        let (done, finished) = oneshot::channel();
+        let key: i64 = self.ordering_key(&upd);
+        let mut job: Job = Job { upd, done };
+
+        loop {
+            // Очередь ищется и создаётся под блокировкой, а ожидание места идёт без неё.
+            // Если воркер успел завершиться, задание возвращается и очередь создаётся заново
+            let tx: mpsc::Sender<Job> = {
+                let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+                match queues.get(&key) {
+                    Some(tx) => tx.clone(),
+                    None => {
+                        let (tx, rx) = mpsc::channel(self.queue_size);
+                        tx.try_send(job).ok();
+                        queues.insert(key, tx);
+                        tokio::spawn(self.clone().run_queue(key, rx));
+                        return finished;
+                    }
+                }
+            };
+            match tx.send(job).await {
+                Ok(()) => return finished,
+                Err(mpsc::error::SendError(returned)) => {
+                    job = returned;
+                    // Закрытая очередь ещё могла остаться в таблице: убираем её, если это она
+                    let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+                    if queues.get(&key).is_some_and(|current| current.same_channel(&tx)) {
+                        queues.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Воркер очереди одного ключа: обрабатывает задания по порядку и завершается после простоя
+    async fn run_queue(self: Arc<Self>, key: i64, mut rx: mpsc::Receiver<Job>) {
+        loop {
+            let job: Job = match tokio::time::timeout(IDLE_WORKER_TIMEOUT, rx.recv()).await {
+                Ok(Some(job)) => job,
+                Ok(None) => return,
+                Err(_) => {
+                    let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+                    match rx.try_recv() {
+                        Ok(job) => job,
+                        Err(_) => {
+                            queues.remove(&key);
+                            return;
+                        }
+                    }
+                }
+            };
+
+            let Ok(_permit) = self.permits.acquire().await else {
+                return;
+            };
+            (self.handler)(self.clone(), job.upd).await;
+            job.done.send(()).ok();
+        }
+    }
+
+    /// Ключ очереди: чат или пользователь, к которому относится обновление
+    fn ordering_key(&self, upd: &TgUpdate) -> i64 {
+        let (chat_id, user_id) = if let Some(msg) = upd.message.as_ref().or(upd.edited_message.as_ref()) {
+            (Some(msg.chat.id), msg.from.as_ref().map(|u| u.id))
+        } else if let Some(cq) = upd.callback_query.as_ref() {
+            (cq.message.as_ref().map(|m| m.chat.id), Some(cq.from.id))
+        } else if let Some(member) = upd.chat_member.as_ref() {
+            (Some(member.chat.id), Some(member.new_chat_member.user.id))
+        } else {
+            (None, None)
+        };
+
+        match self.ordering {
+            DispatchOrdering::Chat => chat_id.or(user_id),
+            DispatchOrdering::User => user_id.or(chat_id),
+        }
+        .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    /// Обновления, которые видел подставной обработчик: (чат, update_id)
+    type Seen = Arc<Mutex<Vec<(i64, i64)>>>;
+
+    async fn dispatcher(extra: &str, handler: UpdateHandler) -> Arc<Dispatcher> {
+        let config: Config = Config::from_toml(&format!("telegram_bot_token = \"x\"\n{extra}")).unwrap();
+        let state: AppState = AppState::for_tests(Path::new(":memory:")).await;
+        Dispatcher::with_handler(Client::new(), "http://127.0.0.1:9".to_string(), state, config, handler)
+    }
+
+    /// Обработчик, который запоминает обновление после задержки, разной для разных обновлений
+    fn recorder(seen: Seen) -> UpdateHandler {
+        Box::new(move |_, upd| {
+            let seen: Seen = seen.clone();
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis((upd.update_id * 7 % 5) as u64)).await;
+                let chat_id: i64 = upd.message.as_ref().map(|m| m.chat.id).unwrap_or_default();
+                seen.lock().unwrap().push((chat_id, upd.update_id));
+            })
+        })
+    }
+
+    fn update(id: i64, chat_id: i64) -> serde_json::Value {
+        serde_json::json!({
+            "update_id": id,
+            "message": {"message_id": id, "chat": {"id": chat_id, "type": "supergroup"}, "text": "привет"},
+        })
+    }
+
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("обновления не обработаны");
+    }
+
+    /// Ждёт, пока обработанные обновления удалятся из базы
+    async fn wait_saved_cleared(dispatcher: &Dispatcher) {
+        for _ in 0..200 {
+            if dispatcher.state().storage.load_pending_updates().await.unwrap().is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("обработанные обновления остались в базе");
+    }
+
+    #[tokio::test]
+    async fn keeps_order_within_chat() {
+        let seen: Seen = Seen::default();
+        let dispatcher: Arc<Dispatcher> = dispatcher("max_queued_updates = 3", recorder(seen.clone())).await;
+        for id in 0..60 {
+            dispatcher.accept(update(id, -(id % 3) - 1)).await.unwrap();
+        }
+        wait_until(|| seen.lock().unwrap().len() == 60).await;
+
+        for chat_id in [-1, -2, -3] {
+            let ids: Vec<i64> = seen.lock().unwrap().iter().filter(|(c, _)| *c == chat_id).map(|(_, id)| *id).collect();
+            assert_eq!(ids.len(), 20);
+            assert!(ids.windows(2).all(|w| w[0] < w[1]), "чат {chat_id}: {ids:?}");
+        }
+        wait_saved_cleared(&dispatcher).await;
+    }
+
+    #[tokio::test]
+    async fn respects_concurrency_limit() {
+        let (active, peak, finished) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let handler: UpdateHandler = {
+            let (active, peak, finished) = (active.clone(), peak.clone(), finished.clone());
+            Box::new(move |_, _| {
+                let (active, peak, finished) = (active.clone(), peak.clone(), finished.clone());
+                Box::pin(async move {
+                    peak.fetch_max(active.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    finished.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+        };
+        let dispatcher: Arc<Dispatcher> = dispatcher("max_concurrent_updates = 2", handler).await;
+        for id in 0..12 {
+            dispatcher.accept(update(id, -id - 1)).await.unwrap();
+        }
+        wait_until(|| finished.load(Ordering::SeqCst) == 12).await;
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn resumes_saved_updates() {
+        let seen: Seen = Seen::default();
+        let dispatcher: Arc<Dispatcher> = dispatcher("", recorder(seen.clone())).await;
+        let storage = &dispatcher.state().storage;
+        for id in [10, 11, 12] {
+            storage.save_pending_update(id, update(id, -1).to_string()).await.unwrap();
+        }
+        storage.save_pending_update(13, "не JSON".to_string()).await.unwrap();
+
+        assert_eq!(dispatcher.resume_pending().await, Some(13));
+        wait_until(|| seen.lock().unwrap().len() == 3).await;
+        assert_eq!(*seen.lock().unwrap(), vec![(-1, 10), (-1, 11), (-1, 12)]);
+        wait_saved_cleared(&dispatcher).await;
+    }
+
+    #[tokio::test]
+    async fn full_queue_holds_back_new_updates() {
+        let gate: Arc<Semaphore> = Arc::new(Semaphore::new(0));
+        let handler: UpdateHandler = {
+            let gate: Arc<Semaphore> = gate.clone();
+            Box::new(move |_, _| {
+                let gate: Arc<Semaphore> = gate.clone();
+                Box::pin(async move {
+                    gate.acquire().await.unwrap().forget();
+                })
+            })
+        };
+        let dispatcher: Arc<Dispatcher> = dispatcher("max_queued_updates = 1", handler).await;
+        // Первое обновление обрабатывается, второе ждёт в очереди, третьему места нет
+        dispatcher.accept(update(1, -1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        dispatcher.accept(update(2, -1)).await.unwrap();
+        let third = tokio::time::timeout(Duration::from_millis(50), dispatcher.accept(update(3, -1))).await;
+        assert!(third.is_err());
+        // Другой чат не ждёт
+        dispatcher.accept(update(4, -2)).await.unwrap();
+
+        gate.add_permits(10);
+        dispatcher.accept(update(3, -1)).await.unwrap();
+        wait_saved_cleared(&dispatcher).await;
+    }
+
+    #[tokio::test]
+    async fn skips_unparseable_updates() {
+        let seen: Seen = Seen::default();
+        let dispatcher: Arc<Dispatcher> = dispatcher("", recorder(seen.clone())).await;
+        dispatcher.accept(serde_json::json!({"message": {}})).await.unwrap();
+        assert!(dispatcher.state().storage.load_pending_updates().await.unwrap().is_empty());
+        assert!(seen.lock().unwrap().is_empty());
+    }
+}
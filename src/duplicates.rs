@@ -0,0 +1,265 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+};
+
+use crate::{actions::unix_now, config::Config, normalize::normalize, spam_checker::SpamVerdict};
+
+/// Сколько последних сообщений хранится самое большее, даже если окно ещё не прошло
+const MAX_RECENT_MESSAGES: usize = 5000;
+
+/// Сравнивается только начало текста — столько же получает классификатор
+const FINGERPRINT_CHARS: usize = 250;
+
+/// Недавнее сообщение и оценка, которую оно получило
+struct RecentMessage {
+    fingerprint: u64,
+    at: i64,
+    chat_id: i64,
+    user_id: i64,
+    score: u8,
+    notes: String,
+    /// Спам подтверждён: бот применил к нему действия или так решил модератор
+    confirmed_spam: bool,
+}
+
+/// Поиск почти одинаковых сообщений за последнее время во всех чатах.
+/// Повтор получает оценку исходного сообщения без обращения к классификатору,
+/// а много копий от разных пользователей или в разных чатах считаются флудом.
+#[derive(Default)]
+pub struct DuplicateDetector {
+    recent: Mutex<VecDeque<RecentMessage>>,
+}
+
+impl DuplicateDetector {
+    /// Оценка для повтора недавнего сообщения: подтверждённый спам, флуд или оценка последней копии.
+    /// `None`, если похожих сообщений в окне нет.
+    pub fn check(&self, config: &Config, fingerprint: u64, chat_id: i64, user_id: i64) -> Option<SpamVerdict> {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        prune(&mut recent, config);
+        let matches: Vec<&RecentMessage> = recent
+            .iter()
+            .filter(|m| (m.fingerprint ^ fingerprint).count_ones() <= config.duplicate_distance)
+            .collect();
+        let latest: &RecentMessage = matches.last()?;
+
+        if let Some(spam) = matches.iter().rev().find(|m| m.confirmed_spam) {
+            return Some(SpamVerdict {
+                spam_score: spam.score,
+                notes: format!("повтор спама: {}", spam.notes),
+                classifier: "duplicates".to_string(),
+                prompt_version: None,
+            });
+        }
+
+        let copies: usize = matches.len() + 1;
+        if config.duplicate_flood_count > 0 && copies >= config.duplicate_flood_count {
+            let users: HashSet<i64> = matches.iter().map(|m| m.user_id).chain([user_id]).collect();
+            let chats: HashSet<i64> = matches.iter().map(|m| m.chat_id).chain([chat_id]).collect();
+            return Some(SpamVerdict {
+                spam_score: 100,
+                notes: format!(
+                    "флуд: {copies} похожих сообщений от {} пользователей в {} чатах",
+                    users.len(),
+                    chats.len()
+                ),
+                classifier: "duplicates".to_string(),
+                prompt_version: None,
+            });
+        }
+
+        Some(SpamVerdict {
+            spam_score: latest.score,
+            notes: format!("повтор: {}", latest.notes),
+            classifier: "duplicates".to_string(),
+            prompt_version: None,
+        })
+    }
+
+    /// Запоминает сообщение с вынесенной ему оценкой
+    pub fn remember(
+        &self,
+        config: &Config,
+        fingerprint: u64,
+        chat_id: i64,
+        user_id: i64,
+        verdict: &SpamVerdict,
+        confirmed_spam: bool,
+    ) {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        prune(&mut recent, config);
+        recent.push_back(RecentMessage {
+            fingerprint,
+            at: unix_now(),
+            chat_id,
+            user_id,
+            score: verdict.spam_score,
+            notes: verdict.notes.clone(),
+            confirmed_spam,
+        });
+    }
+
+    /// Применяет решение модератора ко всем недавним копиям текста,
+    /// чтобы следующие повторы сразу получали ту же оценку
+    pub fn confirm(&self, config: &Config, text: &str, chat_id: i64, user_id: i64, is_spam: bool) {
+        let Some(fingerprint) = fingerprint(config, text) else {
+            return;
+        };
+        let (score, notes): (u8, &str) =
+            if is_spam { (100, "подтверждено модератором") } else { (0, "одобрено модератором") };
+        {
+            let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+            for m in recent
+                .iter_mut()
+                .filter(|m| (m.fingerprint ^ fingerprint).count_ones() <= config.duplicate_distance)
+            {
+                m.score = score;
+                m.notes = notes.to_string();
+                m.confirmed_spam = is_spam;
+            }
+        }
+        let verdict: SpamVerdict = SpamVerdict {
+            spam_score: score,
+            notes: notes.to_string(),
+            classifier: "moderator".to_string(),
+            prompt_version: None,
+        };
+        self.remember(config, fingerprint, chat_id, user_id, &verdict, is_spam);
+    }
+}
+
+/// Убирает сообщения старше окна и сверх лимита
+fn prune(recent: &mut VecDeque<RecentMessage>, config: &Config) {
+    let since: i64 = unix_now() - i64::from(config.duplicate_window_minutes) * 60;
+    while recent
+        .front()
+        .is_some_and(|m| m.at < since || recent.len() > MAX_RECENT_MESSAGES)
+    {
+        recent.pop_front();
+    }
+}
+
+/// SimHash текста по символьным триграммам: у почти одинаковых текстов отличается лишь несколько бит.
+/// `None` для слишком коротких текстов и при выключенном поиске повторов — у коротких фраз
+/// вроде «спасибо» совпадения случайны.
+pub fn fingerprint(config: &Config, text: &str) -> Option<u64> {
+    if config.duplicate_window_minutes == 0 {
+        return None;
+    }
+    // Регистр, пунктуация, лишние пробелы и маскировка не делают сообщение другим.
+    // Решения модераторов приходят с исходным текстом, поэтому нормализуем и здесь
+    let head: String = normalize(text).text.chars().take(FINGERPRINT_CHARS).collect();
+    let normalized: Vec<char> = head
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+        .chars()
+        .collect();
+    if normalized.len() < config.duplicate_min_length {
+        return None;
+    }
+
+    let mut weights: [i32; 64] = [0; 64];
+    for trigram in normalized.windows(3) {
+        let mut hasher: DefaultHasher = DefaultHasher::new();
+        trigram.hash(&mut hasher);
+        let hash: u64 = hasher.finish();
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if hash >> bit & 1 == 1 { 1 } else { -1 };
+        }
+    }
+    Some(
+        weights
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| **w > 0)
+            .fold(0, |acc, (bit, _)| acc | 1 << bit),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AD: &str = "Ищу ответственных людей для удалённой работы, пишите в личные сообщения за подробностями";
+
+    fn config(extra: &str) -> Config {
+        Config::from_toml(&format!("telegram_bot_token = \"x\"\n{extra}")).unwrap()
+    }
+
+    fn verdict(score: u8) -> SpamVerdict {
+        SpamVerdict { spam_score: score, notes: "реклама".to_string(), classifier: "llm".to_string(), prompt_version: None }
+    }
+
+    #[test]
+    fn fingerprint_skips_short_texts_and_disabled_detector() {
+        assert_eq!(fingerprint(&config(""), "спасибо, всё работает"), None);
+        assert_eq!(fingerprint(&config("duplicate_window_minutes = 0"), AD), None);
+        assert!(fingerprint(&config(""), AD).is_some());
+    }
+
+    #[test]
+    fn fingerprint_ignores_case_punctuation_and_small_edits() {
+        let config: Config = config("");
+        let original: u64 = fingerprint(&config, AD).unwrap();
+        let shouted: u64 = fingerprint(&config, &format!("{}!!!", AD.to_uppercase())).unwrap();
+        assert_eq!(original, shouted);
+        let edited: u64 = fingerprint(&config, &AD.replace("личные", "лс")).unwrap();
+        assert!((original ^ edited).count_ones() <= config.duplicate_distance);
+        let other: u64 = fingerprint(&config, "Почему в узле указатель на родителя, а не слайс дочерних узлов?").unwrap();
+        assert!((original ^ other).count_ones() > config.duplicate_distance);
+    }
+
+    #[test]
+    fn check_returns_score_of_remembered_copy() {
+        let config: Config = config("");
+        let detector: DuplicateDetector = DuplicateDetector::default();
+        let print: u64 = fingerprint(&config, AD).unwrap();
+        assert!(detector.check(&config, print, -100, 1).is_none());
+
+        detector.remember(&config, print, -100, 1, &verdict(40), false);
+        let repeat: SpamVerdict = detector.check(&config, print, -100, 2).unwrap();
+        assert_eq!(repeat.spam_score, 40);
+        assert_eq!(repeat.notes, "повтор: реклама");
+    }
+
+    #[test]
+    fn check_prefers_confirmed_spam() {
+        let config: Config = config("");
+        let detector: DuplicateDetector = DuplicateDetector::default();
+        let print: u64 = fingerprint(&config, AD).unwrap();
+        detector.remember(&config, print, -100, 1, &verdict(95), true);
+        detector.remember(&config, print, -200, 2, &verdict(10), false);
+        let repeat: SpamVerdict = detector.check(&config, print, -300, 3).unwrap();
+        assert_eq!(repeat.spam_score, 95);
+        assert!(repeat.notes.starts_with("повтор спама"));
+    }
+
+    #[test]
+    fn check_reports_flood_across_users_and_chats() {
+        let config: Config = config("duplicate_flood_count = 3");
+        let detector: DuplicateDetector = DuplicateDetector::default();
+        let print: u64 = fingerprint(&config, AD).unwrap();
+        detector.remember(&config, print, -100, 1, &verdict(10), false);
+        assert_eq!(detector.check(&config, print, -200, 2).unwrap().spam_score, 10);
+        detector.remember(&config, print, -200, 2, &verdict(10), false);
+        let flood: SpamVerdict = detector.check(&config, print, -200, 3).unwrap();
+        assert_eq!(flood.spam_score, 100);
+        assert_eq!(flood.notes, "флуд: 3 похожих сообщений от 3 пользователей в 2 чатах");
+    }
+
+    #[test]
+    fn moderator_decision_applies_to_recent_copies() {
+        let config: Config = config("");
+        let detector: DuplicateDetector = DuplicateDetector::default();
+        let print: u64 = fingerprint(&config, AD).unwrap();
+        detector.remember(&config, print, -100, 1, &verdict(40), false);
+        detector.confirm(&config, AD, -100, 1, false);
+        let repeat: SpamVerdict = detector.check(&config, print, -100, 2).unwrap();
+        assert_eq!(repeat.spam_score, 0);
+        assert_eq!(repeat.notes, "повтор: одобрено модератором");
+    }
+}
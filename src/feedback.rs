@@ -0,0 +1,182 @@
+use anyhow::Result;
+use reqwest::Client;
+
+use crate::{
+    actions::{apply_spam_actions, revert_spam_actions, SpamTarget},
+    chat_settings::ChatConfig,
+    commands::is_admin,
+    config::Config,
+    handlers::{send_for_review, spam_warning_text},
+    normalize::normalize,
+    state::{chat_config, record_moderation, relabel_counter, revoke_whitelist, AppState},
+    storage::{LabelledExample, ModerationEvent},
+    telegram_api::{answer_callback_query, edit_message_text, CallbackQuery, Message, User},
+};
+
+/// Сохраняет размеченный пример в датасет. С `train` дообучает на нём статистическую модель —
+/// только для меток, подтверждённых модератором или администратором.
+pub async fn record_label(state: &AppState, example: LabelledExample, train: bool) {
+    if train
+        && !example.text.is_empty()
+        && let Err(err) = state.bayes.train(&normalize(&example.text).text, example.is_spam).await
+    {
+        log::warn!("Не удалось дообучить статистическую модель: {err:?}");
+    }
+    if let Err(err) = state.storage.record_example(example).await {
+        log::warn!("Не удалось сохранить размеченный пример: {err:?}");
+    }
+}
+
+/// `/spam` ответом на сообщение. От администратора (`admin`) — сразу применяет к сообщению политику чата,
+/// от участника — отправляет жалобу модераторам. Возвращает ответ для чата или `None`,
+/// если это не жалоба: команда без ответа на сообщение или ответ на сообщение бота.
+pub async fn report_spam(
+    client: &Client,
+    base_url: &str,
+    msg: &Message,
+    admin: bool,
+    state: &AppState,
+    config: &Config,
+) -> Result<Option<String>> {
+    let Some(reported) = msg.reply_to_message.as_deref() else {
+        return Ok(None);
+    };
+    let Some(author) = reported.from.as_ref().filter(|u| !u.is_bot) else {
+        return Ok(None);
+    };
+    let reporter: String = user_tag(msg.from.as_ref(), msg.chat.id);
+    let text: String = reported.content_text();
+    let target: SpamTarget = SpamTarget { chat_id: msg.chat.id, message_id: reported.message_id, user_id: author.id };
+    let chat: ChatConfig = chat_config(state, config, msg.chat.id).await;
+
+    let example: LabelledExample = LabelledExample {
+        chat_id: target.chat_id,
+        user_id: target.user_id,
+        message_id: Some(target.message_id),
+        text: text.clone(),
+        is_spam: true,
+        reporter: Some(reporter.clone()),
+        ..Default::default()
+    };
+
+    if admin {
+        let warn_text: String = spam_warning_text(&chat, 100, "отмечено администратором");
+        apply_spam_actions(client, base_url, target, &chat.spam_actions, chat.mute_minutes, &warn_text, None).await.ok();
+        relabel_counter(target.user_id, state, true).await?;
+        revoke_whitelist(state, target.chat_id, target.user_id, &reporter, "спам по решению администратора").await?;
+        record_moderation(state, ModerationEvent {
+            chat_id: target.chat_id,
+            user_id: target.user_id,
+            message_id: Some(target.message_id),
+            action: "moderator_spam".to_string(),
+            actor: Some(reporter),
+            text: Some(text),
+            ..Default::default()
+        }).await;
+        state.duplicates.confirm(config, &example.text, target.chat_id, target.user_id, true);
+        record_label(state, LabelledExample { source: "spam_command".to_string(), ..example }, true).await;
+        return Ok(Some("Сообщение отмечено как спам".to_string()));
+    }
+
+    record_moderation(state, ModerationEvent {
+        chat_id: target.chat_id,
+        user_id: target.user_id,
+        message_id: Some(target.message_id),
+        action: "spam_report".to_string(),
+        actor: Some(reporter.clone()),
+        text: Some(text.clone()),
+        ..Default::default()
+    }).await;
+    record_label(state, LabelledExample { source: "user_report".to_string(), ..example }, false).await;
+
+    if chat.review_chat_id.is_none() {
+        return Ok(Some("Спасибо, жалоба сохранена".to_string()));
+    }
+    let chat_name: String = msg.chat.title.clone().unwrap_or_else(|| msg.chat.id.to_string());
+    let review_text: String = format!(
+        "Жалоба от {reporter} на {} в чате «{chat_name}»\n\n{text}",
+        user_tag(Some(author), author.id)
+    );
+    send_for_review(client, base_url, &chat, target, &review_text, false).await;
+    Ok(Some("Спасибо, жалоба передана модераторам".to_string()))
+}
+
+/// Кнопка «Не спам» под предупреждением бота: администратор отменяет ошибочное срабатывание.
+/// Без предупреждения в чате кнопка приходит в чат модераторов, где её может нажать любой
+/// модератор, или получателю уведомлений. Снимает мьют и бан, исправляет счётчики
+/// и сохраняет сообщение как пример не-спама.
+pub async fn handle_false_positive(
+    client: &Client,
+    base_url: &str,
+    cq: &CallbackQuery,
+    target: SpamTarget,
+    state: &AppState,
+    config: &Config,
+) -> Result<()> {
+    let chat: ChatConfig = chat_config(state, config, target.chat_id).await;
+    let Some(warning) = cq
+        .message
+        .as_ref()
+        .filter(|m| m.chat.id == target.chat_id || Some(m.chat.id) == chat.undo_chat_id())
+    else {
+        answer_callback_query(client, base_url, &cq.id, "Недоступно").await.ok();
+        return Ok(());
+    };
+    if Some(warning.chat.id) != chat.review_chat_id && !is_admin(client, base_url, target.chat_id, cq.from.id).await {
+        answer_callback_query(client, base_url, &cq.id, "Только для администраторов чата").await.ok();
+        return Ok(());
+    }
+
+    let admin: String = user_tag(Some(&cq.from), cq.from.id);
+    revert_spam_actions(client, base_url, target, &chat.spam_actions).await;
+    relabel_counter(target.user_id, state, false).await?;
+
+    let text: Option<String> = state.storage.moderated_text(target.chat_id, target.message_id).await?;
+    record_moderation(state, ModerationEvent {
+        chat_id: target.chat_id,
+        user_id: target.user_id,
+        message_id: Some(target.message_id),
+        action: "false_positive".to_string(),
+        actor: Some(admin.clone()),
+        text: text.clone(),
+        ..Default::default()
+    }).await;
+    if let Some(text) = text {
+        state.duplicates.confirm(config, &text, target.chat_id, target.user_id, false);
+        record_label(state, LabelledExample {
+            chat_id: target.chat_id,
+            user_id: target.user_id,
+            message_id: Some(target.message_id),
+            text,
+            is_spam: false,
+            source: "false_positive".to_string(),
+            reporter: Some(admin.clone()),
+            ..Default::default()
+        }, true).await;
+    }
+
+    log::info!(
+        "Администратор {admin} отменил срабатывание на сообщение {} в чате {}",
+        target.message_id, target.chat_id
+    );
+
+    let original: &str = warning.text.as_deref().unwrap_or_default();
+    edit_message_text(
+        client,
+        base_url,
+        warning.chat.id,
+        warning.message_id,
+        &format!("{original}\n\nОшибка, не спам ({admin})"),
+    ).await.ok();
+    answer_callback_query(client, base_url, &cq.id, "Отмечено: не спам").await.ok();
+    Ok(())
+}
+
+/// `@username` или `id N` для сообщений и истории модерации
+fn user_tag(user: Option<&User>, fallback_id: i64) -> String {
+    match user {
+        Some(User { username: Some(username), .. }) => format!("@{username}"),
+        Some(user) => format!("id {}", user.id),
+        None => format!("id {fallback_id}"),
+    }
+}
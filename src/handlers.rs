@@ -2,29 +2,100 @@ use anyhow::Result;
 use reqwest::Client;
 
 use crate::{
+    actions::{apply_spam_actions, parse_false_positive_callback, SpamAction, SpamTarget, UndoNotice},
+    captcha::{handle_captcha_callback, parse_captcha_callback, screen_new_members},
+    chat_settings::{ChatConfig, ScoreBand},
+    commands::handle_command,
     config::Config,
-    spam_checker::check_spam_via_ollama,
-    state::{add_user_to_whitelist, increment_ham_counter, is_user_whitelisted, AppState},
-    telegram_api::{send_message, Message},
+    duplicates,
+    entities::MessageFeatures,
+    feedback::{handle_false_positive, record_label},
+    normalize::{normalize, Normalized},
+    probation::{is_risky, on_probation, ProbationMode},
+    recheck::{schedule_recheck, should_recheck, RecheckJob},
+    resilience::FailurePolicy,
+    spam_checker::{ClassifyInput, SpamVerdict},
+    state::{
+        add_user_to_whitelist, increment_ham_counter, increment_spam_counter, is_user_whitelisted,
+        chat_config, record_moderation, revoke_whitelist, AppState,
+    },
+    storage::{LabelledExample, ModerationEvent, WhitelistEntry},
+    telegram_api::{
+        answer_callback_query, delete_message, edit_message_text, send_message, send_message_with_keyboard, CallbackQuery, Message,
+        TgUpdate, User,
+    },
 };
 
+/// Префикс callback_data кнопок ручной проверки: `rv:<s|h>:<chat_id>:<message_id>:<user_id>[:1]`.
+/// Последнее поле отмечает сообщение, скрытое до решения модераторов.
+const REVIEW_CALLBACK_PREFIX: &str = "rv";
+
+/// Имя в вердикте, вынесенном политикой отказа при недоступном классификаторе
+const UNAVAILABLE_CLASSIFIER: &str = "failure_policy";
+
+/// Передаёт обновление Telegram подходящему обработчику.
+pub async fn handle_update(
+    client: &Client,
+    base_url: &str,
+    upd: &TgUpdate,
+    state: &AppState,
+    config: &Config,
+) -> Result<()> {
+    if let Some(msg) = upd.message.as_ref() {
+        if msg.new_chat_members.is_empty() {
+            handle_message(client, base_url, msg, false, state, config).await?;
+        } else {
+            let users: Vec<&User> = msg.new_chat_members.iter().collect();
+            screen_new_members(client, base_url, state, config, msg.chat.id, &users).await?;
+        }
+    }
+    if let Some(member) = upd.chat_member.as_ref()
+        && !member.old_chat_member.in_chat()
+        && member.new_chat_member.in_chat()
+    {
+        screen_new_members(client, base_url, state, config, member.chat.id, &[&member.new_chat_member.user]).await?;
+    }
+    if let Some(msg) = upd.edited_message.as_ref() {
+        handle_message(client, base_url, msg, true, state, config).await?;
+    }
+    if let Some(cq) = upd.callback_query.as_ref() {
+        handle_callback_query(client, base_url, cq, state, config).await?;
+    }
+    Ok(())
+}
+
 /// Основная функция обработки сообщений:
-/// 1. Проверяет входящее сообщение на спам через Ollama
-/// 2. Для спама — отправляет предупреждение в чат
-/// 3. Для не-спама — увеличивает счётчик и добавляет пользователя в вайтлист при достижении порога
+/// 1. Проверяет входящее сообщение на спам выбранным классификатором
+/// 2. Для спама — применяет политику действий чата (удаление, мьют, бан, кик или предупреждение)
+/// 3. Для сомнительных — отправляет сообщение модераторам на ручную проверку
+/// 4. Для не-спама — увеличивает счётчик и добавляет пользователя в вайтлист при достижении порога
+///
+/// Отредактированные сообщения (`edited`) проверяются так же, но не засчитываются в вайтлист
+/// и не разбираются как команды.
 pub async fn handle_message(
     client: &Client,
     base_url: &str,
     msg: &Message,
+    edited: bool,
     state: &AppState,
     config: &Config,
 ) -> Result<()> {
-    // Проверяем наличие текста
-    let Some(text) = msg.text.as_deref().filter(|t| !t.trim().is_empty()) else {
+    // Команды проверяются до отсева ботов: анонимные администраторы пишут от имени GroupAnonymousBot
+    if !edited
+        && let Some(command) = msg.text.as_deref()
+        && handle_command(client, base_url, msg, command, state, config).await?
+    {
         return Ok(());
-    };
-    let text: &str = text.trim();
-    let truncated_text: String = text.chars().take(250).collect();
+    }
+
+    // Проверяем весь видимый текст: сообщение, подпись к медиа, опрос, источник пересылки
+    let content: String = msg.content_text();
+    if content.is_empty() {
+        return Ok(());
+    }
+    let text: &str = content.as_str();
+    // Классификаторы видят текст без маскировки, модераторам и в журнал идёт исходный
+    let normalized: Normalized = normalize(text);
 
     let Some(user) = msg.from.as_ref() else {
         return Ok(());
@@ -36,64 +107,385 @@ pub async fn handle_message(
 
     let user_id: i64 = user.id;
 
+    let chat: ChatConfig = chat_config(state, config, msg.chat.id).await;
+    let features: MessageFeatures = MessageFeatures::extract(msg).without_domains(&chat.allowed_domains);
+    let blocked_domain: Option<String> = features.blocked_domain(&chat.blocked_domains);
+
+    let username_tag: String = user
+        .username
+        .as_ref()
+        .map(|u| format!("@{u}"))
+        .unwrap_or_else(|| format!("id {user_id}"));
+    let target: SpamTarget = SpamTarget { chat_id: msg.chat.id, message_id: msg.message_id, user_id };
+    let chat_name: String = msg.chat.title.clone().unwrap_or_else(|| msg.chat.id.to_string());
+    let input: ClassifyInput = ClassifyInput {
+        text: normalized.text,
+        user_id,
+        model: chat.model.clone(),
+        template: config.prompts.get(chat.template.as_deref()),
+        topic: chat.topic.clone(),
+        prompt: chat.prompt.clone(),
+        language: chat.language.clone(),
+        features,
+        obfuscation: normalized.obfuscation,
+    };
+
+    // Ссылка на домен из чёрного списка и правила с жёстким действием решают без классификатора
+    let hard_verdict: Option<SpamVerdict> = match blocked_domain {
+        Some(domain) => Some(SpamVerdict {
+            spam_score: 100,
+            notes: format!("ссылка на запрещённый домен {domain}"),
+            classifier: "domains".to_string(),
+            prompt_version: None,
+        }),
+        None => state.rules.get().and_then(|rules| rules.hard_verdict(&input, config.new_account_id_from)),
+    };
+
+    // Жёсткие правила действуют и на вайтлист: доверенный аккаунт мог быть взломан
     if is_user_whitelisted(user_id, state).await? {
-        log::debug!("Пользователь {} в белом списке", user_id);
-        return Ok(());
+        let Some(spam) = hard_verdict.as_ref().filter(|v| v.spam_score > 0) else {
+            log::debug!("Пользователь {} в белом списке", user_id);
+            // Часть сообщений доверенных пользователей перепроверяется в фоне
+            if should_recheck(config, &input.features) {
+                schedule_recheck(state, RecheckJob { target, username_tag, chat_name, input });
+            }
+            return Ok(());
+        };
+        revoke_whitelist(state, msg.chat.id, user_id, "auto", &spam.notes).await?;
     }
 
-    // Проверяем сообщение через Ollama
-    let llm: crate::spam_checker::LlmSpamResult = match check_spam_via_ollama(client, &truncated_text, "http://127.0.0.1:11434", &config.ollama_model).await {
-        Ok(v) => v,
-        Err(err) => {
-            log::warn!("Ошибка проверки спама: {err:?}");
-            return Ok(());
+    // Ссылки, упоминания, пересылки и медиа от новичков проверяются строже
+    let (ham_count, first_seen) = state.storage.user_progress(user_id).await?;
+    let probation: bool = is_risky(msg, &input.features) && on_probation(&chat, ham_count, first_seen);
+
+    // Повтор недавнего сообщения получает его оценку без обращения к классификатору
+    let fingerprint: Option<u64> = duplicates::fingerprint(config, &input.text);
+    let llm: SpamVerdict = if let Some(verdict) = hard_verdict {
+        verdict
+    } else if let Some(verdict) = fingerprint.and_then(|fp| state.duplicates.check(config, fp, msg.chat.id, user_id)) {
+        verdict
+    } else {
+        match state.classifier().classify(&input).await {
+            Ok(v) => v,
+            Err(err) => {
+                log::warn!("Ошибка проверки спама: {err:?}");
+                match config.failure_policy {
+                    FailurePolicy::Open => return Ok(()),
+                    // Решение за модераторами, если они есть; иначе безопаснее считать спамом
+                    FailurePolicy::Closed => SpamVerdict {
+                        spam_score: chat.review_threshold.filter(|_| chat.review_chat_id.is_some()).unwrap_or(100),
+                        notes: "классификатор недоступен".to_string(),
+                        classifier: UNAVAILABLE_CLASSIFIER.to_string(),
+                        prompt_version: None,
+                    },
+                }
+            }
         }
     };
-    
-    log::info!("Оценка спама: {}%, причины: {}", llm.spam_score, llm.notes);
-    
-    if llm.spam_score >= config.spam_threshold {
-        // Сообщение определено как спам
-        let mention: String = config.tag_username
-            .as_ref()
-            .map(|u| format!("@{u} "))
-            .unwrap_or_default();
 
-        send_message(
+    log::info!(
+        "Оценка спама ({}{}){}{}: {}%, причины: {}",
+        llm.classifier,
+        llm.prompt_version.as_deref().map(|v| format!(", промпт {v}")).unwrap_or_default(),
+        if edited { ", правка" } else { "" },
+        if probation { ", испытательный срок" } else { "" },
+        llm.spam_score,
+        llm.notes
+    );
+
+    let band: ScoreBand = if probation { chat.probation_band(llm.spam_score) } else { chat.score_band(llm.spam_score) };
+    let hold: bool = probation && chat.probation_mode == ProbationMode::Hold && chat.review_chat_id.is_some();
+
+    // Правки не запоминаются, иначе каждая из них считалась бы ещё одной копией для флуда.
+    // Спам по сниженному порогу испытательного срока не подтверждает текст для остальных,
+    // а оценка при недоступном классификаторе не переносится на копии
+    if let Some(fp) = fingerprint
+        && !edited
+        && llm.classifier != UNAVAILABLE_CLASSIFIER
+    {
+        let confirmed: bool = band == ScoreBand::Spam && !probation;
+        state.duplicates.remember(config, fp, msg.chat.id, user_id, &llm, confirmed);
+    }
+
+    match band {
+        ScoreBand::Spam => {
+            let warn_text: String = spam_warning_text(&chat, llm.spam_score, &llm.notes);
+            let undo_text: String = format!("{warn_text}\n{username_tag} в чате «{chat_name}»\n\n{text}");
+            let undo: Option<UndoNotice<'_>> = chat.undo_chat_id().map(|chat_id| UndoNotice { chat_id, text: &undo_text });
+            apply_spam_actions(
+                client,
+                base_url,
+                target,
+                &chat.spam_actions,
+                chat.mute_minutes,
+                &warn_text,
+                undo,
+            ).await.ok();
+            increment_spam_counter(user_id, state).await?;
+            record_moderation(state, verdict_event(target, "auto_spam", &llm, text)).await;
+        }
+        // Сообщение новичка скрывается, пока модератор не одобрит его
+        _ if hold => {
+            if let Err(err) = delete_message(client, base_url, target.chat_id, target.message_id).await {
+                log::warn!("Не удалось скрыть сообщение новичка: {err:?}");
+            }
+            let review_text: String = format!(
+                "Скрыто до проверки ({}%, {}): новичок {username_tag} в чате «{chat_name}»\n\n{text}",
+                llm.spam_score, llm.notes
+            );
+            send_for_review(client, base_url, &chat, target, &review_text, true).await;
+            record_moderation(state, verdict_event(target, "held", &llm, text)).await;
+        }
+        ScoreBand::Review => {
+            let review_text: String = format!(
+                "На проверку ({}%, {}): {username_tag} в чате «{chat_name}»\n\n{text}",
+                llm.spam_score, llm.notes
+            );
+            send_for_review(client, base_url, &chat, target, &review_text, false).await;
+            record_moderation(state, verdict_event(target, "review", &llm, text)).await;
+        }
+        // Правка не должна приближать к вайтлисту: иначе одно сообщение можно засчитать много раз
+        ScoreBand::Ham if edited => {}
+        ScoreBand::Ham => {
+            register_ham(client, base_url, state, config, &chat, user_id, &username_tag).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Обрабатывает нажатия на inline-кнопки: ответы на проверку новых участников,
+/// вердикты модераторов по полосе проверки и «Не спам» под предупреждениями бота.
+pub async fn handle_callback_query(
+    client: &Client,
+    base_url: &str,
+    cq: &CallbackQuery,
+    state: &AppState,
+    config: &Config,
+) -> Result<()> {
+    let Some(data) = cq.data.as_deref() else {
+        return Ok(());
+    };
+    if let Some((user_id, answer)) = parse_captcha_callback(data) {
+        return handle_captcha_callback(client, base_url, cq, user_id, answer, state).await;
+    }
+    if let Some(target) = parse_false_positive_callback(data) {
+        return handle_false_positive(client, base_url, cq, target, state, config).await;
+    }
+    let Some((is_spam, target, held)) = parse_review_callback(data) else {
+        answer_callback_query(client, base_url, &cq.id, "Неизвестная кнопка").await.ok();
+        return Ok(());
+    };
+
+    // Вердикты принимаются только из чата модераторов исходного чата
+    let chat: ChatConfig = chat_config(state, config, target.chat_id).await;
+    let Some(review_msg) = cq.message.as_ref().filter(|m| Some(m.chat.id) == chat.review_chat_id) else {
+        answer_callback_query(client, base_url, &cq.id, "Недоступно").await.ok();
+        return Ok(());
+    };
+
+    let moderator: String = cq
+        .from
+        .username
+        .as_ref()
+        .map(|u| format!("@{u}"))
+        .unwrap_or_else(|| format!("id {}", cq.from.id));
+
+    // Текст исходного сообщения идёт в посте проверки после заголовка
+    let original: &str = review_msg.text.as_deref().unwrap_or_default();
+    let reviewed_text: Option<&str> = original.split_once("\n\n").map(|(_, t)| t);
+
+    let verdict: &str = if is_spam {
+        let warn_text: String = spam_warning_text(&chat, 100, "подтверждено модератором");
+        // Скрытое сообщение уже удалено
+        let actions: Vec<SpamAction> = chat
+            .spam_actions
+            .iter()
+            .copied()
+            .filter(|a| !held || *a != SpamAction::Delete)
+            .collect();
+        apply_spam_actions(
             client,
             base_url,
-            msg.chat.id,
-            &format!("{mention}СПАМ ({}%). Причина: {}", llm.spam_score, llm.notes),
-            Some(msg.message_id),
+            target,
+            &actions,
+            chat.mute_minutes,
+            &warn_text,
+            None,
         ).await.ok();
+        increment_spam_counter(target.user_id, state).await?;
+        revoke_whitelist(state, target.chat_id, target.user_id, &moderator, "спам по решению модератора").await?;
+        "СПАМ"
     } else {
-        // Сообщение не спам - увеличиваем счетчик
-        let count = increment_ham_counter(user_id, state).await;
-
-        // Добавляем в вайтлист после достижения порога
-        if count >= config.ham_threshold {
-            add_user_to_whitelist(user_id, state, &config.whitelist_path).await?;
-            
-            let username_tag: String = msg.from
-                .as_ref()
-                .and_then(|u| u.username.as_ref())
-                .map(|u| format!("@{u}"))
-                .unwrap_or_else(|| format!("id {user_id}"));
-
-            let target_chat: i64 = config.notify_user_id.unwrap_or(msg.chat.id);
+        if held && let Some(text) = reviewed_text {
             send_message(
                 client,
                 base_url,
-                target_chat,
-                &format!(
-                    "Пользователь {username_tag} добавлен в белый список после {} корректных сообщений",
-                    config.ham_threshold
-                ),
+                target.chat_id,
+                &format!("Сообщение id {} одобрено модератором:\n\n{text}", target.user_id),
                 None,
             ).await.ok();
         }
+        register_ham(
+            client,
+            base_url,
+            state,
+            config,
+            &chat,
+            target.user_id,
+            &format!("id {}", target.user_id),
+        ).await?;
+        "не спам"
+    };
+
+    log::info!(
+        "Модератор {moderator} отметил сообщение {} в чате {} как {verdict}",
+        target.message_id, target.chat_id
+    );
+
+    if let Some(reviewed_text) = reviewed_text {
+        state.duplicates.confirm(config, reviewed_text, target.chat_id, target.user_id, is_spam);
+        record_label(state, LabelledExample {
+            chat_id: target.chat_id,
+            user_id: target.user_id,
+            message_id: Some(target.message_id),
+            text: reviewed_text.to_string(),
+            is_spam,
+            source: "review".to_string(),
+            reporter: Some(moderator.clone()),
+            ..Default::default()
+        }, true).await;
     }
 
+    record_moderation(state, ModerationEvent {
+        chat_id: target.chat_id,
+        user_id: target.user_id,
+        message_id: Some(target.message_id),
+        action: if is_spam { "moderator_spam" } else { "moderator_ham" }.to_string(),
+        actor: Some(moderator.clone()),
+        text: reviewed_text.map(str::to_string),
+        ..Default::default()
+    }).await;
+
+    edit_message_text(
+        client,
+        base_url,
+        review_msg.chat.id,
+        review_msg.message_id,
+        &format!("{original}\n\nРешение: {verdict} ({moderator})"),
+    ).await.ok();
+    answer_callback_query(client, base_url, &cq.id, &format!("Отмечено: {verdict}")).await.ok();
+
     Ok(())
 }
 
+/// Событие истории модерации для вердикта классификатора
+fn verdict_event(target: SpamTarget, action: &str, verdict: &SpamVerdict, text: &str) -> ModerationEvent {
+    ModerationEvent {
+        chat_id: target.chat_id,
+        user_id: target.user_id,
+        message_id: Some(target.message_id),
+        action: action.to_string(),
+        score: Some(verdict.spam_score),
+        notes: Some(verdict.notes.clone()),
+        actor: Some(verdict.classifier.clone()),
+        text: Some(text.to_string()),
+        prompt_version: verdict.prompt_version.clone(),
+    }
+}
+
+/// Текст предупреждения о спаме с упоминанием администратора чата
+pub fn spam_warning_text(chat: &ChatConfig, score: u8, notes: &str) -> String {
+    let mention: String = chat.tag_username
+        .as_ref()
+        .map(|u| format!("@{u} "))
+        .unwrap_or_default();
+    format!("{mention}СПАМ ({score}%). Причина: {notes}")
+}
+
+/// Отправляет сообщение в чат модераторов с кнопками «Спам» / «Не спам».
+/// Для скрытого сообщения (`held`) одобрение модератора публикует его текст заново.
+pub async fn send_for_review(
+    client: &Client,
+    base_url: &str,
+    chat: &ChatConfig,
+    target: SpamTarget,
+    text: &str,
+    held: bool,
+) {
+    let Some(review_chat_id) = chat.review_chat_id else {
+        return;
+    };
+    let held_flag: &str = if held { ":1" } else { "" };
+    let suffix: String = format!("{}:{}:{}{held_flag}", target.chat_id, target.message_id, target.user_id);
+    let keyboard: serde_json::Value = serde_json::json!([[
+        { "text": "Спам", "callback_data": format!("{REVIEW_CALLBACK_PREFIX}:s:{suffix}") },
+        { "text": "Не спам", "callback_data": format!("{REVIEW_CALLBACK_PREFIX}:h:{suffix}") },
+    ]]);
+    if let Err(err) = send_message_with_keyboard(client, base_url, review_chat_id, text, keyboard, None).await {
+        log::warn!("Не удалось отправить сообщение на проверку: {err:?}");
+    }
+}
+
+/// Разбирает callback_data кнопки проверки. Возвращает признак спама, исходное сообщение
+/// и признак того, что сообщение было скрыто до решения.
+fn parse_review_callback(data: &str) -> Option<(bool, SpamTarget, bool)> {
+    let mut parts = data.split(':');
+    if parts.next()? != REVIEW_CALLBACK_PREFIX {
+        return None;
+    }
+    let is_spam: bool = match parts.next()? {
+        "s" => true,
+        "h" => false,
+        _ => return None,
+    };
+    let chat_id: i64 = parts.next()?.parse().ok()?;
+    let message_id: i64 = parts.next()?.parse().ok()?;
+    let user_id: i64 = parts.next()?.parse().ok()?;
+    let held: bool = parts.next() == Some("1");
+    Some((is_spam, SpamTarget { chat_id, message_id, user_id }, held))
+}
+
+/// Засчитывает пользователю не-СПАМ сообщение и добавляет его в вайтлист после достижения порога.
+async fn register_ham(
+    client: &Client,
+    base_url: &str,
+    state: &AppState,
+    config: &Config,
+    chat: &ChatConfig,
+    user_id: i64,
+    username_tag: &str,
+) -> Result<()> {
+    if is_user_whitelisted(user_id, state).await? {
+        return Ok(());
+    }
+
+    let count: u32 = increment_ham_counter(user_id, state).await?;
+
+    // Добавляем в вайтлист после достижения порога
+    if count >= chat.ham_threshold {
+        add_user_to_whitelist(state, WhitelistEntry::new(user_id, "auto", "auto", config.whitelist_ttl_days)).await?;
+        record_moderation(state, ModerationEvent {
+            chat_id: chat.chat_id,
+            user_id,
+            action: "whitelist".to_string(),
+            notes: Some(format!("{count} корректных сообщений")),
+            actor: Some("auto".to_string()),
+            ..Default::default()
+        }).await;
+
+        let target_chat: i64 = chat.notify_user_id.unwrap_or(chat.chat_id);
+        send_message(
+            client,
+            base_url,
+            target_chat,
+            &format!(
+                "Пользователь {username_tag} добавлен в белый список после {} корректных сообщений",
+                chat.ham_threshold
+            ),
+            None,
+        ).await.ok();
+    }
+
+    Ok(())
+}
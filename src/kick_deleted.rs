@@ -121,8 +121,7 @@ pub async fn kick_deleted_users(
 }
 
 async fn find_chat(client: &Client, chat_identifier: &str) -> Result<grammers_client::types::Chat> {
-    if chat_identifier.starts_with('@') {
-        let username: &str = &chat_identifier[1..];
+    if let Some(username) = chat_identifier.strip_prefix('@') {
         match client.resolve_username(username).await {
             Ok(Some(chat)) => Ok(chat),
             Ok(None) => anyhow::bail!("Чат с username '{}' не найден", username),
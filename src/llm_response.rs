@@ -0,0 +1,239 @@
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::spam_checker::LlmSpamResult;
+
+/// Просьба исправить ответ, который не удалось разобрать
+pub const REPAIR_PROMPT: &str = r#"Твой ответ не удалось разобрать. Верни ТОЛЬКО один JSON-объект без текста вокруг:
+    {"spam_score": <целое число 0..100>, "notes": "краткая причина"}"#;
+
+/// Ответ модели, из которого не удалось извлечь оценку.
+/// Это не «не спам»: сообщение обрабатывается как при недоступном классификаторе.
+#[derive(Debug)]
+pub struct UnparseableResponse {
+    pub reason: String,
+    /// Ответ модели, обрезанный для журнала
+    pub content: String,
+}
+
+impl fmt::Display for UnparseableResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ответ модели не разобран ({}): {}", self.reason, self.content)
+    }
+}
+
+impl std::error::Error for UnparseableResponse {}
+
+/// Разбирает ответ LLM, прощая типичные ошибки небольших моделей: текст или ```-блок вокруг JSON,
+/// обрезанный по лимиту токенов ответ, оценку строкой, дробью или за пределами 0..100.
+/// Оценка без поля `spam_score` не додумывается.
+pub fn parse_llm_response(content: &str) -> Result<LlmSpamResult, UnparseableResponse> {
+    let unparseable = |reason: &str| UnparseableResponse {
+        reason: reason.to_string(),
+        content: content.chars().take(300).collect(),
+    };
+
+    let object: Option<serde_json::Map<String, Value>> = extract_object(content)
+        .and_then(|json| serde_json::from_str::<Value>(&json).ok())
+        .and_then(|value| match value {
+            Value::Object(map) => Some(map),
+            _ => None,
+        });
+
+    let (score, notes): (Option<&Value>, Option<String>) = match &object {
+        Some(map) => (
+            map.get("spam_score").or_else(|| map.get("score")),
+            map.get("notes").map(|n| match n {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            }),
+        ),
+        None => (None, None),
+    };
+
+    let score: f64 = match score {
+        Some(value) => coerce_score(value).ok_or_else(|| unparseable("spam_score не число"))?,
+        // JSON не собрался даже после дописывания: ищем поля в тексте как есть
+        None if object.is_none() => scan_number(content, "spam_score").ok_or_else(|| unparseable("нет JSON с полем spam_score"))?,
+        None => return Err(unparseable("нет поля spam_score")),
+    };
+    let notes: String = notes.or_else(|| scan_string(content, "notes")).unwrap_or_default();
+
+    Ok(LlmSpamResult { spam_score: clamp_score(score), notes: notes.trim().to_string() })
+}
+
+/// Вырезает первый JSON-объект из ответа. Обрезанный объект дописывается:
+/// закрывается незаконченная строка и все открытые скобки.
+fn extract_object(content: &str) -> Option<String> {
+    let start: usize = content.find('{')?;
+    let mut stack: Vec<char> = Vec::new();
+    let (mut in_string, mut escaped) = (false, false);
+    for (i, c) in content[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => stack.push('}'),
+            '[' => stack.push(']'),
+            '}' | ']' => {
+                stack.pop();
+                if stack.is_empty() {
+                    return Some(content[start..start + i + 1].to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut json: String = content[start..].trim_end().to_string();
+    if escaped {
+        json.pop();
+    }
+    if in_string {
+        json.push('"');
+    }
+    // Висящая запятая или ключ без значения после обрезки
+    let trimmed: &str = json.trim_end().trim_end_matches([',', ':']);
+    json = trimmed.to_string();
+    while let Some(close) = stack.pop() {
+        json.push(close);
+    }
+    Some(json)
+}
+
+/// Число из значения `spam_score`: `85`, `85.4`, `"85"`, `"85%"`.
+/// Число с дробной частью от 0 до 1 включительно (`0.85`, `1.0`) — оценка по шкале 0..1, она умножается
+/// на 100. Целые числа и значения со знаком `%` — всегда проценты: `1` и `"0.5%"` — это 1% и 0.5%.
+fn coerce_score(value: &Value) -> Option<f64> {
+    let (score, decimal): (f64, bool) = match value {
+        Value::Number(n) => (n.as_f64()?, n.is_f64()),
+        Value::String(s) => {
+            let s: &str = s.trim();
+            let number: &str = s.trim_end_matches('%').trim();
+            (number.parse().ok()?, number.contains('.') && !s.ends_with('%'))
+        }
+        _ => return None,
+    };
+    if !score.is_finite() {
+        return None;
+    }
+    Some(if decimal && (0.0..=1.0).contains(&score) { score * 100.0 } else { score })
+}
+
+fn clamp_score(score: f64) -> u8 {
+    score.round().clamp(0.0, 100.0) as u8
+}
+
+/// Значение после `"ключ":` в тексте, который не удалось разобрать как JSON
+fn value_after<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    let at: usize = content.find(key)?;
+    let rest: &str = content[at + key.len()..].trim_start_matches(['"', '\'']).trim_start();
+    let rest: &str = rest.strip_prefix(':').or_else(|| rest.strip_prefix('='))?;
+    Some(rest.trim_start())
+}
+
+fn scan_number(content: &str, key: &str) -> Option<f64> {
+    let rest: &str = value_after(content, key)?.trim_start_matches('"');
+    let end: usize = rest.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-')).unwrap_or(rest.len());
+    coerce_score(&Value::String(rest[..end].to_string()))
+}
+
+fn scan_string(content: &str, key: &str) -> Option<String> {
+    let rest: &str = value_after(content, key)?.strip_prefix('"')?;
+    let end: usize = rest.find('"').unwrap_or(rest.len());
+    Some(rest[..end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn coerce_score_keeps_integers_as_percent() {
+        assert_eq!(coerce_score(&json!(0)), Some(0.0));
+        assert_eq!(coerce_score(&json!(1)), Some(1.0));
+        assert_eq!(coerce_score(&json!(85)), Some(85.0));
+        assert_eq!(coerce_score(&json!("85")), Some(85.0));
+        assert_eq!(coerce_score(&json!("85%")), Some(85.0));
+    }
+
+    #[test]
+    fn coerce_score_scales_decimals_up_to_one_inclusive() {
+        assert_eq!(coerce_score(&json!(0.85)), Some(85.0));
+        assert_eq!(coerce_score(&json!(1.0)), Some(100.0));
+        assert_eq!(coerce_score(&json!(0.0)), Some(0.0));
+        assert_eq!(coerce_score(&json!("0.99")), Some(99.0));
+        assert_eq!(coerce_score(&json!("1.0")), Some(100.0));
+        // Выше единицы шкала уже процентная
+        assert_eq!(coerce_score(&json!(1.5)), Some(1.5));
+        assert_eq!(coerce_score(&json!(85.4)), Some(85.4));
+    }
+
+    #[test]
+    fn coerce_score_treats_percent_sign_as_percent() {
+        assert_eq!(coerce_score(&json!("0.5%")), Some(0.5));
+        assert_eq!(coerce_score(&json!("1.0 %")), Some(1.0));
+    }
+
+    #[test]
+    fn coerce_score_rejects_non_numbers() {
+        assert_eq!(coerce_score(&json!("много")), None);
+        assert_eq!(coerce_score(&json!(null)), None);
+        assert_eq!(coerce_score(&json!([85])), None);
+        assert_eq!(coerce_score(&json!("NaN")), None);
+    }
+
+    fn parse(content: &str) -> (u8, String) {
+        let result: LlmSpamResult = parse_llm_response(content).unwrap();
+        (result.spam_score, result.notes)
+    }
+
+    #[test]
+    fn parses_plain_json() {
+        assert_eq!(parse(r#"{"spam_score": 85, "notes": "реклама"}"#), (85, "реклама".to_string()));
+        assert_eq!(parse(r#"{"score": "0.9", "notes": " вопрос "}"#), (90, "вопрос".to_string()));
+    }
+
+    #[test]
+    fn ignores_text_and_code_fence_around_json() {
+        let content: &str = "Вот ответ:\n```json\n{\"spam_score\": 95, \"notes\": \"призыв в {лс}\"}\n```\nГотово.";
+        assert_eq!(parse(content), (95, "призыв в {лс}".to_string()));
+    }
+
+    #[test]
+    fn completes_truncated_json() {
+        assert_eq!(extract_object(r#"{"spam_score": 70, "notes": "рекл"#).as_deref(), Some(r#"{"spam_score": 70, "notes": "рекл"}"#));
+        assert_eq!(extract_object(r#"{"spam_score": 70, "tags": ["a", "#).as_deref(), Some(r#"{"spam_score": 70, "tags": ["a"]}"#));
+        assert_eq!(parse(r#"{"spam_score": 70, "notes": "рекл"#), (70, "рекл".to_string()));
+        assert_eq!(parse(r#"{"notes": "реклама", "spam_score": 88,"#), (88, "реклама".to_string()));
+    }
+
+    #[test]
+    fn scans_fields_when_json_is_broken() {
+        assert_eq!(parse(r#"{spam_score: 60, notes: "x"}"#), (60, "x".to_string()));
+        assert_eq!(parse(r#"spam_score = "75%", notes: "похоже на рекламу""#), (75, "похоже на рекламу".to_string()));
+    }
+
+    #[test]
+    fn clamps_score_to_percent_range() {
+        assert_eq!(parse(r#"{"spam_score": 150, "notes": ""}"#).0, 100);
+        assert_eq!(parse(r#"{"spam_score": -5, "notes": ""}"#).0, 0);
+    }
+
+    #[test]
+    fn rejects_responses_without_score() {
+        assert_eq!(parse_llm_response(r#"{"notes": "реклама"}"#).unwrap_err().reason, "нет поля spam_score");
+        assert_eq!(parse_llm_response(r#"{"spam_score": "много"}"#).unwrap_err().reason, "spam_score не число");
+        assert_eq!(parse_llm_response("Это спам").unwrap_err().reason, "нет JSON с полем spam_score");
+        assert!(parse_llm_response("[85]").is_err());
+    }
+}
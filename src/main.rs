@@ -1,19 +1,45 @@
 use anyhow::Result;
 use clap::Parser;
 use reqwest::Client;
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::time::sleep;
 
+mod actions;
+mod bayes;
+mod captcha;
+mod chat_settings;
+mod commands;
 mod config;
+mod dispatcher;
+mod duplicates;
+mod entities;
+mod feedback;
 mod handlers;
+mod llm_response;
+mod normalize;
+mod probation;
+mod prompts;
+mod recheck;
+mod resilience;
+mod rules;
 mod spam_checker;
 mod state;
+mod storage;
 mod telegram_api;
+mod verdict_cache;
 mod kick_deleted;
+mod webhook;
 
 use config::Config;
-use state::{AppState, load_whitelist};
-use telegram_api::{delete_webhook, get_me, get_updates};
+use dispatcher::Dispatcher;
+use state::AppState;
+use storage::{SqliteStorage, Storage};
+use telegram_api::{delete_webhook, get_updates};
 
 #[derive(Parser)]
 #[command(name = "tg_anti_spam")]
@@ -26,7 +52,23 @@ struct Args {
 #[derive(clap::Subcommand)]
 enum Commands {
     #[command(name = "bot")]
-    Bot,
+    Bot {
+        /// Принимать обновления через вебхук вместо long polling
+        #[arg(long)]
+        webhook: bool,
+    },
+    /// Выгружает размеченные примеры (решения модераторов, жалобы) в JSONL
+    #[command(name = "export-dataset")]
+    ExportDataset {
+        #[arg(short, long, default_value = "dataset.jsonl")]
+        output: PathBuf,
+        /// База SQLite; по умолчанию DATABASE_PATH
+        #[arg(long)]
+        database: Option<PathBuf>,
+        /// Добавить непроверенные жалобы участников
+        #[arg(long)]
+        include_reports: bool,
+    },
     #[command(name = "kick-deleted")]
     KickDeleted {
         #[arg(short, long)]
@@ -49,29 +91,82 @@ async fn main() -> Result<()> {
     let args: Args = Args::parse();
 
     match args.command {
-        Some(Commands::Bot) => run_bot().await,
+        Some(Commands::Bot { webhook }) => run_bot(webhook).await,
+        Some(Commands::ExportDataset { output, database, include_reports }) => {
+            run_export_dataset(output, database, include_reports).await
+        }
         Some(Commands::KickDeleted { chat, session, dry_run, pause }) => {
             run_kick_deleted_cli(chat, session, dry_run, pause).await
         }
-        None => run_bot().await,
+        None => run_bot(false).await,
     }
 }
 
-/// Запускает бота для фильтрации спама
-async fn run_bot() -> Result<()> {
-    let config: Config = Config::from_env()?;
-    let whitelist: std::collections::HashSet<i64> = load_whitelist(&config.whitelist_path).await.unwrap_or_else(|e| {
-        log::warn!("Не удалось загрузить вайтлист: {}. Используется пустой список.", e);
-        std::collections::HashSet::new()
-    });
-    let state: AppState = AppState::new(whitelist);
+/// Запускает бота для фильтрации спама в режиме long polling или вебхука
+async fn run_bot(webhook: bool) -> Result<()> {
+    let config: Config = Config::load()?;
+    let storage: SqliteStorage = SqliteStorage::open(&config.database_path)?;
+    let imported: usize = storage.import_whitelist_file(&config.whitelist_path).await?;
+    if imported > 0 {
+        log::info!("Импортировано {imported} пользователей из {}", config.whitelist_path.display());
+    }
+    let whitelist: HashMap<i64, storage::WhitelistEntry> = storage.load_whitelist().await?;
+    let chat_settings: HashMap<i64, chat_settings::ChatSettings> = state::load_chat_settings(&storage).await?;
+    for problem in chat_settings::stored_threshold_problems(&config, &chat_settings) {
+        log::warn!("Пороги чата противоречат друг другу, поправьте их командой /threshold: {problem}");
+    }
+    let captchas: Vec<storage::PendingCaptcha> = storage.load_captchas().await?;
+    let client: Client = create_client()?;
+    let bayes: Arc<bayes::BayesClassifier> = Arc::new(
+        bayes::BayesClassifier::load(config.bayes_model_path.clone(), config.bayes_min_examples).await,
+    );
+    let verdict_cache: Option<Arc<verdict_cache::VerdictCache>> = if config.verdict_cache_size > 0 {
+        let cache: verdict_cache::VerdictCache = verdict_cache::VerdictCache::load(
+            config.verdict_cache_size,
+            config.verdict_cache_ttl_minutes,
+            config.verdict_cache_path.clone(),
+        )
+        .await;
+        Some(Arc::new(cache))
+    } else {
+        None
+    };
+    let rules: Arc<rules::RuleEngine> =
+        Arc::new(rules::RuleEngine::load(config.rules_path.clone()).await?);
+    let classifier: Box<dyn spam_checker::SpamClassifier> =
+        spam_checker::build_classifier(&config, bayes.clone(), verdict_cache.clone(), rules.clone())?;
+    // При перезагрузке конфигурации классификатор собирается заново из тех же частей
+    let rebuild_classifier = {
+        let (bayes, cache, rules) = (bayes.clone(), verdict_cache.clone(), rules.clone());
+        move |config: &Config| spam_checker::build_classifier(config, bayes.clone(), cache.clone(), rules.clone())
+    };
+    let state: AppState = AppState::new(Box::new(storage), whitelist, chat_settings, captchas, classifier, bayes);
+    state.rules.set(rules.clone()).ok();
 
     log::info!("Бот запущен. Ожидаю сообщения...");
 
     // Запускаем задачу удаления удалённых аккаунтов в отдельном таске
     tokio::spawn(kick_deleted_task_loop());
 
-    run_long_polling(&config, state).await?;
+    let base_url: String = format!("https://api.telegram.org/bot{}", config.bot_token);
+    let dispatcher: Arc<Dispatcher> = Dispatcher::new(client, base_url, state, config);
+    tokio::spawn(captcha::run_expiry_loop(dispatcher.clone()));
+    recheck::start_recheck_worker(dispatcher.clone());
+    tokio::spawn(rules::run_reload_loop(rules));
+    tokio::spawn(config::run_reload_loop(dispatcher.clone(), rebuild_classifier));
+    if let Some(cache) = verdict_cache.clone() {
+        tokio::spawn(verdict_cache::run_flush_loop(cache));
+    }
+
+    if webhook {
+        webhook::run_webhook(dispatcher).await?;
+    } else {
+        run_long_polling(dispatcher).await?;
+    }
+
+    if let Some(cache) = verdict_cache {
+        cache.save().await?;
+    }
 
     Ok(())
 }
@@ -118,6 +213,29 @@ async fn run_kick_deleted_cli(chat: Option<String>, session: String, dry_run: bo
     Ok(())
 }
 
+/// Выгружает датасет размеченных примеров: одна JSON-запись на строку
+async fn run_export_dataset(output: PathBuf, database: Option<PathBuf>, include_reports: bool) -> Result<()> {
+    let database: PathBuf = database
+        .or_else(|| std::env::var("DATABASE_PATH").ok().map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("anti_spam.db"));
+    let storage: SqliteStorage = SqliteStorage::open(&database)?;
+
+    let mut lines: String = String::new();
+    let mut count: usize = 0;
+    for example in storage.load_examples().await? {
+        if example.source == "user_report" && !include_reports {
+            continue;
+        }
+        lines.push_str(&serde_json::to_string(&example)?);
+        lines.push('\n');
+        count += 1;
+    }
+    tokio::fs::write(&output, lines).await?;
+
+    log::info!("Выгружено {count} примеров в {}", output.display());
+    Ok(())
+}
+
 /// Создает HTTP клиент с таймаутами для Telegram API
 fn create_client() -> Result<Client> {
     Ok(Client::builder()
@@ -126,32 +244,38 @@ fn create_client() -> Result<Client> {
         .build()?)
 }
 
-/// Long polling: получение и обработка обновлений от Telegram
-async fn run_long_polling(config: &Config, state: AppState) -> Result<()> {
-    let client = create_client()?;
-    let base_url = format!("https://api.telegram.org/bot{}", config.bot_token);
-    
-    delete_webhook(&client, &base_url).await.ok();
-    if let Err(err) = get_me(&client, &base_url).await {
-        log::warn!("getMe error: {err:?}");
-    }
-    
-    let mut offset: i64 = 0;
+/// Long polling: получение обновлений от Telegram и передача их диспетчеру.
+/// Offset сдвигается только за обновления, сохранённые в базе: необработанное к моменту падения
+/// обрабатывается из базы после запуска, а несохранённое Telegram пришлёт снова.
+async fn run_long_polling(dispatcher: Arc<Dispatcher>) -> Result<()> {
+    let client: &Client = dispatcher.client();
+    let base_url: &str = dispatcher.base_url();
+
+    delete_webhook(client, base_url).await.ok();
+    dispatcher.identify().await;
+
+    // Смещение всегда следует за последним полученным обновлением, чтобы долгая обработка в одном чате
+    // не останавливала получение новых. Подтверждённые так обновления хранятся в базе до конца обработки
+    let mut last_seen: i64 = dispatcher.resume_pending().await.unwrap_or(-1);
 
     loop {
-        let Ok(resp) = get_updates(&client, &base_url, offset).await else {
+        let Ok(resp) = get_updates(client, base_url, last_seen + 1).await else {
             log::warn!("getUpdates error, повтор через 2 секунды...");
             tokio::time::sleep(std::time::Duration::from_secs(2)).await;
             continue;
         };
 
         for upd in resp.result {
-            offset = upd.update_id + 1;
-            if let Some(msg) = upd.message {
-                if let Err(err) = handlers::handle_message(&client, &base_url, &msg, &state, config).await {
-                    log::error!("handler error: {err:?}");
-                }
+            let Some(id) = upd.get("update_id").and_then(serde_json::Value::as_i64) else {
+                continue;
+            };
+            // Несохранённое обновление не подтверждается: следующий запрос начнётся с него
+            if let Err(err) = dispatcher.accept(upd).await {
+                log::error!("{err:?}, повтор через 2 секунды...");
+                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+                break;
             }
+            last_seen = last_seen.max(id);
         }
     }
 }
@@ -164,7 +288,7 @@ async fn run_kick_deleted_task() -> Result<()> {
         .and_then(|v| v.parse::<i32>().ok())
         .unwrap_or_else(|| {
             log::warn!("TELEGRAM_API_ID не задан, пропускаем задачу удаления");
-            return 0;
+            0
         });
     
     if api_id == 0 {
@@ -174,7 +298,7 @@ async fn run_kick_deleted_task() -> Result<()> {
     let api_hash: String = std::env::var("TELEGRAM_API_HASH")
         .unwrap_or_else(|_| {
             log::warn!("TELEGRAM_API_HASH не задан, пропускаем задачу удаления");
-            return String::new();
+            String::new()
         });
     
     if api_hash.is_empty() {
@@ -184,7 +308,7 @@ async fn run_kick_deleted_task() -> Result<()> {
     let phone = std::env::var("TELEGRAM_PHONE")
         .unwrap_or_else(|_| {
             log::warn!("TELEGRAM_PHONE не задан, пропускаем задачу удаления");
-            return String::new();
+            String::new()
         });
 
     if phone.is_empty() {
@@ -194,7 +318,7 @@ async fn run_kick_deleted_task() -> Result<()> {
     let chat = std::env::var("KICK_DELETED_CHAT")
         .unwrap_or_else(|_| {
             log::warn!("KICK_DELETED_CHAT не задан, пропускаем задачу удаления");
-            return String::new();
+            String::new()
         });
     
     if chat.is_empty() {
@@ -0,0 +1,329 @@
+/// Сколько приёмов маскировки текста найдено при нормализации
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ObfuscationStats {
+    /// Невидимые символы рядом с буквами: нулевой ширины, мягкие переносы, управление направлением
+    pub invisible: usize,
+    /// Стилизованные буквы: математические, полноширинные, в кружках и квадратах
+    pub fancy_letters: usize,
+    /// Слова, в которых латиница смешана с похожей кириллицей
+    pub homoglyph_words: usize,
+    /// Слова, написанные по буквам через разделители: «з а р а б о т о к»
+    pub spaced_words: usize,
+    /// Слова, разбитые повторяющимися эмодзи: «за💰ра💰бо💰ток»
+    pub emoji_split_words: usize,
+}
+
+impl ObfuscationStats {
+    pub fn is_empty(&self) -> bool {
+        self.score() == 0
+    }
+
+    /// Оценка маскировки от 0 до 100. Единичные стилизованные буквы и невидимые символы
+    /// встречаются и в обычных сообщениях, поэтому весят меньше, чем разбитые слова.
+    pub fn score(&self) -> u8 {
+        let score: usize = self.invisible * 10
+            + self.fancy_letters.min(10) * 4
+            + (self.homoglyph_words + self.spaced_words + self.emoji_split_words) * 30;
+        score.min(100) as u8
+    }
+
+    /// Описание для объяснений и промпта LLM
+    pub fn describe(&self) -> String {
+        [
+            (self.invisible, "невидимых символов"),
+            (self.fancy_letters, "стилизованных букв"),
+            (self.homoglyph_words, "слов со смешанным алфавитом"),
+            (self.spaced_words, "слов по буквам"),
+            (self.emoji_split_words, "слов, разбитых эмодзи"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, name)| format!("{name}: {count}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+/// Текст, приведённый к каноническому виду, и найденные приёмы маскировки
+#[derive(Debug, Clone)]
+pub struct Normalized {
+    pub text: String,
+    pub obfuscation: ObfuscationStats,
+}
+
+/// Символы, которые не видны в тексте: нулевой ширины, мягкий перенос, управление направлением,
+/// селекторы вариантов
+pub fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{034F}'
+            | '\u{180E}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FEFF}'
+    )
+}
+
+/// Приводит текст к виду, в котором его видит человек: убирает невидимые символы,
+/// заменяет стилизованные буквы обычными, склеивает слова, написанные по буквам или через эмодзи,
+/// и исправляет латиницу в кириллических словах (и наоборот).
+pub fn normalize(text: &str) -> Normalized {
+    let mut stats: ObfuscationStats = ObfuscationStats::default();
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut visible: String = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        if is_invisible(c) {
+            // Селекторы вариантов и соединители внутри эмодзи — не маскировка
+            let near_letter: bool = [i.checked_sub(1), Some(i + 1)]
+                .iter()
+                .flatten()
+                .any(|&j| chars.get(j).is_some_and(|c| c.is_alphanumeric()));
+            if near_letter && !('\u{FE00}'..='\u{FE0F}').contains(&c) {
+                stats.invisible += 1;
+            }
+            continue;
+        }
+        match fancy_to_plain(c) {
+            Some(plain) => {
+                stats.fancy_letters += 1;
+                visible.push(plain);
+            }
+            None => visible.push(c),
+        }
+    }
+
+    let mut segments: Vec<Segment> = split_segments(&visible);
+    stats.spaced_words = merge_runs(&mut segments, is_single_letter, is_letter_separator);
+    stats.emoji_split_words = merge_runs(&mut segments, is_syllable, is_emoji_separator);
+
+    let mut normalized: String = String::with_capacity(visible.len());
+    for segment in &segments {
+        if segment.is_word
+            && let Some(fixed) = fix_homoglyphs(&segment.text)
+        {
+            stats.homoglyph_words += 1;
+            normalized.push_str(&fixed);
+        } else {
+            normalized.push_str(&segment.text);
+        }
+    }
+
+    Normalized { text: normalized.trim().to_string(), obfuscation: stats }
+}
+
+/// Слово (буквы и цифры) или разделитель между словами
+struct Segment {
+    text: String,
+    is_word: bool,
+}
+
+fn split_segments(text: &str) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    for c in text.chars() {
+        let is_word: bool = c.is_alphanumeric();
+        match segments.last_mut() {
+            Some(last) if last.is_word == is_word => last.text.push(c),
+            _ => segments.push(Segment { text: c.to_string(), is_word }),
+        }
+    }
+    segments
+}
+
+/// Сколько слов должно быть в цепочке, чтобы считать её одним разбитым словом
+const MIN_RUN_WORDS: usize = 4;
+
+/// Склеивает цепочки «слово, разделитель, слово, ...» из `MIN_RUN_WORDS` и больше слов,
+/// подходящих под `word_ok`, с одинаковыми разделителями, подходящими под `separator_ok`.
+/// Возвращает число склеенных цепочек.
+fn merge_runs(segments: &mut Vec<Segment>, word_ok: fn(&str) -> bool, separator_ok: fn(&str) -> bool) -> usize {
+    let mut merged: usize = 0;
+    let mut i: usize = 0;
+    while i < segments.len() {
+        if !segments[i].is_word || !word_ok(&segments[i].text) {
+            i += 1;
+            continue;
+        }
+        // Слова и разделители чередуются, поэтому за разделителем всегда идёт слово
+        let mut end: usize = i;
+        while end + 2 < segments.len()
+            && separator_ok(&segments[end + 1].text)
+            && segments[end + 1].text == segments[i + 1].text
+            && word_ok(&segments[end + 2].text)
+        {
+            end += 2;
+        }
+        if (end - i) / 2 + 1 < MIN_RUN_WORDS {
+            i = end + 1;
+            continue;
+        }
+        let word: String = segments[i..=end]
+            .iter()
+            .filter(|s| s.is_word)
+            .map(|s| s.text.as_str())
+            .collect();
+        segments.splice(i..=end, [Segment { text: word, is_word: true }]);
+        merged += 1;
+        i += 1;
+    }
+    merged
+}
+
+/// Одиночная буква: «з а р а б о т о к»
+fn is_single_letter(word: &str) -> bool {
+    let mut chars = word.chars();
+    chars.next().is_some_and(char::is_alphabetic) && chars.next().is_none()
+}
+
+/// Короткий разделитель в пределах строки. Запятые не подходят: «варианты A, B, C, D» — обычный список
+fn is_letter_separator(separator: &str) -> bool {
+    separator.chars().count() <= 3 && !separator.contains(['\n', ',', ';'])
+}
+
+/// Слог до трёх символов: «за💰ра💰бо💰ток»
+fn is_syllable(word: &str) -> bool {
+    word.chars().count() <= 3
+}
+
+/// Эмодзи или другой символ без пробелов и обычной пунктуации: дефисы и точки встречаются в обычном тексте
+fn is_emoji_separator(separator: &str) -> bool {
+    !separator.chars().any(|c| c.is_whitespace() || c.is_ascii_punctuation())
+}
+
+/// Латинские буквы, неотличимые от кириллических, и их пары
+const CONFUSABLES: &[(char, char)] = &[
+    ('a', 'а'), ('c', 'с'), ('e', 'е'), ('o', 'о'), ('p', 'р'), ('x', 'х'), ('y', 'у'), ('k', 'к'),
+    ('A', 'А'), ('B', 'В'), ('C', 'С'), ('E', 'Е'), ('H', 'Н'), ('K', 'К'), ('M', 'М'), ('O', 'О'),
+    ('P', 'Р'), ('T', 'Т'), ('X', 'Х'), ('Y', 'У'), ('i', 'і'), ('j', 'ј'), ('s', 'ѕ'), ('h', 'һ'),
+];
+
+fn is_cyrillic(c: char) -> bool {
+    ('\u{0400}'..='\u{04FF}').contains(&c)
+}
+
+/// Заменяет в слове со смешанным алфавитом похожие буквы на буквы преобладающего алфавита.
+/// `None`, если слово написано одним алфавитом или заменять нечего.
+fn fix_homoglyphs(word: &str) -> Option<String> {
+    let cyrillic: usize = word.chars().filter(|c| is_cyrillic(*c)).count();
+    let latin: usize = word.chars().filter(|c| c.is_ascii_alphabetic()).count();
+    if cyrillic == 0 || latin == 0 {
+        return None;
+    }
+    let to_cyrillic: bool = cyrillic >= latin;
+    let fixed: String = word
+        .chars()
+        .map(|c| {
+            CONFUSABLES
+                .iter()
+                .find_map(|&(lat, cyr)| match to_cyrillic {
+                    true if c == lat => Some(cyr),
+                    false if c == cyr => Some(lat),
+                    _ => None,
+                })
+                .unwrap_or(c)
+        })
+        .collect();
+    (fixed != word).then_some(fixed)
+}
+
+/// Обычная буква или цифра для стилизованной: математические алфавиты, полноширинные формы,
+/// буквы в кружках и квадратах
+fn fancy_to_plain(c: char) -> Option<char> {
+    let code: u32 = c as u32;
+    let letter = |offset: u32| -> Option<char> {
+        if offset < 26 {
+            char::from_u32('A' as u32 + offset)
+        } else {
+            char::from_u32('a' as u32 + offset - 26)
+        }
+    };
+    match code {
+        // 13 начертаний по 52 буквы: A-Z, затем a-z
+        0x1D400..=0x1D6A3 => letter((code - 0x1D400) % 52),
+        // 5 начертаний цифр по 10
+        0x1D7CE..=0x1D7FF => char::from_digit((code - 0x1D7CE) % 10, 10),
+        0xFF10..=0xFF19 | 0xFF21..=0xFF3A | 0xFF41..=0xFF5A => char::from_u32(code - 0xFEE0),
+        0x24B6..=0x24CF => letter(code - 0x24B6),
+        0x24D0..=0x24E9 => letter(code - 0x24D0 + 26),
+        0x1F130..=0x1F149 => letter(code - 0x1F130),
+        0x1F150..=0x1F169 => letter(code - 0x1F150),
+        0x1F170..=0x1F189 => letter(code - 0x1F170),
+        // Буквы, которые в математических алфавитах взяты из блока буквоподобных символов
+        _ => match c {
+            'ℂ' | 'ℭ' => Some('C'),
+            'ℊ' => Some('g'),
+            'ℋ' | 'ℌ' | 'ℍ' => Some('H'),
+            'ℎ' => Some('h'),
+            'ℐ' | 'ℑ' => Some('I'),
+            'ℒ' => Some('L'),
+            'ℕ' => Some('N'),
+            'ℙ' => Some('P'),
+            'ℚ' => Some('Q'),
+            'ℛ' | 'ℜ' | 'ℝ' => Some('R'),
+            'ℤ' | 'ℨ' => Some('Z'),
+            'ℬ' => Some('B'),
+            'ℯ' => Some('e'),
+            'ℰ' => Some('E'),
+            'ℱ' => Some('F'),
+            'ℳ' => Some('M'),
+            'ℴ' => Some('o'),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_is_unchanged() {
+        let result: Normalized = normalize("  Почему в узле указатель на родителя? Варианты A, B, C, D  ");
+        assert_eq!(result.text, "Почему в узле указатель на родителя? Варианты A, B, C, D");
+        assert!(result.obfuscation.is_empty());
+    }
+
+    #[test]
+    fn removes_invisible_characters_inside_words() {
+        let result: Normalized = normalize("за\u{200B}ра\u{00AD}боток ❤\u{FE0F}");
+        assert_eq!(result.text, "заработок ❤");
+        assert_eq!(result.obfuscation.invisible, 2);
+    }
+
+    #[test]
+    fn replaces_fancy_letters() {
+        let result: Normalized = normalize("𝐅𝐑𝐄𝐄 ｍｏｎｅｙ Ⓒⓐⓢⓗ ℍ𝕠𝕥");
+        assert_eq!(result.text, "FREE money Cash Hot");
+        assert_eq!(result.obfuscation.fancy_letters, 16);
+    }
+
+    #[test]
+    fn merges_spaced_and_emoji_split_words() {
+        let result: Normalized = normalize("з а р а б о т о к, за💰ра💰бо💰ток");
+        assert_eq!(result.text, "заработок, заработок");
+        assert_eq!(result.obfuscation.spaced_words, 1);
+        assert_eq!(result.obfuscation.emoji_split_words, 1);
+    }
+
+    #[test]
+    fn fixes_mixed_scripts_towards_dominant_alphabet() {
+        // Латинские «a» и «o» в кириллическом слове и кириллическая «о» в латинском
+        let result: Normalized = normalize("зaрабoток в crypt\u{043E}");
+        assert_eq!(result.text, "заработок в crypto");
+        assert_eq!(result.obfuscation.homoglyph_words, 2);
+        assert_eq!(fix_homoglyphs("привет"), None);
+        assert_eq!(fix_homoglyphs("hello"), None);
+    }
+
+    #[test]
+    fn score_weights_split_words_above_single_symbols() {
+        let stats: ObfuscationStats = ObfuscationStats { invisible: 1, fancy_letters: 50, ..Default::default() };
+        assert_eq!(stats.score(), 50);
+        assert_eq!(stats.describe(), "невидимых символов: 1, стилизованных букв: 50");
+        let stats: ObfuscationStats = ObfuscationStats { spaced_words: 2, homoglyph_words: 2, ..Default::default() };
+        assert_eq!(stats.score(), 100);
+    }
+}
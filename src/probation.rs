@@ -0,0 +1,42 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+
+use crate::{actions::unix_now, chat_settings::ChatConfig, entities::MessageFeatures, telegram_api::Message};
+
+/// Что делать с рискованными сообщениями пользователя на испытательном сроке
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbationMode {
+    /// Проверять с порогом `probation_threshold` вместо `spam_threshold`
+    Strict,
+    /// Скрывать сообщение до решения модераторов (без чата модераторов — как `strict`)
+    Hold,
+}
+
+impl FromStr for ProbationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "strict" => Ok(ProbationMode::Strict),
+            "hold" => Ok(ProbationMode::Hold),
+            other => anyhow::bail!("Неизвестный режим испытательного срока '{other}' (допустимо: strict, hold)"),
+        }
+    }
+}
+
+/// Пользователь на испытательном сроке, пока у него меньше `probation_messages` не-СПАМ сообщений
+/// и с первого появления (или вступления) прошло меньше `probation_hours` часов.
+/// Срок — первые шаги к вайтлисту по `ham_threshold`, а не отдельный счётчик.
+pub fn on_probation(chat: &ChatConfig, ham_count: u32, first_seen: i64) -> bool {
+    if chat.probation_messages == 0 {
+        return false;
+    }
+    let within_hours: bool = unix_now() - first_seen < i64::from(chat.probation_hours) * 3600;
+    ham_count < chat.probation_messages && within_hours
+}
+
+/// Сообщение с тем, чем обычно пользуются спамеры: ссылки, упоминания, пересылка, медиа
+pub fn is_risky(msg: &Message, features: &MessageFeatures) -> bool {
+    !features.urls.is_empty() || !features.mentions.is_empty() || msg.forward_origin.is_some() || msg.has_media()
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{entities::MessageFeatures, normalize::ObfuscationStats};
+
+/// Встроенный шаблон; файл `default.toml` в `PROMPTS_DIR` заменяет его
+const BUILTIN_TEMPLATE: &str = include_str!("../prompts/default.toml");
+
+/// Имя встроенного шаблона
+pub const DEFAULT_TEMPLATE: &str = "default";
+
+/// Сколько символов сообщения получает LLM; правила и остальные классификаторы видят текст целиком
+const PROMPT_TEXT_CHARS: usize = 250;
+
+/// Пример для модели: текст и ожидаемый ответ
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PromptExample {
+    text: String,
+    spam_score: u8,
+    notes: String,
+}
+
+/// Шаблон в том виде, в каком он записан в файле.
+/// Необязательные части берутся из встроенного шаблона.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateSpec {
+    version: String,
+    system: String,
+    user: Option<String>,
+    features: Option<String>,
+    obfuscation: Option<String>,
+    chat_prompt: Option<String>,
+    topic: Option<String>,
+    language: Option<String>,
+    #[serde(default)]
+    examples: Vec<PromptExample>,
+}
+
+/// Шаблон промпта LLM с подстановками для темы чата, языка и примеров
+#[derive(Debug)]
+pub struct PromptTemplate {
+    /// Версия попадает в журнал вместе с каждой оценкой, полученной по шаблону
+    pub version: String,
+    system: String,
+    user: String,
+    features: String,
+    obfuscation: String,
+    chat_prompt: String,
+    topic: String,
+    language: String,
+    examples: Vec<PromptExample>,
+}
+
+impl PromptTemplate {
+    fn from_spec(spec: TemplateSpec, base: Option<&PromptTemplate>) -> Result<Self> {
+        let version: String = spec.version.trim().to_string();
+        if version.is_empty() {
+            anyhow::bail!("Не задана version");
+        }
+        let inherit = |value: Option<String>, field: fn(&PromptTemplate) -> &String| -> String {
+            value.or_else(|| base.map(|b| field(b).clone())).unwrap_or_default()
+        };
+        let template: PromptTemplate = PromptTemplate {
+            version,
+            system: spec.system,
+            user: inherit(spec.user, |b| &b.user),
+            features: inherit(spec.features, |b| &b.features),
+            obfuscation: inherit(spec.obfuscation, |b| &b.obfuscation),
+            chat_prompt: inherit(spec.chat_prompt, |b| &b.chat_prompt),
+            topic: inherit(spec.topic, |b| &b.topic),
+            language: inherit(spec.language, |b| &b.language),
+            examples: spec.examples,
+        };
+        if !template.user.contains("{text}") {
+            anyhow::bail!("В user нет подстановки {{text}}: модель не увидит сообщение");
+        }
+        Ok(template)
+    }
+
+    /// Системный промпт с темой и языком чата; указания чата добавляются через `chat_prompt`
+    pub fn system(&self, topic: Option<&str>, language: Option<&str>, chat_prompt: Option<&str>) -> String {
+        let examples: String = self
+            .examples
+            .iter()
+            .map(|e| {
+                // Ответ собирается вручную: поля идут в том порядке, в каком их ждут от модели
+                let notes: serde_json::Value = serde_json::Value::from(e.notes.as_str());
+                format!("- \"{}\" → {{\"spam_score\": {}, \"notes\": {notes}}}", e.text, e.spam_score)
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        let chat_prompt: String = chat_prompt
+            .map(|p| self.chat_prompt.replace("{prompt}", p))
+            .unwrap_or_default();
+        let mut system: String = self
+            .system
+            .replace("{topic}", topic.unwrap_or(&self.topic))
+            .replace("{language}", language.unwrap_or(&self.language))
+            .replace("{examples}", &examples);
+        // Без подстановки указания чата дописываются в конец, чтобы не потеряться
+        if system.contains("{chat_prompt}") {
+            system = system.replace("{chat_prompt}", &chat_prompt);
+        } else if !chat_prompt.is_empty() {
+            system.push_str(&format!("\n{chat_prompt}"));
+        }
+        system.trim_end().to_string()
+    }
+
+    /// Пользовательский промпт с началом текста сообщения. Ссылки из разметки добавляются после текста:
+    /// скрытые гиперссылки в нём не видны. Текст уже нормализован, поэтому о найденной маскировке
+    /// модель узнаёт отдельно.
+    pub fn user(&self, text: &str, features: &MessageFeatures, obfuscation: &ObfuscationStats) -> String {
+        let text: String = text.chars().take(PROMPT_TEXT_CHARS).collect();
+        let mut message: String = self.user.replace("{text}", &text);
+        if !features.is_empty() {
+            message.push('\n');
+            message.push_str(&self.features.replace("{features}", &features.describe()));
+        }
+        if !obfuscation.is_empty() {
+            message.push('\n');
+            message.push_str(&self.obfuscation.replace("{obfuscation}", &obfuscation.describe()));
+        }
+        message
+    }
+}
+
+/// Шаблоны промптов по имени: встроенный `default` и файлы `<имя>.toml` из `PROMPTS_DIR`
+#[derive(Debug)]
+pub struct PromptLibrary {
+    templates: BTreeMap<String, Arc<PromptTemplate>>,
+    /// Шаблон чатов без своей настройки `template`
+    default: String,
+}
+
+impl PromptLibrary {
+    /// Загружает встроенный шаблон и шаблоны из каталога. Ошибка в любом файле не даёт загрузить
+    /// библиотеку: при перезагрузке конфигурации остаются прежние шаблоны.
+    pub fn load(dir: Option<&Path>, default: &str) -> Result<Self> {
+        let builtin: PromptTemplate = PromptTemplate::from_spec(toml::from_str(BUILTIN_TEMPLATE)?, None)?;
+        let mut templates: BTreeMap<String, Arc<PromptTemplate>> = BTreeMap::new();
+
+        if let Some(dir) = dir {
+            let entries = std::fs::read_dir(dir).with_context(|| format!("Не удалось прочитать каталог {}", dir.display()))?;
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_none_or(|e| e != "toml") {
+                    continue;
+                }
+                let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                    continue;
+                };
+                let template: PromptTemplate = std::fs::read_to_string(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|content| Ok(toml::from_str::<TemplateSpec>(&content)?))
+                    .and_then(|spec| PromptTemplate::from_spec(spec, Some(&builtin)))
+                    .with_context(|| format!("Шаблон промпта {}", path.display()))?;
+                templates.insert(name.to_string(), Arc::new(template));
+            }
+        }
+        templates
+            .entry(DEFAULT_TEMPLATE.to_string())
+            .or_insert_with(|| Arc::new(builtin));
+
+        if !templates.contains_key(default) {
+            anyhow::bail!("Шаблон промпта '{default}' не найден. Доступны: {}", Self::join(&templates));
+        }
+        Ok(Self { templates, default: default.to_string() })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.templates.contains_key(name)
+    }
+
+    /// Имена шаблонов через запятую
+    pub fn names(&self) -> String {
+        Self::join(&self.templates)
+    }
+
+    fn join(templates: &BTreeMap<String, Arc<PromptTemplate>>) -> String {
+        templates.keys().cloned().collect::<Vec<String>>().join(", ")
+    }
+
+    /// Шаблон по имени из настроек чата. Пропавший после перезагрузки шаблон заменяется
+    /// шаблоном по умолчанию, чтобы чат не остался без проверки.
+    pub fn get(&self, name: Option<&str>) -> Arc<PromptTemplate> {
+        let name: &str = name.unwrap_or(&self.default);
+        match self.templates.get(name) {
+            Some(template) => template.clone(),
+            None => {
+                log::warn!("Шаблон промпта '{name}' не найден, использую '{}'", self.default);
+                self.templates[&self.default].clone()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builtin() -> PromptTemplate {
+        PromptTemplate::from_spec(toml::from_str(BUILTIN_TEMPLATE).unwrap(), None).unwrap()
+    }
+
+    fn spec(content: &str) -> TemplateSpec {
+        toml::from_str(content).unwrap()
+    }
+
+    /// Временный каталог шаблонов, удаляется вместе с файлами
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let path: std::path::PathBuf = std::env::temp_dir().join(format!("tg_anti_spam_{}_{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            for (file, content) in files {
+                std::fs::write(path.join(file), content).unwrap();
+            }
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn builtin_template_parses() {
+        let template: PromptTemplate = builtin();
+        assert_eq!(template.version, "default-1");
+        assert!(!template.examples.is_empty());
+        let system: String = template.system(None, None, None);
+        assert!(system.contains("чата программистов"));
+        assert!(system.contains("Поле notes пиши на языке: русский."));
+        assert!(system.contains("рекрутинг, призыв к действию"));
+        for placeholder in ["{topic}", "{language}", "{examples}", "{chat_prompt}"] {
+            assert!(!system.contains(placeholder), "{placeholder}");
+        }
+        let user: String = template.user("привет", &MessageFeatures::default(), &ObfuscationStats::default());
+        assert!(user.contains("\"\"\"\nпривет\n\"\"\""));
+    }
+
+    #[test]
+    fn file_template_inherits_missing_parts_from_builtin() {
+        let base: PromptTemplate = builtin();
+        let template: PromptTemplate = PromptTemplate::from_spec(
+            spec("version = \"short-1\"\nsystem = \"Фильтр для {topic}\"\ntopic = \"чата о рыбалке\""),
+            Some(&base),
+        )
+        .unwrap();
+        assert_eq!(template.user, base.user);
+        assert_eq!(template.features, base.features);
+        assert_eq!(template.language, base.language);
+        assert!(template.examples.is_empty());
+        assert_eq!(template.system(None, None, None), "Фильтр для чата о рыбалке");
+        assert_eq!(template.system(Some("чата о грибах"), None, None), "Фильтр для чата о грибах");
+    }
+
+    #[test]
+    fn rejects_template_without_text_or_version() {
+        let base: PromptTemplate = builtin();
+        let err: anyhow::Error = PromptTemplate::from_spec(
+            spec("version = \"x\"\nsystem = \"s\"\nuser = \"Сообщение без подстановки\""),
+            Some(&base),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("{text}"), "{err}");
+        // Без встроенного шаблона user не наследуется
+        assert!(PromptTemplate::from_spec(spec("version = \"x\"\nsystem = \"s\""), None).is_err());
+        assert!(PromptTemplate::from_spec(spec("version = \" \"\nsystem = \"s\""), Some(&base)).is_err());
+        assert!(toml::from_str::<TemplateSpec>("version = \"x\"\nsystem = \"s\"\nsytem = \"s\"").is_err());
+    }
+
+    #[test]
+    fn chat_prompt_is_substituted_or_appended() {
+        let base: PromptTemplate = builtin();
+        let placed: PromptTemplate = PromptTemplate::from_spec(
+            spec("version = \"x\"\nsystem = \"Начало\\n{chat_prompt}\\nКонец\""),
+            Some(&base),
+        )
+        .unwrap();
+        assert_eq!(
+            placed.system(None, None, Some("без вакансий")),
+            "Начало\nОсобенности этого чата: без вакансий\nКонец"
+        );
+        assert_eq!(placed.system(None, None, None), "Начало\n\nКонец");
+
+        let appended: PromptTemplate =
+            PromptTemplate::from_spec(spec("version = \"x\"\nsystem = \"Начало\""), Some(&base)).unwrap();
+        assert_eq!(appended.system(None, None, Some("без вакансий")), "Начало\nОсобенности этого чата: без вакансий");
+        assert_eq!(appended.system(None, None, None), "Начало");
+    }
+
+    #[test]
+    fn loads_directory_and_falls_back_to_default() {
+        let dir: TempDir = TempDir::new("prompts", &[
+            ("short.toml", "version = \"short-1\"\nsystem = \"Коротко\""),
+            ("notes.txt", "не шаблон"),
+        ]);
+        let library: PromptLibrary = PromptLibrary::load(Some(&dir.0), DEFAULT_TEMPLATE).unwrap();
+        assert_eq!(library.names(), "default, short");
+        assert!(library.contains("short"));
+        assert_eq!(library.get(Some("short")).version, "short-1");
+        assert_eq!(library.get(None).version, "default-1");
+        assert_eq!(library.get(Some("removed")).version, "default-1");
+
+        let library: PromptLibrary = PromptLibrary::load(Some(&dir.0), "short").unwrap();
+        assert_eq!(library.get(None).version, "short-1");
+        assert_eq!(library.get(Some("removed")).version, "short-1");
+        assert!(PromptLibrary::load(Some(&dir.0), "missing").is_err());
+    }
+
+    #[test]
+    fn broken_file_fails_whole_library() {
+        let dir: TempDir = TempDir::new("prompts_broken", &[
+            ("good.toml", "version = \"good-1\"\nsystem = \"s\""),
+            ("bad.toml", "version = \"bad-1\"\nsystem = \"s\"\nuser = \"нет текста\""),
+        ]);
+        let err: String = format!("{:#}", PromptLibrary::load(Some(&dir.0), DEFAULT_TEMPLATE).unwrap_err());
+        assert!(err.contains("bad.toml"), "{err}");
+    }
+
+    #[test]
+    fn user_prompt_gets_only_start_of_text() {
+        let library: PromptLibrary = PromptLibrary::load(None, DEFAULT_TEMPLATE).unwrap();
+        let text: String = format!("{}хвост", "а".repeat(PROMPT_TEXT_CHARS));
+        let prompt: String = library.get(None).user(&text, &MessageFeatures::default(), &ObfuscationStats::default());
+        assert!(prompt.contains(&"а".repeat(PROMPT_TEXT_CHARS)));
+        assert!(!prompt.contains("хвост"));
+    }
+}
@@ -0,0 +1,197 @@
+use std::{str::FromStr, sync::Arc};
+
+use anyhow::Result;
+use rand::Rng;
+use tokio::sync::mpsc;
+
+use crate::{
+    actions::SpamTarget,
+    chat_settings::ChatConfig,
+    config::Config,
+    dispatcher::Dispatcher,
+    entities::MessageFeatures,
+    handlers::send_for_review,
+    spam_checker::{ClassifyInput, SpamVerdict},
+    state::{chat_config, record_moderation, revoke_whitelist, AppState},
+    storage::ModerationEvent,
+    telegram_api::send_message,
+};
+
+/// Сколько повторных проверок может ждать в очереди; лишние отбрасываются
+const RECHECK_QUEUE_SIZE: usize = 100;
+
+/// Что делать, если сообщение пользователя из вайтлиста уверенно признано спамом
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecheckAction {
+    /// Сообщить модераторам, пользователь остаётся в вайтлисте до их решения
+    Alert,
+    /// Сразу убрать пользователя из вайтлиста и сообщить модераторам
+    Demote,
+}
+
+impl FromStr for RecheckAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "alert" => Ok(RecheckAction::Alert),
+            "demote" => Ok(RecheckAction::Demote),
+            other => anyhow::bail!("Неизвестное действие повторной проверки '{other}' (допустимо: alert, demote)"),
+        }
+    }
+}
+
+/// Сообщение пользователя из вайтлиста, ожидающее повторной проверки
+pub struct RecheckJob {
+    pub target: SpamTarget,
+    pub username_tag: String,
+    pub chat_name: String,
+    pub input: ClassifyInput,
+}
+
+/// Нужно ли перепроверить сообщение пользователя из вайтлиста:
+/// все сообщения со ссылками (если включено) и случайная доля остальных
+pub fn should_recheck(config: &Config, features: &MessageFeatures) -> bool {
+    if config.whitelist_recheck_links && !features.urls.is_empty() {
+        return true;
+    }
+    config.whitelist_recheck_percent > 0 && rand::thread_rng().gen_range(0..100) < config.whitelist_recheck_percent
+}
+
+/// Ставит сообщение в очередь повторной проверки, не дожидаясь классификатора.
+/// Если очередь переполнена или выключена, сообщение пропускается.
+pub fn schedule_recheck(state: &AppState, job: RecheckJob) {
+    let Some(queue) = state.recheck_queue.get() else {
+        return;
+    };
+    if queue.try_send(job).is_err() {
+        log::debug!("Очередь повторной проверки переполнена, сообщение пропущено");
+    }
+}
+
+/// Запускает фоновую повторную проверку вайтлиста. Очередь заполняется, только если проверка включена,
+/// поэтому её можно включить перезагрузкой конфигурации. Проверки идут по одной, чтобы не отнимать классификатор у обычных сообщений.
+pub fn start_recheck_worker(dispatcher: Arc<Dispatcher>) {
+    let (tx, mut rx) = mpsc::channel::<RecheckJob>(RECHECK_QUEUE_SIZE);
+    dispatcher.state().recheck_queue.set(tx).ok();
+
+    tokio::spawn(async move {
+        while let Some(job) = rx.recv().await {
+            if let Err(err) = recheck(&dispatcher, &job).await {
+                log::warn!("Ошибка повторной проверки сообщения {}: {err:?}", job.target.message_id);
+            }
+        }
+    });
+}
+
+/// Проверяет сообщение классификатором и при уверенном спаме предупреждает модераторов
+/// или убирает пользователя из вайтлиста.
+async fn recheck(dispatcher: &Dispatcher, job: &RecheckJob) -> Result<()> {
+    let (client, base_url, state) = (dispatcher.client(), dispatcher.base_url(), dispatcher.state());
+    let config: Arc<Config> = dispatcher.config();
+    let target: SpamTarget = job.target;
+
+    let verdict: SpamVerdict = state.classifier().classify(&job.input).await?;
+    log::info!(
+        "Повторная проверка {} ({}): {}%, причины: {}",
+        job.username_tag, verdict.classifier, verdict.spam_score, verdict.notes
+    );
+    if verdict.spam_score < config.whitelist_recheck_threshold {
+        return Ok(());
+    }
+
+    let demoted: bool = config.whitelist_recheck_action == RecheckAction::Demote
+        && revoke_whitelist(
+            state,
+            target.chat_id,
+            target.user_id,
+            &verdict.classifier,
+            &format!("повторная проверка: {}%, {}", verdict.spam_score, verdict.notes),
+        )
+        .await?;
+
+    record_moderation(state, ModerationEvent {
+        chat_id: target.chat_id,
+        user_id: target.user_id,
+        message_id: Some(target.message_id),
+        action: "recheck_spam".to_string(),
+        score: Some(verdict.spam_score),
+        notes: Some(verdict.notes.clone()),
+        actor: Some(verdict.classifier.clone()),
+        text: Some(job.input.text.clone()),
+        prompt_version: verdict.prompt_version.clone(),
+    }).await;
+
+    let chat: ChatConfig = chat_config(state, &config, target.chat_id).await;
+    let alert: String = format!(
+        "Похоже на спам от пользователя из белого списка ({}%, {}): {} в чате «{}»{}\n\n{}",
+        verdict.spam_score,
+        verdict.notes,
+        job.username_tag,
+        job.chat_name,
+        if demoted { ", он удалён из белого списка" } else { "" },
+        job.input.text
+    );
+    // С чатом модераторов решение принимается кнопками, иначе — уведомление администратору
+    if chat.review_chat_id.is_some() {
+        send_for_review(client, base_url, &chat, target, &alert, false).await;
+    } else {
+        let (notify_chat, text, reply_to) = fallback_alert(&chat, job, &alert);
+        send_message(client, base_url, notify_chat, &text, reply_to).await?;
+    }
+    Ok(())
+}
+
+/// Куда и что отправить без чата модераторов. Получатель настройки `notify` видит полное предупреждение,
+/// а в сам чат уходит только короткое уведомление ответом на сообщение: текст подозрительного сообщения
+/// туда не повторяется, чтобы бот не размножал спам взломанного аккаунта.
+fn fallback_alert(chat: &ChatConfig, job: &RecheckJob, alert: &str) -> (i64, String, Option<i64>) {
+    let mention: String = chat.tag_username.as_ref().map(|u| format!("@{u} ")).unwrap_or_default();
+    match chat.notify_user_id {
+        Some(user_id) => (user_id, format!("{mention}{alert}"), None),
+        None => (
+            chat.chat_id,
+            format!("{mention}Сообщение {} похоже на спам, проверьте его", job.username_tag),
+            Some(job.target.message_id),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job() -> RecheckJob {
+        RecheckJob {
+            target: SpamTarget { chat_id: -100, message_id: 7, user_id: 42 },
+            username_tag: "@trusted".to_string(),
+            chat_name: "Группа".to_string(),
+            input: ClassifyInput::for_tests("Заработок от 500$ в день, пиши в лс"),
+        }
+    }
+
+    fn chat(extra: &str) -> ChatConfig {
+        let config: Config = Config::from_toml(&format!("telegram_bot_token = \"x\"\n{extra}")).unwrap();
+        ChatConfig::resolve(&config, -100, None)
+    }
+
+    #[test]
+    fn alert_goes_to_notify_recipient_in_full() {
+        let (recipient, text, reply_to) = fallback_alert(&chat("notify_user_id = 5"), &job(), "предупреждение с текстом");
+        assert_eq!(recipient, 5);
+        assert_eq!(text, "предупреждение с текстом");
+        assert_eq!(reply_to, None);
+    }
+
+    #[test]
+    fn source_chat_gets_notice_without_message_text() {
+        let job: RecheckJob = job();
+        let alert: String = format!("предупреждение\n\n{}", job.input.text);
+        let (recipient, text, reply_to) = fallback_alert(&chat("teg_username = \"admin\""), &job, &alert);
+        assert_eq!(recipient, -100);
+        assert_eq!(reply_to, Some(7));
+        assert!(text.starts_with("@admin "), "{text}");
+        assert!(text.contains("@trusted"), "{text}");
+        assert!(!text.contains("500$"), "{text}");
+    }
+}
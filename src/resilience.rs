@@ -0,0 +1,312 @@
+use std::{
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    config::Config,
+    spam_checker::{ClassifyInput, SpamClassifier, SpamVerdict},
+};
+
+/// Что делать с сообщением, если ни один бэкенд классификатора не ответил
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Пропустить сообщение, как будто это не спам
+    Open,
+    /// Отправить модераторам, если у чата есть чат проверки, иначе считать спамом
+    Closed,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "open" => Ok(FailurePolicy::Open),
+            "closed" => Ok(FailurePolicy::Closed),
+            other => anyhow::bail!("Неизвестная политика отказа '{other}' (допустимо: open, closed)"),
+        }
+    }
+}
+
+/// Запасная модель Ollama: `модель` или `модель@адрес`; без адреса используется `OLLAMA_URL`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FallbackEndpoint {
+    pub model: String,
+    pub url: Option<String>,
+}
+
+/// Разбирает список запасных моделей через запятую: `qwen2.5:7b@http://gpu:11434,llama3.2:1b`
+pub fn parse_fallbacks(value: &str) -> Result<Vec<FallbackEndpoint>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            let (model, url) = match entry.split_once('@') {
+                Some((model, url)) => (model.trim(), Some(url.trim().to_string())),
+                None => (entry, None),
+            };
+            if model.is_empty() || url.as_deref().is_some_and(str::is_empty) {
+                anyhow::bail!("Ожидается 'модель' или 'модель@адрес', получено '{entry}'");
+            }
+            Ok(FallbackEndpoint { model: model.to_string(), url })
+        })
+        .collect()
+}
+
+/// Состояние предохранителя бэкенда
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    /// До какого момента бэкенд не опрашивается
+    open_until: Option<Instant>,
+    /// Когда после паузы ушёл пробный запрос; пока он не завершился, остальные запросы бэкенд пропускают
+    probe_started: Option<Instant>,
+}
+
+/// Предохранитель: после нескольких отказов подряд бэкенд пропускается на время,
+/// чтобы сообщения не ждали таймаута недоступного сервера. По истечении паузы
+/// бэкенд получает ровно один пробный запрос: ответ возвращает его в работу,
+/// отказ — снова отключает на время паузы.
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    /// Можно ли опросить бэкенд. После паузы разрешает один пробный запрос; если он не завершился
+    /// за время паузы (например, запрос отменён), разрешается следующий.
+    fn allow_request(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now: Instant = Instant::now();
+        match state.open_until {
+            None => true,
+            Some(until) if now < until => false,
+            Some(_) if state.probe_started.is_some_and(|started| now < started + self.cooldown) => false,
+            Some(_) => {
+                state.probe_started = Some(now);
+                true
+            }
+        }
+    }
+
+    /// Сервер ответил: предохранитель закрывается
+    fn record_success(&self) {
+        *self.state.lock().unwrap_or_else(|e| e.into_inner()) = BreakerState::default();
+    }
+
+    /// Возвращает `true`, если предохранитель только что сработал или пробный запрос не прошёл
+    fn record_failure(&self) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.consecutive_failures += 1;
+        if state.consecutive_failures < self.threshold && state.probe_started.is_none() {
+            return false;
+        }
+        state.open_until = Some(Instant::now() + self.cooldown);
+        state.probe_started = None;
+        true
+    }
+}
+
+/// Бэкенд цепочки с подписью для логов
+struct Backend {
+    label: String,
+    classifier: Box<dyn SpamClassifier>,
+    breaker: CircuitBreaker,
+}
+
+/// Опрашивает бэкенды по порядку: каждый с повторами при временных ошибках и предохранителем,
+/// следующий — только если предыдущий не ответил. Модель из настроек чата действует лишь
+/// для первого бэкенда: у запасных своя модель.
+pub struct FallbackClassifier {
+    name: String,
+    backends: Vec<Backend>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl FallbackClassifier {
+    /// `backends` — пары из подписи для логов и классификатора, в порядке опроса
+    pub fn new(config: &Config, backends: Vec<(String, Box<dyn SpamClassifier>)>) -> Self {
+        let name: String = backends.first().map(|(_, c)| c.name().to_string()).unwrap_or_default();
+        Self {
+            name,
+            backends: backends
+                .into_iter()
+                .map(|(label, classifier)| Backend {
+                    label,
+                    classifier,
+                    breaker: CircuitBreaker {
+                        threshold: config.llm_breaker_failures,
+                        cooldown: Duration::from_secs(config.llm_breaker_cooldown_secs),
+                        state: Mutex::new(BreakerState::default()),
+                    },
+                })
+                .collect(),
+            retries: config.llm_retries,
+            backoff: Duration::from_millis(config.llm_retry_backoff_ms),
+        }
+    }
+
+    /// Опрашивает один бэкенд с повторами
+    async fn ask(&self, backend: &Backend, input: &ClassifyInput) -> Result<SpamVerdict> {
+        let mut attempt: u32 = 0;
+        loop {
+            match backend.classifier.classify(input).await {
+                Ok(verdict) => return Ok(verdict),
+                Err(err) if attempt < self.retries && is_transient(&err) => {
+                    let delay: Duration = self.backoff * 2u32.saturating_pow(attempt);
+                    log::debug!("{}: {err}, повтор через {} мс", backend.label, delay.as_millis());
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for FallbackClassifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn classify(&self, input: &ClassifyInput) -> Result<SpamVerdict> {
+        let mut errors: Vec<String> = Vec::new();
+        for (i, backend) in self.backends.iter().enumerate() {
+            if !backend.breaker.allow_request() {
+                errors.push(format!("{}: временно отключён", backend.label));
+                continue;
+            }
+            let fallback_input: ClassifyInput;
+            let input: &ClassifyInput = if i == 0 {
+                input
+            } else {
+                fallback_input = ClassifyInput { model: None, ..input.clone() };
+                &fallback_input
+            };
+            match self.ask(backend, input).await {
+                Ok(verdict) => {
+                    backend.breaker.record_success();
+                    if i > 0 {
+                        log::info!("Ответил запасной бэкенд {}", backend.label);
+                    }
+                    return Ok(verdict);
+                }
+                Err(err) => {
+                    log::warn!("Бэкенд {} не ответил: {err:?}", backend.label);
+                    // Непонятный ответ — не отказ сервера: сервер доступен, предохранитель закрывается
+                    if err.downcast_ref::<reqwest::Error>().is_none() {
+                        backend.breaker.record_success();
+                    } else if backend.breaker.record_failure() {
+                        log::warn!(
+                            "Бэкенд {} отключён на {} с после отказов подряд",
+                            backend.label,
+                            backend.breaker.cooldown.as_secs()
+                        );
+                    }
+                    errors.push(format!("{}: {err}", backend.label));
+                }
+            }
+        }
+        anyhow::bail!("Ни один бэкенд {} не ответил: {}", self.name, errors.join("; "))
+    }
+}
+
+/// Временная ошибка, которую имеет смысл повторить: таймаут, обрыв соединения,
+/// перегрузка или ошибка сервера
+fn is_transient(err: &anyhow::Error) -> bool {
+    let Some(err) = err.downcast_ref::<reqwest::Error>() else {
+        return false;
+    };
+    err.is_timeout()
+        || err.is_connect()
+        || err.is_request()
+        || err
+            .status()
+            .is_some_and(|s| s.is_server_error() || s == reqwest::StatusCode::TOO_MANY_REQUESTS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker { threshold, cooldown, state: Mutex::new(BreakerState::default()) }
+    }
+
+    #[test]
+    fn breaker_opens_after_consecutive_failures() {
+        let breaker: CircuitBreaker = breaker(2, Duration::from_secs(60));
+        assert!(!breaker.record_failure());
+        assert!(breaker.allow_request());
+        assert!(breaker.record_failure());
+        assert!(!breaker.allow_request());
+    }
+
+    #[test]
+    fn breaker_lets_exactly_one_probe_through_after_cooldown() {
+        let breaker: CircuitBreaker = breaker(1, Duration::from_secs(60));
+        assert!(breaker.record_failure());
+        assert!(!breaker.allow_request());
+        // Пауза истекла
+        breaker.state.lock().unwrap().open_until = Some(Instant::now());
+        assert!(breaker.allow_request());
+        assert!(!breaker.allow_request());
+        assert!(!breaker.allow_request());
+    }
+
+    #[test]
+    fn failed_probe_reopens_and_successful_probe_closes() {
+        let breaker: CircuitBreaker = breaker(3, Duration::from_secs(60));
+        breaker.state.lock().unwrap().open_until = Some(Instant::now());
+        assert!(breaker.allow_request());
+        // Одного отказа пробного запроса достаточно, порог не важен
+        assert!(breaker.record_failure());
+        assert!(!breaker.allow_request());
+
+        breaker.state.lock().unwrap().open_until = Some(Instant::now());
+        assert!(breaker.allow_request());
+        breaker.record_success();
+        assert!(breaker.allow_request());
+        assert!(breaker.allow_request());
+    }
+
+    #[test]
+    fn zero_threshold_disables_breaker() {
+        let breaker: CircuitBreaker = breaker(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            assert!(!breaker.record_failure());
+        }
+        assert!(breaker.allow_request());
+    }
+
+    #[test]
+    fn parse_fallbacks_reads_models_and_urls() {
+        let fallbacks: Vec<FallbackEndpoint> = parse_fallbacks(" qwen2.5:7b@http://gpu:11434 , llama3.2:1b,").unwrap();
+        assert_eq!(
+            fallbacks,
+            vec![
+                FallbackEndpoint { model: "qwen2.5:7b".to_string(), url: Some("http://gpu:11434".to_string()) },
+                FallbackEndpoint { model: "llama3.2:1b".to_string(), url: None },
+            ]
+        );
+        assert!(parse_fallbacks("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_fallbacks_rejects_empty_parts() {
+        assert!(parse_fallbacks("@http://gpu:11434").is_err());
+        assert!(parse_fallbacks("qwen@").is_err());
+    }
+}
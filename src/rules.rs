@@ -0,0 +1,433 @@
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use tokio::fs;
+
+use crate::{
+    entities::domain_of,
+    spam_checker::{ClassifyInput, SpamClassifier, SpamVerdict},
+};
+
+/// Как часто проверяется, не изменился ли файл правил
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Правило: если в тексте (в нижнем регистре) встречается любая из фраз, к оценке добавляется `score`
+pub struct Rule {
+    pub name: String,
+    pub phrases: Vec<String>,
+    pub score: u8,
+}
+
+/// Жёсткое действие правила из файла: решение принимается без классификаторов
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Спам с оценкой 100, в том числе от пользователей из вайтлиста
+    Spam,
+    /// Не спам с оценкой 0
+    Allow,
+}
+
+impl FromStr for RuleAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "spam" => Ok(RuleAction::Spam),
+            "allow" => Ok(RuleAction::Allow),
+            other => anyhow::bail!("Неизвестное действие правила '{other}' (допустимо: spam, allow)"),
+        }
+    }
+}
+
+/// Содержимое файла правил
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleSpec>,
+}
+
+/// Правило в том виде, в каком оно записано в файле.
+/// Внутри списка достаточно одного совпадения, а все заданные условия должны выполниться вместе.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    name: String,
+    /// Фразы, любая из которых должна встретиться в тексте
+    #[serde(default)]
+    keywords: Vec<String>,
+    /// Регулярные выражения, любое из которых должно найтись в тексте
+    #[serde(default)]
+    regex: Vec<String>,
+    /// Домены ссылок, включая поддомены
+    #[serde(default)]
+    domains: Vec<String>,
+    /// Есть ли ссылки на ботов, в том числе с параметром `start`
+    bot_links: Option<bool>,
+    /// Есть ли приглашения в чаты
+    invite_links: Option<bool>,
+    /// Есть ли скрытые ссылки: гиперссылки на тексте и кнопки
+    hidden_links: Option<bool>,
+    min_links: Option<usize>,
+    min_mentions: Option<usize>,
+    /// Новый ли аккаунт по `NEW_ACCOUNT_ID_FROM`
+    new_account: Option<bool>,
+    #[serde(default)]
+    user_ids: Vec<i64>,
+    /// Сколько правило добавляет к оценке классификатора `rules`
+    score: Option<u8>,
+    /// Жёсткое действие: `spam` или `allow`
+    action: Option<String>,
+}
+
+/// Что делает сработавшее правило
+enum RuleEffect {
+    Score(u8),
+    Action(RuleAction),
+}
+
+/// Проверенное и скомпилированное правило из файла
+struct FileRule {
+    name: String,
+    keywords: Vec<String>,
+    regex: Vec<Regex>,
+    domains: Vec<String>,
+    bot_links: Option<bool>,
+    invite_links: Option<bool>,
+    hidden_links: Option<bool>,
+    min_links: Option<usize>,
+    min_mentions: Option<usize>,
+    new_account: Option<bool>,
+    user_ids: Vec<i64>,
+    effect: RuleEffect,
+}
+
+impl FileRule {
+    fn compile(spec: RuleSpec) -> Result<Self> {
+        let name: String = spec.name.trim().to_string();
+        if name.is_empty() {
+            anyhow::bail!("У правила нет имени");
+        }
+        let effect: RuleEffect = match (spec.score, spec.action.as_deref()) {
+            (Some(score), None) => RuleEffect::Score(score.min(100)),
+            (None, Some(action)) => RuleEffect::Action(action.parse().with_context(|| format!("Правило «{name}»"))?),
+            _ => anyhow::bail!("Правило «{name}»: нужно задать ровно одно из score и action"),
+        };
+        let regex: Vec<Regex> = spec
+            .regex
+            .iter()
+            .map(|r| RegexBuilder::new(r).case_insensitive(true).build())
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Правило «{name}»: некорректное регулярное выражение"))?;
+
+        let rule: FileRule = FileRule {
+            keywords: spec.keywords.iter().map(|k| k.trim().to_lowercase()).filter(|k| !k.is_empty()).collect(),
+            regex,
+            domains: spec.domains.iter().filter_map(|d| domain_of(d)).collect(),
+            bot_links: spec.bot_links,
+            invite_links: spec.invite_links,
+            hidden_links: spec.hidden_links,
+            min_links: spec.min_links,
+            min_mentions: spec.min_mentions,
+            new_account: spec.new_account,
+            user_ids: spec.user_ids,
+            effect,
+            name,
+        };
+        let has_condition: bool = !rule.keywords.is_empty()
+            || !rule.regex.is_empty()
+            || !rule.domains.is_empty()
+            || !rule.user_ids.is_empty()
+            || [rule.bot_links, rule.invite_links, rule.hidden_links, rule.new_account].iter().any(Option::is_some)
+            || rule.min_links.is_some()
+            || rule.min_mentions.is_some();
+        if !has_condition {
+            anyhow::bail!("Правило «{}» без условий сработало бы на любое сообщение", rule.name);
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, input: &ClassifyInput, lowercase_text: &str, new_account_id_from: i64) -> bool {
+        let features = &input.features;
+        (self.keywords.is_empty() || self.keywords.iter().any(|k| lowercase_text.contains(k.as_str())))
+            && (self.regex.is_empty() || self.regex.iter().any(|r| r.is_match(&input.text)))
+            && (self.domains.is_empty() || features.blocked_domain(&self.domains).is_some())
+            && self.bot_links.is_none_or(|want| (features.bot_links() > 0) == want)
+            && self.invite_links.is_none_or(|want| (features.invite_links() > 0) == want)
+            && self.hidden_links.is_none_or(|want| (features.hidden_urls > 0) == want)
+            && self.min_links.is_none_or(|n| features.urls.len() >= n)
+            && self.min_mentions.is_none_or(|n| features.mentions.len() >= n)
+            && self.new_account.is_none_or(|want| (input.user_id >= new_account_id_from) == want)
+            && (self.user_ids.is_empty() || self.user_ids.contains(&input.user_id))
+    }
+}
+
+/// Разбирает и проверяет файл правил целиком: одна ошибка отклоняет весь файл
+fn parse_rules(content: &str) -> Result<Vec<FileRule>> {
+    let file: RulesFile = toml::from_str(content)?;
+    let mut names: HashSet<String> = HashSet::new();
+    let mut rules: Vec<FileRule> = Vec::with_capacity(file.rule.len());
+    for spec in file.rule {
+        let rule: FileRule = FileRule::compile(spec)?;
+        if !names.insert(rule.name.clone()) {
+            anyhow::bail!("Правило «{}» задано дважды", rule.name);
+        }
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+/// Правила из файла `RULES_FILE`. Файл перечитывается при изменении;
+/// если новая версия некорректна, продолжают действовать прежние правила.
+pub struct RuleEngine {
+    path: Option<PathBuf>,
+    rules: RwLock<Vec<FileRule>>,
+    /// Время изменения загруженной версии файла
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl RuleEngine {
+    /// Загружает правила из файла, если он задан. Ошибка в файле при запуске — ошибка запуска.
+    pub async fn load(path: Option<PathBuf>) -> Result<Self> {
+        let engine: RuleEngine = RuleEngine {
+            path,
+            rules: RwLock::new(Vec::new()),
+            modified: Mutex::new(None),
+        };
+        if let Some(path) = engine.path.as_ref() {
+            engine
+                .reload_if_changed()
+                .await
+                .with_context(|| format!("Не удалось загрузить правила из {}", path.display()))?;
+        }
+        Ok(engine)
+    }
+
+    /// Перечитывает файл, если он изменился с прошлой загрузки. Возвращает `true`, если правила обновлены.
+    pub async fn reload_if_changed(&self) -> Result<bool> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(false);
+        };
+        let modified: SystemTime = fs::metadata(path).await?.modified()?;
+        if *self.modified.lock().unwrap_or_else(|e| e.into_inner()) == Some(modified) {
+            return Ok(false);
+        }
+        // Время запоминается и при ошибке, чтобы не повторять её в логе до следующей правки файла
+        *self.modified.lock().unwrap_or_else(|e| e.into_inner()) = Some(modified);
+        let rules: Vec<FileRule> = parse_rules(&fs::read_to_string(path).await?)?;
+        log::info!("Загружено правил из {}: {}", path.display(), rules.len());
+        *self.rules.write().unwrap_or_else(|e| e.into_inner()) = rules;
+        Ok(true)
+    }
+
+    /// Первое сработавшее правило с жёстким действием; правила `spam` важнее `allow`
+    pub fn hard_verdict(&self, input: &ClassifyInput, new_account_id_from: i64) -> Option<SpamVerdict> {
+        let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+        let text: String = input.text.to_lowercase();
+        let matched: Vec<(&str, RuleAction)> = rules
+            .iter()
+            .filter_map(|r| match r.effect {
+                RuleEffect::Action(action) => Some((r, action)),
+                RuleEffect::Score(_) => None,
+            })
+            .filter(|(r, _)| r.matches(input, &text, new_account_id_from))
+            .map(|(r, action)| (r.name.as_str(), action))
+            .collect();
+        let (name, action) = matched
+            .iter()
+            .find(|(_, a)| *a == RuleAction::Spam)
+            .or_else(|| matched.first())?;
+        Some(SpamVerdict {
+            spam_score: if *action == RuleAction::Spam { 100 } else { 0 },
+            notes: format!("правило «{name}»"),
+            classifier: "rules".to_string(),
+            prompt_version: None,
+        })
+    }
+
+    /// Сработавшие правила с оценкой: имя и вклад
+    fn scored_matches(&self, input: &ClassifyInput, lowercase_text: &str, new_account_id_from: i64) -> Vec<(String, u8)> {
+        let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+        rules
+            .iter()
+            .filter_map(|r| match r.effect {
+                RuleEffect::Score(score) => Some((r, score)),
+                RuleEffect::Action(_) => None,
+            })
+            .filter(|(r, _)| r.matches(input, lowercase_text, new_account_id_from))
+            .map(|(r, score)| (r.name.clone(), score))
+            .collect()
+    }
+}
+
+/// Фоновая задача: перечитывает файл правил после изменения
+pub async fn run_reload_loop(engine: Arc<RuleEngine>) {
+    if engine.path.is_none() {
+        return;
+    }
+    loop {
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        if let Err(err) = engine.reload_if_changed().await {
+            log::warn!("Не удалось перечитать правила, действуют прежние: {err:?}");
+        }
+    }
+}
+
+/// Детерминированный классификатор на фразах-маркерах и правилах из файла с оценкой.
+/// Не требует сети и отвечает мгновенно, поэтому годится как дешёвый бэкенд или дополнение к LLM.
+pub struct RulesClassifier {
+    rules: Vec<Rule>,
+    file_rules: Arc<RuleEngine>,
+    new_account_id_from: i64,
+}
+
+impl RulesClassifier {
+    /// Встроенный набор правил для типичного спама в русскоязычных чатах и правила из файла.
+    /// Фразы привязаны к контексту объявлений: в чате программистов «удалённый репозиторий»
+    /// или «p2p-сеть» — обычные слова.
+    pub fn with_builtin_rules(file_rules: Arc<RuleEngine>, new_account_id_from: i64) -> Self {
+        let rule = |name: &str, phrases: &[&str], score: u8| Rule {
+            name: name.to_string(),
+            phrases: phrases.iter().map(|p| p.to_string()).collect(),
+            score,
+        };
+
+        Self {
+            rules: vec![
+                rule("призыв в лс", &["пиши в лс", "пишите в лс", "писать в лс", "в личку", "в личные сообщения"], 40),
+                rule("обещание дохода", &["заработок от", "доход от", "без вложений", "пассивный доход", "в день от"], 40),
+                rule(
+                    "рекрутинг",
+                    &[
+                        "удалённая работа",
+                        "удаленная работа",
+                        "удалённую работу",
+                        "удаленную работу",
+                        "удалённый заработок",
+                        "удаленный заработок",
+                        "удалённой онлайн",
+                        "удаленной онлайн",
+                        "подработк",
+                        "ищу ответственных",
+                        "набираем в команду",
+                    ],
+                    30,
+                ),
+                rule("крипта", &["usdt", "арбитраж крипт", "p2p арбитраж", "p2p-арбитраж", "арбитраж p2p", "инвестиции в крипт"], 30),
+                rule("ставки", &["ставки на спорт", "ставках", "казино", "букмекер"], 40),
+            ],
+            file_rules,
+            new_account_id_from,
+        }
+    }
+
+    /// Сработавшие встроенные правила для текста в нижнем регистре
+    fn builtin_matches(&self, text: &str) -> Vec<(String, u8)> {
+        self.rules
+            .iter()
+            .filter(|r| r.phrases.iter().any(|p| text.contains(p.as_str())))
+            .map(|r| (r.name.clone(), r.score))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for RulesClassifier {
+    fn name(&self) -> &str {
+        "rules"
+    }
+
+    async fn classify(&self, input: &ClassifyInput) -> Result<SpamVerdict> {
+        let text: String = input.text.to_lowercase();
+        let mut matched: Vec<(String, u8)> = self.builtin_matches(&text);
+        matched.extend(self.file_rules.scored_matches(input, &text, self.new_account_id_from));
+
+        let score: u32 = matched.iter().map(|(_, score)| u32::from(*score)).sum();
+        let notes: String = if matched.is_empty() {
+            "правила не сработали".to_string()
+        } else {
+            matched.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", ")
+        };
+
+        Ok(SpamVerdict {
+            spam_score: score.min(100) as u8,
+            notes,
+            classifier: self.name().to_string(),
+            prompt_version: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Длинное безобидное начало, после которого идёт спам
+    fn long_text(tail: &str) -> String {
+        format!("{} {tail}", "Разбираю, почему компилятор ругается на заимствование в цикле. ".repeat(6))
+    }
+
+    fn classifier() -> RulesClassifier {
+        let engine: RuleEngine = RuleEngine { path: None, rules: RwLock::new(Vec::new()), modified: Mutex::new(None) };
+        RulesClassifier::with_builtin_rules(Arc::new(engine), 0)
+    }
+
+    fn matched(text: &str) -> Vec<String> {
+        classifier().builtin_matches(&text.to_lowercase()).into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn builtin_rules_ignore_technical_talk() {
+        assert!(matched("Удалённый репозиторий не отвечает, git push висит").is_empty());
+        assert!(matched("удаленно подключился по ssh, всё работает").is_empty());
+        assert!(matched("libp2p и p2p-сети в Rust — кто пробовал?").is_empty());
+    }
+
+    #[test]
+    fn builtin_rules_catch_job_and_crypto_ads() {
+        assert_eq!(matched("Удалённая работа, пиши в лс"), vec!["призыв в лс", "рекрутинг"]);
+        assert_eq!(
+            matched("Ищу людей от 20 лет для удалённой онлайн-деятельности"),
+            vec!["рекрутинг"]
+        );
+        assert_eq!(matched("Обучу P2P арбитражу, связки каждый день"), vec!["крипта"]);
+    }
+
+    #[test]
+    fn rules_see_text_past_llm_limit() {
+        let engine: RuleEngine = RuleEngine {
+            path: None,
+            rules: RwLock::new(
+                parse_rules(
+                    "[[rule]]\nname = \"бот-ссылка\"\nregex = ['\\?start=']\naction = \"spam\"\n\
+                     [[rule]]\nname = \"казино\"\nkeywords = [\"казино\"]\nscore = 20\n",
+                )
+                .unwrap(),
+            ),
+            modified: Mutex::new(None),
+        };
+        let text: String = long_text("Жми t.me/luckybot?start=ref42, лучшее казино, пиши в лс");
+        assert!(text.chars().count() > 300);
+        let input: ClassifyInput = ClassifyInput::for_tests(&text);
+
+        let verdict: SpamVerdict = engine.hard_verdict(&input, i64::MAX).unwrap();
+        assert_eq!((verdict.spam_score, verdict.notes.as_str()), (100, "правило «бот-ссылка»"));
+        let scored: Vec<(String, u8)> = engine.scored_matches(&input, &text.to_lowercase(), i64::MAX);
+        assert_eq!(scored, vec![("казино".to_string(), 20)]);
+    }
+
+    #[tokio::test]
+    async fn builtin_rules_see_text_past_llm_limit() {
+        let verdict: SpamVerdict = classifier().classify(&ClassifyInput::for_tests(&long_text("Пишите в лс, расскажу"))).await.unwrap();
+        assert_eq!(verdict.notes, "призыв в лс");
+    }
+}
@@ -1,85 +1,218 @@
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock as SyncRwLock},
 };
 
 use anyhow::Result;
-use tokio::{fs, io::AsyncWriteExt, sync::RwLock};
+use tokio::sync::{mpsc, RwLock};
+
+use crate::{
+    actions::unix_now,
+    bayes::BayesClassifier,
+    chat_settings::{ChatConfig, ChatSettings},
+    config::Config,
+    duplicates::DuplicateDetector,
+    recheck::RecheckJob,
+    rules::RuleEngine,
+    spam_checker::SpamClassifier,
+    storage::{ModerationEvent, PendingCaptcha, Storage, WhitelistEntry},
+};
 
 pub struct AppState {
-    pub user_ham_counter: RwLock<HashMap<i64, u32>>,
-    pub whitelist_cache: RwLock<HashSet<i64>>,
+    /// Вайтлист с метаданными записей
+    pub whitelist_cache: RwLock<HashMap<i64, WhitelistEntry>>,
+    /// Классификатор; пересобирается при перезагрузке конфигурации
+    classifier: SyncRwLock<Arc<dyn SpamClassifier>>,
+    /// Статистическая модель, дообучаемая на вердиктах модераторов
+    pub bayes: Arc<BayesClassifier>,
+    /// Переопределения настроек чатов, заданные командами администраторов
+    pub chat_settings: RwLock<HashMap<i64, ChatSettings>>,
+    /// Постоянное хранилище; кэши выше — его копия в памяти
+    pub storage: Box<dyn Storage>,
+    /// Незавершённые проверки новых участников по (чат, пользователь)
+    pub captchas: RwLock<HashMap<(i64, i64), PendingCaptcha>>,
+    /// Username бота из getMe, чтобы отличать свои команды `/cmd@bot` от чужих
+    pub bot_username: OnceLock<String>,
+    /// Очередь повторной проверки сообщений из вайтлиста; задаётся, если проверка включена
+    pub recheck_queue: OnceLock<mpsc::Sender<RecheckJob>>,
+    /// Недавние сообщения для поиска повторов и флуда
+    pub duplicates: DuplicateDetector,
+    /// Правила из файла с жёсткими действиями; задаются при запуске
+    pub rules: OnceLock<Arc<RuleEngine>>,
 }
 
 impl AppState {
-    /// Создаёт новое состояние приложения с переданным набором пользователей в вайтлисте.
-    /// Используется для хранения счётчиков HAM и кэша вайтлиста.
-    pub fn new(whitelist: HashSet<i64>) -> Self {
+    /// Создаёт новое состояние приложения с вайтлистом, настройками чатов и проверками,
+    /// загруженными из хранилища. Счётчики сообщений читаются из хранилища по мере надобности.
+    pub fn new(
+        storage: Box<dyn Storage>,
+        whitelist: HashMap<i64, WhitelistEntry>,
+        chat_settings: HashMap<i64, ChatSettings>,
+        captchas: Vec<PendingCaptcha>,
+        classifier: Box<dyn SpamClassifier>,
+        bayes: Arc<BayesClassifier>,
+    ) -> Self {
         Self {
-            user_ham_counter: RwLock::new(HashMap::new()),
             whitelist_cache: RwLock::new(whitelist),
+            classifier: SyncRwLock::new(Arc::from(classifier)),
+            bayes,
+            chat_settings: RwLock::new(chat_settings),
+            captchas: RwLock::new(captchas.into_iter().map(|c| ((c.chat_id, c.user_id), c)).collect()),
+            storage,
+            bot_username: OnceLock::new(),
+            recheck_queue: OnceLock::new(),
+            duplicates: DuplicateDetector::default(),
+            rules: OnceLock::new(),
         }
     }
+
+    /// Действующий классификатор; начатая проверка заканчивается тем, с которым началась
+    pub fn classifier(&self) -> Arc<dyn SpamClassifier> {
+        self.classifier.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_classifier(&self, classifier: Box<dyn SpamClassifier>) {
+        *self.classifier.write().unwrap_or_else(|e| e.into_inner()) = Arc::from(classifier);
+    }
 }
 
-/// Проверяет, находится ли пользователь в кэшированном вайтлисте.
-pub async fn is_user_whitelisted(user_id: i64, state: &AppState) -> Result<bool> {
-    let cache: tokio::sync::RwLockReadGuard<'_, HashSet<i64>> = state.whitelist_cache.read().await;
-    Ok(cache.contains(&user_id))
+#[cfg(test)]
+impl AppState {
+    /// Пустое состояние с базой `database` (например, `:memory:`) и классификатором по ссылкам
+    pub async fn for_tests(database: &std::path::Path) -> Self {
+        let storage: crate::storage::SqliteStorage = crate::storage::SqliteStorage::open(database).unwrap();
+        let bayes: Arc<BayesClassifier> = Arc::new(BayesClassifier::load("/nonexistent/bayes.json".into(), 10).await);
+        let classifier: Box<dyn SpamClassifier> = Box::new(crate::spam_checker::LinkHeuristic);
+        Self::new(Box::new(storage), HashMap::new(), HashMap::new(), Vec::new(), classifier, bayes)
+    }
 }
 
-/// Добавляет пользователя в вайтлист (кэш и файл).
-pub async fn add_user_to_whitelist(user_id: i64, state: &AppState, whitelist_path: &PathBuf) -> Result<()> {
-    {
-        let mut cache: tokio::sync::RwLockWriteGuard<'_, HashSet<i64>> = state.whitelist_cache.write().await;
-        if !cache.insert(user_id) {
-            return Ok(());
+/// Загружает переопределения настроек чатов из хранилища.
+/// Некорректные записи (например, оставшиеся от старой версии) пропускаются с предупреждением.
+pub async fn load_chat_settings(storage: &dyn Storage) -> Result<HashMap<i64, ChatSettings>> {
+    let mut result: HashMap<i64, ChatSettings> = HashMap::new();
+    for (chat_id, pairs) in storage.load_chat_settings().await? {
+        let settings: &mut ChatSettings = result.entry(chat_id).or_default();
+        for (key, value) in pairs {
+            if let Err(err) = settings.set(&key, &value) {
+                log::warn!("Пропускаю настройку {key}={value} чата {chat_id}: {err}");
+            }
         }
     }
-    append_line_to_file(whitelist_path, &user_id.to_string()).await
+    Ok(result)
 }
 
-/// Увеличивает счётчик не-СПАМ сообщений для пользователя и возвращает текущее значение.
-pub async fn increment_ham_counter(user_id: i64, state: &AppState) -> u32 {
-    let mut map: tokio::sync::RwLockWriteGuard<'_, HashMap<i64, u32>> = state.user_ham_counter.write().await;
-    let entry: &mut u32 = map.entry(user_id).or_insert(0);
-    *entry += 1;
-    *entry
+/// Возвращает действующие настройки чата с учётом переопределений.
+pub async fn chat_config(state: &AppState, config: &Config, chat_id: i64) -> ChatConfig {
+    let settings: tokio::sync::RwLockReadGuard<'_, HashMap<i64, ChatSettings>> = state.chat_settings.read().await;
+    ChatConfig::resolve(config, chat_id, settings.get(&chat_id))
 }
 
-/// Загружает вайтлист из файла, возвращая пустой если файл не существует.
-/// Формат файла: по одному user_id на строку.
-pub async fn load_whitelist(path: &PathBuf) -> Result<HashSet<i64>> {
-    if !path.exists() {
-        return Ok(HashSet::new());
+/// Изменяет настройки чата (`None` — сброс к глобальному значению) в кэше и хранилище.
+/// Изменения применяются все вместе или ни одно: значения и действующие после изменения пороги
+/// проверяются до сохранения, поэтому в базу не попадают некорректные настройки.
+pub async fn update_chat_settings(
+    state: &AppState,
+    config: &Config,
+    chat_id: i64,
+    changes: &[(&str, Option<&str>)],
+) -> Result<()> {
+    let mut settings: tokio::sync::RwLockWriteGuard<'_, HashMap<i64, ChatSettings>> = state.chat_settings.write().await;
+    let mut updated: ChatSettings = settings.get(&chat_id).cloned().unwrap_or_default();
+    for (key, value) in changes {
+        match value {
+            Some(value) => updated.set(key, value)?,
+            None => updated.unset(key)?,
+        }
+    }
+    if let Some(problem) = ChatConfig::resolve(config, chat_id, Some(&updated)).threshold_problem() {
+        anyhow::bail!("{problem}");
     }
 
-    let content: String = fs::read_to_string(path).await.unwrap_or_else(|e| {
-        log::warn!("Не удалось прочитать файл вайтлиста {}: {}", path.display(), e);
-        String::new()
-    });
-    
-    Ok(content
-        .lines()
-        .filter_map(|l| l.trim().parse::<i64>().ok())
-        .collect())
+    let stored: Vec<(String, Option<String>)> = changes
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.map(str::to_string)))
+        .collect();
+    state.storage.set_chat_settings(chat_id, stored).await?;
+    settings.insert(chat_id, updated);
+    Ok(())
 }
 
-/// Добавляет строку в конец файла, создавая его при необходимости.
-/// Вспомогательная функция, используется только внутри модуля.
-async fn append_line_to_file(path: &PathBuf, line: &str) -> Result<()> {
-    let mut file: fs::File = if path.exists() {
-        tokio::fs::OpenOptions::new().append(true).open(path).await?
-    } else {
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).await.ok();
-        }
-        tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
-            .await?
+/// Проверяет, находится ли пользователь в кэшированном вайтлисте.
+/// Истёкшая запись удаляется, и пользователь снова зарабатывает доверие с нуля.
+pub async fn is_user_whitelisted(user_id: i64, state: &AppState) -> Result<bool> {
+    let expired: bool = match state.whitelist_cache.read().await.get(&user_id) {
+        Some(entry) => entry.is_expired(unix_now()),
+        None => return Ok(false),
     };
-    file.write_all(format!("{}\n", line).as_bytes()).await?;
-    Ok(())
+    if expired {
+        remove_user_from_whitelist(user_id, state).await?;
+        log::info!("Срок записи пользователя {user_id} в белом списке истёк");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Добавляет пользователя в вайтлист (кэш и хранилище) или заменяет его запись.
+/// Возвращает `false`, если пользователь уже был в вайтлисте.
+pub async fn add_user_to_whitelist(state: &AppState, entry: WhitelistEntry) -> Result<bool> {
+    let added: bool = state
+        .whitelist_cache
+        .write()
+        .await
+        .insert(entry.user_id, entry.clone())
+        .is_none();
+    state.storage.add_to_whitelist(entry).await?;
+    Ok(added)
+}
+
+/// Удаляет пользователя из вайтлиста (кэш и хранилище) и сбрасывает его прогресс к вайтлисту.
+/// Возвращает `false`, если пользователя в вайтлисте не было.
+pub async fn remove_user_from_whitelist(user_id: i64, state: &AppState) -> Result<bool> {
+    state.whitelist_cache.write().await.remove(&user_id);
+    state.storage.remove_from_whitelist(user_id).await
+}
+
+/// Отзывает доверие к пользователю: удаляет его из вайтлиста и записывает причину в историю модерации.
+/// Возвращает `false`, если пользователя в вайтлисте не было.
+pub async fn revoke_whitelist(state: &AppState, chat_id: i64, user_id: i64, actor: &str, reason: &str) -> Result<bool> {
+    if !remove_user_from_whitelist(user_id, state).await? {
+        return Ok(false);
+    }
+    log::info!("Пользователь {user_id} удалён из белого списка в чате {chat_id}: {reason}");
+    record_moderation(state, ModerationEvent {
+        chat_id,
+        user_id,
+        action: "unwhitelist".to_string(),
+        notes: Some(reason.to_string()),
+        actor: Some(actor.to_string()),
+        ..Default::default()
+    }).await;
+    Ok(true)
+}
+
+/// Увеличивает счётчик не-СПАМ сообщений для пользователя и возвращает текущее значение.
+/// Значение сохраняется в хранилище, поэтому прогресс к вайтлисту переживает перезапуск.
+pub async fn increment_ham_counter(user_id: i64, state: &AppState) -> Result<u32> {
+    state.storage.increment_counter(user_id, false).await
+}
+
+/// Исправляет счётчики пользователя после того, как решение бота оспорено.
+/// Возвращает новое значение счётчика не-СПАМ сообщений.
+pub async fn relabel_counter(user_id: i64, state: &AppState, is_spam: bool) -> Result<u32> {
+    let (ham, _spam) = state.storage.relabel_counter(user_id, is_spam).await?;
+    Ok(ham)
+}
+
+/// Увеличивает счётчик СПАМ сообщений для пользователя.
+pub async fn increment_spam_counter(user_id: i64, state: &AppState) -> Result<u32> {
+    state.storage.increment_counter(user_id, true).await
+}
+
+/// Записывает событие в историю модерации. Ошибка хранилища только логируется,
+/// чтобы сбой записи истории не мешал модерации.
+pub async fn record_moderation(state: &AppState, event: ModerationEvent) {
+    if let Err(err) = state.storage.record_moderation(event).await {
+        log::warn!("Не удалось записать историю модерации: {err:?}");
+    }
 }
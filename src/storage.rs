@@ -0,0 +1,815 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::actions::unix_now;
+
+/// Миграции схемы по порядку; номер применённой хранится в `PRAGMA user_version`
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE whitelist (
+        user_id   INTEGER PRIMARY KEY,
+        added_at  INTEGER NOT NULL,
+        reason    TEXT NOT NULL
+    );
+    CREATE TABLE user_counters (
+        user_id   INTEGER PRIMARY KEY,
+        ham       INTEGER NOT NULL DEFAULT 0,
+        spam      INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE chat_settings (
+        chat_id   INTEGER NOT NULL,
+        key       TEXT NOT NULL,
+        value     TEXT NOT NULL,
+        PRIMARY KEY (chat_id, key)
+    );
+    CREATE TABLE moderation_log (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at  INTEGER NOT NULL,
+        chat_id     INTEGER NOT NULL,
+        user_id     INTEGER NOT NULL,
+        message_id  INTEGER,
+        action      TEXT NOT NULL,
+        score       INTEGER,
+        notes       TEXT,
+        actor       TEXT,
+        text        TEXT
+    );
+    CREATE INDEX moderation_log_user ON moderation_log (user_id);
+    CREATE TABLE meta (
+        key    TEXT PRIMARY KEY,
+        value  TEXT NOT NULL
+    );
+    "#,
+    r#"
+    CREATE TABLE labelled_examples (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at  INTEGER NOT NULL,
+        chat_id     INTEGER NOT NULL,
+        user_id     INTEGER NOT NULL,
+        message_id  INTEGER,
+        text        TEXT NOT NULL,
+        is_spam     INTEGER NOT NULL,
+        source      TEXT NOT NULL,
+        reporter    TEXT
+    );
+    "#,
+    r#"
+    CREATE TABLE pending_captcha (
+        chat_id     INTEGER NOT NULL,
+        user_id     INTEGER NOT NULL,
+        message_id  INTEGER NOT NULL,
+        answer      TEXT NOT NULL,
+        expires_at  INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, user_id)
+    );
+    "#,
+    r#"
+    ALTER TABLE user_counters ADD COLUMN first_seen INTEGER;
+    "#,
+    r#"
+    ALTER TABLE whitelist ADD COLUMN added_by TEXT;
+    ALTER TABLE whitelist ADD COLUMN expires_at INTEGER;
+    UPDATE whitelist SET added_by = substr(reason, 7), reason = 'manual' WHERE reason LIKE 'admin %';
+    UPDATE whitelist SET added_by = reason WHERE added_by IS NULL;
+    "#,
+    r#"
+    ALTER TABLE moderation_log ADD COLUMN prompt_version TEXT;
+    "#,
+    r#"
+    CREATE TABLE pending_updates (
+        update_id    INTEGER PRIMARY KEY,
+        payload      TEXT NOT NULL,
+        received_at  INTEGER NOT NULL
+    );
+    "#,
+    r#"
+    ALTER TABLE pending_captcha ADD COLUMN passed INTEGER NOT NULL DEFAULT 0;
+    "#,
+    r#"
+    -- Пользователи, которых бот знал до испытательного срока, — не новички
+    UPDATE user_counters SET first_seen = 0 WHERE first_seen IS NULL;
+    "#,
+];
+
+/// Запись в истории модерации
+#[derive(Debug, Clone, Default)]
+pub struct ModerationEvent {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub message_id: Option<i64>,
+    /// Что произошло: `auto_spam`, `review`, `moderator_spam`, `moderator_ham`, `spam_report`,
+    /// `false_positive`, `whitelist`, `unwhitelist` (в том числе автоматический отзыв), `recheck_spam`
+    pub action: String,
+    pub score: Option<u8>,
+    pub notes: Option<String>,
+    /// Кто принял решение: классификатор или модератор
+    pub actor: Option<String>,
+    pub text: Option<String>,
+    /// Версия шаблона промпта, по которому LLM оценила сообщение
+    pub prompt_version: Option<String>,
+}
+
+/// Размеченный пример для обучения и оценки классификаторов
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LabelledExample {
+    /// Заполняется хранилищем при чтении
+    pub created_at: i64,
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub message_id: Option<i64>,
+    pub text: String,
+    pub is_spam: bool,
+    /// Откуда метка: `review`, `spam_command`, `user_report`, `false_positive`
+    pub source: String,
+    pub reporter: Option<String>,
+}
+
+/// Запись вайтлиста: кто, когда и почему доверился пользователю
+#[derive(Debug, Clone)]
+pub struct WhitelistEntry {
+    pub user_id: i64,
+    pub added_at: i64,
+    /// Кто добавил: `auto`, `import` или администратор
+    pub added_by: String,
+    /// Почему добавлен: `auto` (порог корректных сообщений), `manual` (команда) или `import`
+    pub reason: String,
+    /// Когда запись истекает (unix time); `None` — бессрочно
+    pub expires_at: Option<i64>,
+}
+
+impl WhitelistEntry {
+    /// Новая запись со сроком действия в днях; `None` — бессрочно
+    pub fn new(user_id: i64, reason: &str, added_by: &str, ttl_days: Option<u32>) -> Self {
+        let added_at: i64 = unix_now();
+        Self {
+            user_id,
+            added_at,
+            added_by: added_by.to_string(),
+            reason: reason.to_string(),
+            expires_at: ttl_days.map(|days| added_at + i64::from(days) * 86_400),
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+/// Незавершённая проверка нового участника
+#[derive(Debug, Clone)]
+pub struct PendingCaptcha {
+    pub chat_id: i64,
+    pub user_id: i64,
+    /// Сообщение бота с вопросом; удаляется после проверки
+    pub message_id: i64,
+    pub answer: String,
+    /// Когда истекает время на ответ (unix time), а после неудачного завершения — время повторной попытки
+    pub expires_at: i64,
+    /// Ответ верный, но снять ограничения не удалось: повторная попытка снимет их, а не удалит участника
+    pub passed: bool,
+}
+
+/// Постоянное хранилище состояния бота: вайтлист, счётчики, настройки чатов и история модерации
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn load_whitelist(&self) -> Result<HashMap<i64, WhitelistEntry>>;
+
+    /// Добавляет пользователя в вайтлист. Повторное добавление заменяет запись, например продлевает срок.
+    async fn add_to_whitelist(&self, entry: WhitelistEntry) -> Result<()>;
+
+    /// Удаляет пользователя из вайтлиста и обнуляет его счётчик не-СПАМ сообщений,
+    /// чтобы он не вернулся в вайтлист следующим же сообщением. Возвращает `false`, если его там не было.
+    async fn remove_from_whitelist(&self, user_id: i64) -> Result<bool>;
+
+    /// Увеличивает счётчик не-СПАМ или СПАМ сообщений пользователя и возвращает новое значение
+    async fn increment_counter(&self, user_id: i64, is_spam: bool) -> Result<u32>;
+
+    /// Переносит одно сообщение пользователя в счётчик спама или не-спама, когда решение бота исправлено.
+    /// Возвращает новые значения (не-спам, спам).
+    async fn relabel_counter(&self, user_id: i64, is_spam: bool) -> Result<(u32, u32)>;
+
+    /// Счётчик не-СПАМ сообщений и время первого появления пользователя.
+    /// Первое появление запоминается при первом вызове; у пользователей, известных боту
+    /// до появления испытательного срока, оно равно `0`.
+    async fn user_progress(&self, user_id: i64) -> Result<(u32, i64)>;
+
+    /// Запоминает вступление в чат: испытательный срок отсчитывается заново
+    async fn mark_joined(&self, user_id: i64) -> Result<()>;
+
+    async fn record_moderation(&self, event: ModerationEvent) -> Result<()>;
+
+    /// Текст сообщения из истории модерации: после удаления спама его больше негде взять
+    async fn moderated_text(&self, chat_id: i64, message_id: i64) -> Result<Option<String>>;
+
+    async fn record_example(&self, example: LabelledExample) -> Result<()>;
+
+    /// Все размеченные примеры в порядке добавления
+    async fn load_examples(&self) -> Result<Vec<LabelledExample>>;
+
+    /// Загружает переопределения настроек всех чатов как пары ключ-значение
+    async fn load_chat_settings(&self) -> Result<HashMap<i64, Vec<(String, String)>>>;
+
+    /// Сохраняет настройки чата одной транзакцией; `None` удаляет переопределение
+    async fn set_chat_settings(&self, chat_id: i64, changes: Vec<(String, Option<String>)>) -> Result<()>;
+
+    async fn load_captchas(&self) -> Result<Vec<PendingCaptcha>>;
+
+    /// Сохраняет проверку участника, заменяя предыдущую в этом чате
+    async fn save_captcha(&self, captcha: PendingCaptcha) -> Result<()>;
+
+    async fn remove_captcha(&self, chat_id: i64, user_id: i64) -> Result<()>;
+
+    /// Однократно импортирует старый файл `white_user.txt`. Возвращает число импортированных записей.
+    async fn import_whitelist_file(&self, path: &Path) -> Result<usize>;
+
+    /// Сохраняет полученное обновление до конца его обработки
+    async fn save_pending_update(&self, update_id: i64, payload: String) -> Result<()>;
+
+    async fn remove_pending_update(&self, update_id: i64) -> Result<()>;
+
+    /// Обновления, обработка которых не закончилась до остановки, по возрастанию id
+    async fn load_pending_updates(&self) -> Result<Vec<(i64, String)>>;
+}
+
+/// Хранилище во встроенной SQLite.
+/// Запросы короткие, поэтому одно соединение под мьютексом в `spawn_blocking` не становится узким местом.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Открывает (или создаёт) базу и применяет недостающие миграции.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut conn: Connection = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+
+        let version: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx: rusqlite::Transaction<'_> = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+            log::info!("Применена миграция базы №{}", i + 1);
+        }
+
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    /// Выполняет запрос к базе в отдельном потоке, чтобы не блокировать рантайм
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn: Arc<Mutex<Connection>> = self.conn.clone();
+        let result: rusqlite::Result<T> = tokio::task::spawn_blocking(move || {
+            let mut guard = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut guard)
+        })
+        .await?;
+        Ok(result?)
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn load_whitelist(&self) -> Result<HashMap<i64, WhitelistEntry>> {
+        self.with_conn(|conn| {
+            let mut stmt: rusqlite::Statement<'_> =
+                conn.prepare("SELECT user_id, added_at, added_by, reason, expires_at FROM whitelist")?;
+            let rows = stmt.query_map([], |r| {
+                let entry: WhitelistEntry = WhitelistEntry {
+                    user_id: r.get(0)?,
+                    added_at: r.get(1)?,
+                    added_by: r.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    reason: r.get(3)?,
+                    expires_at: r.get(4)?,
+                };
+                Ok((entry.user_id, entry))
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn add_to_whitelist(&self, entry: WhitelistEntry) -> Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO whitelist (user_id, added_at, added_by, reason, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![entry.user_id, entry.added_at, entry.added_by, entry.reason, entry.expires_at],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_from_whitelist(&self, user_id: i64) -> Result<bool> {
+        self.with_conn(move |conn| {
+            let tx: rusqlite::Transaction<'_> = conn.transaction()?;
+            let removed: usize = tx.execute("DELETE FROM whitelist WHERE user_id = ?1", params![user_id])?;
+            tx.execute("UPDATE user_counters SET ham = 0 WHERE user_id = ?1", params![user_id])?;
+            tx.commit()?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn increment_counter(&self, user_id: i64, is_spam: bool) -> Result<u32> {
+        self.with_conn(move |conn| {
+            let column: &str = if is_spam { "spam" } else { "ham" };
+            conn.query_row(
+                &format!(
+                    "INSERT INTO user_counters (user_id, {column}) VALUES (?1, 1)
+                     ON CONFLICT(user_id) DO UPDATE SET {column} = {column} + 1
+                     RETURNING {column}"
+                ),
+                params![user_id],
+                |r| r.get::<_, u32>(0),
+            )
+        })
+        .await
+    }
+
+    async fn relabel_counter(&self, user_id: i64, is_spam: bool) -> Result<(u32, u32)> {
+        self.with_conn(move |conn| {
+            let (to, from) = if is_spam { ("spam", "ham") } else { ("ham", "spam") };
+            conn.query_row(
+                &format!(
+                    "INSERT INTO user_counters (user_id, {to}) VALUES (?1, 1)
+                     ON CONFLICT(user_id) DO UPDATE SET {to} = {to} + 1, {from} = MAX({from} - 1, 0)
+                     RETURNING ham, spam"
+                ),
+                params![user_id],
+                |r| Ok((r.get::<_, u32>(0)?, r.get::<_, u32>(1)?)),
+            )
+        })
+        .await
+    }
+
+    async fn user_progress(&self, user_id: i64) -> Result<(u32, i64)> {
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "INSERT INTO user_counters (user_id, first_seen) VALUES (?1, ?2)
+                 ON CONFLICT(user_id) DO UPDATE SET first_seen = COALESCE(first_seen, excluded.first_seen)
+                 RETURNING ham, first_seen",
+                params![user_id, unix_now()],
+                |r| Ok((r.get::<_, u32>(0)?, r.get::<_, i64>(1)?)),
+            )
+        })
+        .await
+    }
+
+    async fn mark_joined(&self, user_id: i64) -> Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO user_counters (user_id, first_seen) VALUES (?1, ?2)
+                 ON CONFLICT(user_id) DO UPDATE SET first_seen = excluded.first_seen",
+                params![user_id, unix_now()],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn record_moderation(&self, event: ModerationEvent) -> Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO moderation_log
+                    (created_at, chat_id, user_id, message_id, action, score, notes, actor, text, prompt_version)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    unix_now(),
+                    event.chat_id,
+                    event.user_id,
+                    event.message_id,
+                    event.action,
+                    event.score,
+                    event.notes,
+                    event.actor,
+                    event.text,
+                    event.prompt_version,
+                ],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn moderated_text(&self, chat_id: i64, message_id: i64) -> Result<Option<String>> {
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT text FROM moderation_log
+                 WHERE chat_id = ?1 AND message_id = ?2 AND text IS NOT NULL
+                 ORDER BY id DESC LIMIT 1",
+                params![chat_id, message_id],
+                |r| r.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn record_example(&self, example: LabelledExample) -> Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO labelled_examples
+                    (created_at, chat_id, user_id, message_id, text, is_spam, source, reporter)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    unix_now(),
+                    example.chat_id,
+                    example.user_id,
+                    example.message_id,
+                    example.text,
+                    example.is_spam,
+                    example.source,
+                    example.reporter,
+                ],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn load_examples(&self) -> Result<Vec<LabelledExample>> {
+        self.with_conn(|conn| {
+            let mut stmt: rusqlite::Statement<'_> = conn.prepare(
+                "SELECT created_at, chat_id, user_id, message_id, text, is_spam, source, reporter
+                 FROM labelled_examples ORDER BY id",
+            )?;
+            let rows = stmt.query_map([], |r| {
+                Ok(LabelledExample {
+                    created_at: r.get(0)?,
+                    chat_id: r.get(1)?,
+                    user_id: r.get(2)?,
+                    message_id: r.get(3)?,
+                    text: r.get(4)?,
+                    is_spam: r.get(5)?,
+                    source: r.get(6)?,
+                    reporter: r.get(7)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn load_chat_settings(&self) -> Result<HashMap<i64, Vec<(String, String)>>> {
+        let rows: Vec<(i64, String, String)> = self
+            .with_conn(|conn| {
+                let mut stmt: rusqlite::Statement<'_> = conn.prepare("SELECT chat_id, key, value FROM chat_settings")?;
+                let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+                rows.collect()
+            })
+            .await?;
+
+        let mut result: HashMap<i64, Vec<(String, String)>> = HashMap::new();
+        for (chat_id, key, value) in rows {
+            result.entry(chat_id).or_default().push((key, value));
+        }
+        Ok(result)
+    }
+
+    async fn set_chat_settings(&self, chat_id: i64, changes: Vec<(String, Option<String>)>) -> Result<()> {
+        self.with_conn(move |conn| {
+            let tx: rusqlite::Transaction<'_> = conn.transaction()?;
+            for (key, value) in changes {
+                match value {
+                    Some(value) => tx.execute(
+                        "INSERT INTO chat_settings (chat_id, key, value) VALUES (?1, ?2, ?3)
+                         ON CONFLICT(chat_id, key) DO UPDATE SET value = excluded.value",
+                        params![chat_id, key, value],
+                    )?,
+                    None => tx.execute(
+                        "DELETE FROM chat_settings WHERE chat_id = ?1 AND key = ?2",
+                        params![chat_id, key],
+                    )?,
+                };
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn load_captchas(&self) -> Result<Vec<PendingCaptcha>> {
+        self.with_conn(|conn| {
+            let mut stmt: rusqlite::Statement<'_> =
+                conn.prepare("SELECT chat_id, user_id, message_id, answer, expires_at, passed FROM pending_captcha")?;
+            let rows = stmt.query_map([], |r| {
+                Ok(PendingCaptcha {
+                    chat_id: r.get(0)?,
+                    user_id: r.get(1)?,
+                    message_id: r.get(2)?,
+                    answer: r.get(3)?,
+                    expires_at: r.get(4)?,
+                    passed: r.get(5)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn save_captcha(&self, captcha: PendingCaptcha) -> Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO pending_captcha (chat_id, user_id, message_id, answer, expires_at, passed)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    captcha.chat_id,
+                    captcha.user_id,
+                    captcha.message_id,
+                    captcha.answer,
+                    captcha.expires_at,
+                    captcha.passed
+                ],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_captcha(&self, chat_id: i64, user_id: i64) -> Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM pending_captcha WHERE chat_id = ?1 AND user_id = ?2",
+                params![chat_id, user_id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn import_whitelist_file(&self, path: &Path) -> Result<usize> {
+        let imported: Option<String> = self
+            .with_conn(|conn| {
+                conn.query_row("SELECT value FROM meta WHERE key = 'whitelist_file_imported'", [], |r| r.get(0))
+                    .optional()
+            })
+            .await?;
+        if imported.is_some() || !path.exists() {
+            return Ok(0);
+        }
+
+        // Непрочитанный файл не отмечается импортированным: иначе вайтлист потерялся бы навсегда
+        let content: String = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Не удалось прочитать файл вайтлиста {}", path.display()))?;
+        let path: PathBuf = path.to_path_buf();
+        self.with_conn(move |conn| {
+            let tx: rusqlite::Transaction<'_> = conn.transaction()?;
+            let mut count: usize = 0;
+            for user_id in content.lines().filter_map(|l| l.trim().parse::<i64>().ok()) {
+                count += tx.execute(
+                    "INSERT OR IGNORE INTO whitelist (user_id, added_at, added_by, reason) VALUES (?1, ?2, 'import', 'import')",
+                    params![user_id, unix_now()],
+                )?;
+            }
+            tx.execute(
+                "INSERT INTO meta (key, value) VALUES ('whitelist_file_imported', ?1)",
+                params![path.display().to_string()],
+            )?;
+            tx.commit()?;
+            Ok(count)
+        })
+        .await
+    }
+
+    async fn save_pending_update(&self, update_id: i64, payload: String) -> Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO pending_updates (update_id, payload, received_at) VALUES (?1, ?2, ?3)",
+                params![update_id, payload, unix_now()],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_pending_update(&self, update_id: i64) -> Result<()> {
+        self.with_conn(move |conn| conn.execute("DELETE FROM pending_updates WHERE update_id = ?1", params![update_id]))
+            .await?;
+        Ok(())
+    }
+
+    async fn load_pending_updates(&self) -> Result<Vec<(i64, String)>> {
+        self.with_conn(|conn| {
+            let mut stmt: rusqlite::Statement<'_> =
+                conn.prepare("SELECT update_id, payload FROM pending_updates ORDER BY update_id")?;
+            let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            rows.collect()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> SqliteStorage {
+        SqliteStorage::open(Path::new(":memory:")).unwrap()
+    }
+
+    /// Временный файл, который удаляется вместе с файлами журнала
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("tg_anti_spam_{}_{name}", std::process::id())))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                std::fs::remove_file(format!("{}{suffix}", self.0.display())).ok();
+            }
+        }
+    }
+
+    fn user_version(storage: &SqliteStorage) -> usize {
+        storage.conn.lock().unwrap().pragma_query_value(None, "user_version", |r| r.get(0)).unwrap()
+    }
+
+    #[test]
+    fn applies_all_migrations_to_new_database() {
+        assert_eq!(user_version(&memory()), MIGRATIONS.len());
+    }
+
+    #[tokio::test]
+    async fn upgrades_old_database_and_keeps_data() {
+        let database: TempPath = TempPath::new("upgrade.db");
+        {
+            let conn: Connection = Connection::open(&database.0).unwrap();
+            for migration in &MIGRATIONS[..3] {
+                conn.execute_batch(migration).unwrap();
+            }
+            conn.execute_batch(
+                "INSERT INTO whitelist (user_id, added_at, reason) VALUES (1, 100, 'admin @root'), (2, 100, 'auto');
+                 INSERT INTO user_counters (user_id, ham, spam) VALUES (3, 5, 1);
+                 INSERT INTO pending_captcha VALUES (-100, 4, 10, '7', 200);
+                 PRAGMA user_version = 3;",
+            )
+            .unwrap();
+        }
+
+        let storage: SqliteStorage = SqliteStorage::open(&database.0).unwrap();
+        assert_eq!(user_version(&storage), MIGRATIONS.len());
+        let whitelist: HashMap<i64, WhitelistEntry> = storage.load_whitelist().await.unwrap();
+        assert_eq!((whitelist[&1].added_by.as_str(), whitelist[&1].reason.as_str()), ("@root", "manual"));
+        assert_eq!((whitelist[&2].added_by.as_str(), whitelist[&2].reason.as_str()), ("auto", "auto"));
+        assert_eq!(whitelist[&2].expires_at, None);
+        // Известный до обновления пользователь не новичок
+        assert_eq!(storage.user_progress(3).await.unwrap(), (5, 0));
+        assert!(!storage.load_captchas().await.unwrap()[0].passed);
+
+        // Повторное открытие ничего не применяет заново
+        drop(storage);
+        assert_eq!(user_version(&SqliteStorage::open(&database.0).unwrap()), MIGRATIONS.len());
+    }
+
+    #[tokio::test]
+    async fn imports_whitelist_file_once() {
+        let file: TempPath = TempPath::new("whitelist.txt");
+        let storage: SqliteStorage = memory();
+        // Файла ещё нет: импорт не отмечается и случится, когда он появится
+        assert_eq!(storage.import_whitelist_file(&file.0).await.unwrap(), 0);
+
+        std::fs::write(&file.0, "1\n 2 \nне id\n2\n").unwrap();
+        assert_eq!(storage.import_whitelist_file(&file.0).await.unwrap(), 2);
+        assert_eq!(storage.load_whitelist().await.unwrap()[&1].added_by, "import");
+
+        // Удалённый из вайтлиста пользователь не возвращается при следующем запуске
+        storage.remove_from_whitelist(1).await.unwrap();
+        std::fs::write(&file.0, "1\n3\n").unwrap();
+        assert_eq!(storage.import_whitelist_file(&file.0).await.unwrap(), 0);
+        let whitelist: HashMap<i64, WhitelistEntry> = storage.load_whitelist().await.unwrap();
+        assert_eq!(whitelist.keys().copied().collect::<Vec<i64>>(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn failed_whitelist_read_is_retried() {
+        let dir: PathBuf = std::env::temp_dir();
+        let storage: SqliteStorage = memory();
+        // Каталог вместо файла не читается
+        assert!(storage.import_whitelist_file(&dir).await.is_err());
+        let imported: Option<String> = storage
+            .with_conn(|conn| {
+                conn.query_row("SELECT value FROM meta WHERE key = 'whitelist_file_imported'", [], |r| r.get(0))
+                    .optional()
+            })
+            .await
+            .unwrap();
+        assert_eq!(imported, None);
+    }
+
+    #[tokio::test]
+    async fn counts_and_relabels_messages() {
+        let storage: SqliteStorage = memory();
+        assert_eq!(storage.increment_counter(1, false).await.unwrap(), 1);
+        assert_eq!(storage.increment_counter(1, false).await.unwrap(), 2);
+        assert_eq!(storage.increment_counter(1, true).await.unwrap(), 1);
+        assert_eq!(storage.relabel_counter(1, true).await.unwrap(), (1, 2));
+        assert_eq!(storage.relabel_counter(1, false).await.unwrap(), (2, 1));
+        // Счётчик не уходит в минус
+        assert_eq!(storage.relabel_counter(2, false).await.unwrap(), (1, 0));
+        assert_eq!(storage.relabel_counter(2, true).await.unwrap(), (0, 1));
+
+        storage.add_to_whitelist(WhitelistEntry::new(1, "auto", "auto", Some(30))).await.unwrap();
+        assert!(storage.remove_from_whitelist(1).await.unwrap());
+        assert!(!storage.remove_from_whitelist(1).await.unwrap());
+        assert_eq!(storage.user_progress(1).await.unwrap().0, 0);
+    }
+
+    #[tokio::test]
+    async fn tracks_first_seen() {
+        let storage: SqliteStorage = memory();
+        let (ham, first_seen) = storage.user_progress(1).await.unwrap();
+        assert_eq!(ham, 0);
+        assert!(first_seen > 0);
+        storage.increment_counter(1, false).await.unwrap();
+        assert_eq!(storage.user_progress(1).await.unwrap(), (1, first_seen));
+    }
+
+    #[tokio::test]
+    async fn stores_whitelist_entries_with_expiry() {
+        let storage: SqliteStorage = memory();
+        let entry: WhitelistEntry = WhitelistEntry::new(5, "manual", "@admin", Some(2));
+        storage.add_to_whitelist(entry.clone()).await.unwrap();
+        let loaded: WhitelistEntry = storage.load_whitelist().await.unwrap().remove(&5).unwrap();
+        assert_eq!((loaded.added_by.as_str(), loaded.reason.as_str()), ("@admin", "manual"));
+        assert_eq!(loaded.expires_at, Some(entry.added_at + 2 * 86_400));
+        assert!(!loaded.is_expired(entry.added_at));
+        assert!(loaded.is_expired(entry.added_at + 2 * 86_400));
+    }
+
+    #[tokio::test]
+    async fn saves_loads_and_removes_pending_updates() {
+        let storage: SqliteStorage = memory();
+        storage.save_pending_update(11, "{\"update_id\":11}".to_string()).await.unwrap();
+        storage.save_pending_update(10, "{\"update_id\":10}".to_string()).await.unwrap();
+        storage.save_pending_update(11, "{\"update_id\":11,\"x\":1}".to_string()).await.unwrap();
+        assert_eq!(
+            storage.load_pending_updates().await.unwrap(),
+            vec![(10, "{\"update_id\":10}".to_string()), (11, "{\"update_id\":11,\"x\":1}".to_string())]
+        );
+        storage.remove_pending_update(10).await.unwrap();
+        storage.remove_pending_update(99).await.unwrap();
+        assert_eq!(storage.load_pending_updates().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn changes_chat_settings_together() {
+        let storage: SqliteStorage = memory();
+        let set = |key: &str, value: Option<&str>| (key.to_string(), value.map(str::to_string));
+        storage.set_chat_settings(-1, vec![set("spam_threshold", Some("80")), set("review_threshold", Some("60"))]).await.unwrap();
+        storage.set_chat_settings(-1, vec![set("spam_threshold", Some("90")), set("review_threshold", None)]).await.unwrap();
+        assert_eq!(storage.load_chat_settings().await.unwrap()[&-1], vec![("spam_threshold".to_string(), "90".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn stores_captchas() {
+        let storage: SqliteStorage = memory();
+        let captcha: PendingCaptcha =
+            PendingCaptcha { chat_id: -1, user_id: 2, message_id: 3, answer: "🍎".to_string(), expires_at: 100, passed: false };
+        storage.save_captcha(captcha.clone()).await.unwrap();
+        storage.save_captcha(PendingCaptcha { passed: true, expires_at: 160, ..captcha }).await.unwrap();
+        let loaded: Vec<PendingCaptcha> = storage.load_captchas().await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert!(loaded[0].passed);
+        assert_eq!((loaded[0].answer.as_str(), loaded[0].expires_at), ("🍎", 160));
+        storage.remove_captcha(-1, 2).await.unwrap();
+        assert!(storage.load_captchas().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn finds_latest_moderated_text() {
+        let storage: SqliteStorage = memory();
+        let event = |text: Option<&str>| ModerationEvent {
+            chat_id: -1,
+            user_id: 2,
+            message_id: Some(3),
+            action: "review".to_string(),
+            text: text.map(str::to_string),
+            ..Default::default()
+        };
+        storage.record_moderation(event(Some("первый"))).await.unwrap();
+        storage.record_moderation(event(Some("второй"))).await.unwrap();
+        storage.record_moderation(event(None)).await.unwrap();
+        assert_eq!(storage.moderated_text(-1, 3).await.unwrap().as_deref(), Some("второй"));
+        assert_eq!(storage.moderated_text(-1, 4).await.unwrap(), None);
+    }
+}
@@ -1,12 +1,58 @@
 use anyhow::Result;
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{de::IgnoredAny, Deserialize};
+
+/// Типы обновлений, которые бот запрашивает у Telegram (long polling и вебхук)
+pub const ALLOWED_UPDATES: &[&str] = &["message", "edited_message", "callback_query", "chat_member"];
 
 /// Структуры для работы с Telegram Bot API
 #[derive(Deserialize, Debug)]
 pub struct TgUpdate {
     pub update_id: i64,
     pub message: Option<Message>,
+    /// Новая версия ранее отправленного сообщения
+    pub edited_message: Option<Message>,
+    pub callback_query: Option<CallbackQuery>,
+    /// Изменение статуса участника чата; приходит, только если бот администратор
+    pub chat_member: Option<ChatMemberUpdated>,
+}
+
+/// Изменение статуса участника чата (вступление, выход, ограничения)
+#[derive(Deserialize, Debug)]
+pub struct ChatMemberUpdated {
+    pub chat: Chat,
+    pub old_chat_member: ChatMember,
+    pub new_chat_member: ChatMember,
+}
+
+/// Участник чата. `status`: `creator`, `administrator`, `member`, `restricted`, `left`, `kicked`
+#[derive(Deserialize, Debug)]
+pub struct ChatMember {
+    pub status: String,
+    pub user: User,
+    /// Для `restricted`: состоит ли пользователь в чате
+    #[serde(default)]
+    pub is_member: bool,
+}
+
+impl ChatMember {
+    /// Пользователь состоит в чате (в том числе с ограничениями)
+    pub fn in_chat(&self) -> bool {
+        match self.status.as_str() {
+            "creator" | "administrator" | "member" => true,
+            "restricted" => self.is_member,
+            _ => false,
+        }
+    }
+}
+
+/// Нажатие на inline-кнопку под сообщением бота
+#[derive(Deserialize, Debug)]
+pub struct CallbackQuery {
+    pub id: String,
+    pub from: User,
+    pub message: Option<Message>,
+    pub data: Option<String>,
 }
 
 /// Сообщение Telegram
@@ -14,8 +60,190 @@ pub struct TgUpdate {
 pub struct Message {
     pub message_id: i64,
     pub from: Option<User>,
+    /// Чат, от имени которого отправлено сообщение (анонимные администраторы, каналы)
+    pub sender_chat: Option<Chat>,
     pub chat: Chat,
     pub text: Option<String>,
+    /// Подпись к фото, видео, документу и другим медиа
+    pub caption: Option<String>,
+    pub poll: Option<Poll>,
+    /// Откуда переслано сообщение
+    pub forward_origin: Option<MessageOrigin>,
+    pub document: Option<Document>,
+    pub photo: Option<IgnoredAny>,
+    pub video: Option<IgnoredAny>,
+    pub animation: Option<IgnoredAny>,
+    pub audio: Option<IgnoredAny>,
+    pub voice: Option<IgnoredAny>,
+    pub video_note: Option<IgnoredAny>,
+    pub sticker: Option<IgnoredAny>,
+    pub contact: Option<Contact>,
+    pub venue: Option<Venue>,
+    /// Пересланная история; содержимое Bot API не передаёт, важен сам факт и её автор
+    pub story: Option<Story>,
+    /// Разметка текста: ссылки, упоминания, скрытые гиперссылки
+    #[serde(default)]
+    pub entities: Vec<MessageEntity>,
+    #[serde(default)]
+    pub caption_entities: Vec<MessageEntity>,
+    /// Inline-кнопки; у пересланных рекламных постов в них часто спрятаны ссылки
+    pub reply_markup: Option<InlineKeyboardMarkup>,
+    /// Сообщение, на которое отвечают (для команд вида «ответом на сообщение»)
+    pub reply_to_message: Option<Box<Message>>,
+    /// Служебное сообщение о новых участниках
+    #[serde(default)]
+    pub new_chat_members: Vec<User>,
+}
+
+impl Message {
+    /// Сообщение содержит фото, видео, файл, стикер или другое медиа
+    pub fn has_media(&self) -> bool {
+        self.document.is_some()
+            || self.photo.is_some()
+            || self.video.is_some()
+            || self.animation.is_some()
+            || self.audio.is_some()
+            || self.voice.is_some()
+            || self.video_note.is_some()
+            || self.sticker.is_some()
+            || self.story.is_some()
+    }
+
+    /// Весь видимый пользователю текст сообщения для проверки на спам:
+    /// источник пересылки, текст или подпись, опрос, кнопки, имя файла, контакт, место.
+    pub fn content_text(&self) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        // Источник идёт первым, чтобы не потеряться при обрезке длинного текста
+        if let Some(origin) = self.forward_origin.as_ref().and_then(MessageOrigin::describe) {
+            parts.push(format!("Переслано из: {origin}"));
+        }
+        if let Some(story) = self.story.as_ref() {
+            let author: String = story.chat.title.clone().or_else(|| story.chat.username.clone()).unwrap_or_default();
+            parts.push(format!("История {author}"));
+        }
+        parts.extend(self.text.clone());
+        parts.extend(self.caption.clone());
+        if let Some(poll) = self.poll.as_ref() {
+            parts.push(poll.question.clone());
+            parts.extend(poll.options.iter().map(|o| o.text.clone()));
+        }
+        if let Some(markup) = self.reply_markup.as_ref() {
+            parts.extend(markup.inline_keyboard.iter().flatten().map(|b| b.text.clone()));
+        }
+        if let Some(file_name) = self.document.as_ref().and_then(|d| d.file_name.clone()) {
+            parts.push(format!("Файл: {file_name}"));
+        }
+        if let Some(contact) = self.contact.as_ref() {
+            let name: String = [Some(contact.first_name.clone()), contact.last_name.clone()]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" ");
+            parts.push(format!("Контакт: {name} {}", contact.phone_number));
+        }
+        if let Some(venue) = self.venue.as_ref() {
+            parts.push(format!("Место: {}, {}", venue.title, venue.address));
+        }
+        parts
+            .into_iter()
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Элемент разметки текста. `offset` и `length` считаются в UTF-16 code units.
+#[derive(Deserialize, Debug)]
+pub struct MessageEntity {
+    #[serde(rename = "type")]
+    pub r#type: String,
+    pub offset: usize,
+    pub length: usize,
+    /// Адрес для `text_link`
+    pub url: Option<String>,
+    /// Пользователь для `text_mention` (упоминание без username)
+    pub user: Option<User>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InlineKeyboardMarkup {
+    #[serde(default)]
+    pub inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InlineKeyboardButton {
+    pub text: String,
+    pub url: Option<String>,
+}
+
+/// Опрос
+#[derive(Deserialize, Debug)]
+pub struct Poll {
+    pub question: String,
+    #[serde(default)]
+    pub options: Vec<PollOption>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PollOption {
+    pub text: String,
+}
+
+/// Источник пересланного сообщения. Поля зависят от `type`: `user`, `hidden_user`, `chat`, `channel`.
+#[derive(Deserialize, Debug)]
+pub struct MessageOrigin {
+    pub sender_user: Option<User>,
+    pub sender_user_name: Option<String>,
+    pub sender_chat: Option<Chat>,
+    /// Канал, из которого переслано сообщение
+    pub chat: Option<Chat>,
+}
+
+impl MessageOrigin {
+    /// Название канала или чата, `@username` или имя отправителя
+    fn describe(&self) -> Option<String> {
+        let chat: Option<&Chat> = self.chat.as_ref().or(self.sender_chat.as_ref());
+        if let Some(chat) = chat {
+            let username: Option<String> = chat.username.as_ref().map(|u| format!("@{u}"));
+            return match (chat.title.clone(), username) {
+                (Some(title), Some(username)) => Some(format!("{title} ({username})")),
+                (title, username) => title.or(username),
+            };
+        }
+        self.sender_user
+            .as_ref()
+            .and_then(|u| u.username.as_ref().map(|name| format!("@{name}")))
+            .or_else(|| self.sender_user_name.clone())
+    }
+}
+
+/// Документ (файл)
+#[derive(Deserialize, Debug)]
+pub struct Document {
+    pub file_name: Option<String>,
+}
+
+/// Контакт, отправленный в чат
+#[derive(Deserialize, Debug)]
+pub struct Contact {
+    pub phone_number: String,
+    pub first_name: String,
+    pub last_name: Option<String>,
+}
+
+/// Место на карте с названием
+#[derive(Deserialize, Debug)]
+pub struct Venue {
+    pub title: String,
+    pub address: String,
+}
+
+/// Пересланная история
+#[derive(Deserialize, Debug)]
+pub struct Story {
+    pub chat: Chat,
 }
 
 /// Пользователь Telegram
@@ -23,6 +251,8 @@ pub struct Message {
 pub struct User {
     pub id: i64,
     pub is_bot: bool,
+    #[serde(default)]
+    pub first_name: String,
     pub username: Option<String>,
 }
 
@@ -33,6 +263,9 @@ pub struct Chat {
     #[serde(rename = "type")]
     #[allow(dead_code)]
     pub r#type: String,
+    pub title: Option<String>,
+    /// Username публичного чата или канала
+    pub username: Option<String>,
 }
 
 /// Обёртка ответа Telegram Bot API
@@ -41,6 +274,34 @@ pub struct TgResponse<T> {
     pub result: T 
 }
 
+/// Полный ответ Telegram Bot API с признаком успеха и описанием ошибки
+#[derive(Deserialize, Debug)]
+struct TgApiReply {
+    ok: bool,
+    #[serde(default)]
+    result: serde_json::Value,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+/// Вызывает метод Bot API и возвращает `result`.
+/// В отличие от `send_message`, ошибка Telegram (например, нет прав администратора) возвращается как `Err`.
+async fn call_method(
+    client: &Client,
+    base_url: &str,
+    method: &str,
+    payload: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let url: String = format!("{base_url}/{method}");
+    let resp: reqwest::Response = client.post(&url).json(&payload).send().await?;
+    let status: reqwest::StatusCode = resp.status();
+    let reply: TgApiReply = resp.json().await?;
+    if !reply.ok {
+        anyhow::bail!("{method} HTTP {status}: {}", reply.description.unwrap_or_default());
+    }
+    Ok(reply.result)
+}
+
 
 /// Отправляет текстовое сообщение через Telegram Bot API.
 pub async fn send_message(
@@ -63,6 +324,161 @@ pub async fn send_message(
     Ok(())
 }
 
+/// Отправляет сообщение с inline-клавиатурой и возвращает его message_id.
+pub async fn send_message_with_keyboard(
+    client: &Client,
+    base_url: &str,
+    chat_id: i64,
+    text: &str,
+    inline_keyboard: serde_json::Value,
+    reply_to_message_id: Option<i64>,
+) -> Result<i64> {
+    let mut payload: serde_json::Value = serde_json::json!({
+        "chat_id": chat_id,
+        "text": text,
+        "reply_markup": { "inline_keyboard": inline_keyboard },
+    });
+    if let Some(mid) = reply_to_message_id {
+        payload["reply_parameters"] = serde_json::json!({ "message_id": mid, "allow_sending_without_reply": true });
+    }
+    let result: serde_json::Value = call_method(client, base_url, "sendMessage", payload).await?;
+    Ok(result.get("message_id").and_then(|m| m.as_i64()).unwrap_or_default())
+}
+
+/// Заменяет текст сообщения бота. Inline-клавиатура при этом убирается.
+pub async fn edit_message_text(
+    client: &Client,
+    base_url: &str,
+    chat_id: i64,
+    message_id: i64,
+    text: &str,
+) -> Result<()> {
+    call_method(
+        client,
+        base_url,
+        "editMessageText",
+        serde_json::json!({ "chat_id": chat_id, "message_id": message_id, "text": text }),
+    ).await?;
+    Ok(())
+}
+
+/// Подтверждает получение нажатия на кнопку, показывая пользователю короткое уведомление.
+pub async fn answer_callback_query(client: &Client, base_url: &str, callback_query_id: &str, text: &str) -> Result<()> {
+    call_method(
+        client,
+        base_url,
+        "answerCallbackQuery",
+        serde_json::json!({ "callback_query_id": callback_query_id, "text": text }),
+    ).await?;
+    Ok(())
+}
+
+/// Удаляет сообщение из чата. Требует права администратора на удаление сообщений.
+pub async fn delete_message(client: &Client, base_url: &str, chat_id: i64, message_id: i64) -> Result<()> {
+    call_method(
+        client,
+        base_url,
+        "deleteMessage",
+        serde_json::json!({ "chat_id": chat_id, "message_id": message_id }),
+    ).await?;
+    Ok(())
+}
+
+/// Запрещает пользователю писать в чат до `until_date` (unix time).
+/// Значение `0` или время более чем через 366 дней означает бессрочное ограничение.
+pub async fn restrict_chat_member(
+    client: &Client,
+    base_url: &str,
+    chat_id: i64,
+    user_id: i64,
+    until_date: i64,
+) -> Result<()> {
+    call_method(
+        client,
+        base_url,
+        "restrictChatMember",
+        serde_json::json!({
+            "chat_id": chat_id,
+            "user_id": user_id,
+            "until_date": until_date,
+            "use_independent_chat_permissions": true,
+            "permissions": {
+                "can_send_messages": false,
+                "can_send_audios": false,
+                "can_send_documents": false,
+                "can_send_photos": false,
+                "can_send_videos": false,
+                "can_send_video_notes": false,
+                "can_send_voice_notes": false,
+                "can_send_polls": false,
+                "can_send_other_messages": false,
+                "can_add_web_page_previews": false,
+            },
+        }),
+    ).await?;
+    Ok(())
+}
+
+/// Снимает ограничения, наложенные `restrict_chat_member`: участник получает права,
+/// которые чат даёт всем участникам (`getChat`). Если в чате, например, запрещены опросы,
+/// снятие мьюта их не разрешит.
+pub async fn lift_chat_member_restrictions(client: &Client, base_url: &str, chat_id: i64, user_id: i64) -> Result<()> {
+    let chat: serde_json::Value = call_method(
+        client,
+        base_url,
+        "getChat",
+        serde_json::json!({ "chat_id": chat_id }),
+    ).await?;
+    let Some(permissions) = chat.get("permissions").cloned() else {
+        anyhow::bail!("getChat не вернул права участников чата {chat_id}");
+    };
+    call_method(
+        client,
+        base_url,
+        "restrictChatMember",
+        serde_json::json!({
+            "chat_id": chat_id,
+            "user_id": user_id,
+            "use_independent_chat_permissions": true,
+            "permissions": permissions,
+        }),
+    ).await?;
+    Ok(())
+}
+
+/// Банит пользователя в чате и при `revoke_messages` удаляет все его сообщения.
+pub async fn ban_chat_member(
+    client: &Client,
+    base_url: &str,
+    chat_id: i64,
+    user_id: i64,
+    revoke_messages: bool,
+) -> Result<()> {
+    call_method(
+        client,
+        base_url,
+        "banChatMember",
+        serde_json::json!({
+            "chat_id": chat_id,
+            "user_id": user_id,
+            "revoke_messages": revoke_messages,
+        }),
+    ).await?;
+    Ok(())
+}
+
+/// Снимает бан с пользователя. С `only_if_banned` не трогает тех, кто не забанен.
+/// Вместе с `ban_chat_member` используется для «кика» без постоянного бана.
+pub async fn unban_chat_member(client: &Client, base_url: &str, chat_id: i64, user_id: i64) -> Result<()> {
+    call_method(
+        client,
+        base_url,
+        "unbanChatMember",
+        serde_json::json!({ "chat_id": chat_id, "user_id": user_id, "only_if_banned": true }),
+    ).await?;
+    Ok(())
+}
+
 /// Отключает вебхук у бота, чтобы работал long polling.
 pub async fn delete_webhook(client: &Client, base_url: &str) -> Result<()> {
     let url: String = format!("{base_url}/deleteWebhook");
@@ -70,8 +486,44 @@ pub async fn delete_webhook(client: &Client, base_url: &str) -> Result<()> {
     Ok(())
 }
 
+/// Регистрирует вебхук. Telegram будет присылать `secret_token` в заголовке
+/// `X-Telegram-Bot-Api-Secret-Token` каждого запроса.
+pub async fn set_webhook(client: &Client, base_url: &str, url: &str, secret_token: &str) -> Result<()> {
+    call_method(
+        client,
+        base_url,
+        "setWebhook",
+        serde_json::json!({
+            "url": url,
+            "secret_token": secret_token,
+            "allowed_updates": ALLOWED_UPDATES,
+            "drop_pending_updates": false,
+        }),
+    ).await?;
+    Ok(())
+}
+
+/// Проверяет через `getChat`, что бот видит чат или пользователя. Для пользователя это значит,
+/// что он начал диалог с ботом, иначе бот не сможет ему написать.
+pub async fn get_chat(client: &Client, base_url: &str, chat_id: i64) -> Result<()> {
+    call_method(client, base_url, "getChat", serde_json::json!({ "chat_id": chat_id })).await?;
+    Ok(())
+}
+
+/// Возвращает статус участника чата: `creator`, `administrator`, `member`, `restricted`, `left` или `kicked`.
+pub async fn get_chat_member_status(client: &Client, base_url: &str, chat_id: i64, user_id: i64) -> Result<String> {
+    let result: serde_json::Value = call_method(
+        client,
+        base_url,
+        "getChatMember",
+        serde_json::json!({ "chat_id": chat_id, "user_id": user_id }),
+    ).await?;
+    Ok(result.get("status").and_then(|s| s.as_str()).unwrap_or_default().to_string())
+}
+
 /// Проверяет корректность токена, запрашивая getMe у Telegram Bot API.
-pub async fn get_me(client: &Client, base_url: &str) -> Result<()> {
+/// Возвращает username бота.
+pub async fn get_me(client: &Client, base_url: &str) -> Result<Option<String>> {
     #[derive(Deserialize)]
     struct Me { 
         id: i64, 
@@ -81,22 +533,22 @@ pub async fn get_me(client: &Client, base_url: &str) -> Result<()> {
     let resp: reqwest::Response = client.get(&url).send().await?;
     let parsed: TgResponse<Me> = resp.json().await?;
     log::info!("getMe: id={}, username={:?}", parsed.result.id, parsed.result.username);
-    Ok(())
+    Ok(parsed.result.username)
 }
 
-/// Получает обновления от Telegram Bot API
+/// Получает новые обновления без разбора: исходный JSON сохраняется до конца обработки
 pub async fn get_updates(
     client: &Client, 
     base_url: &str, 
     offset: i64
-) -> Result<TgResponse<Vec<TgUpdate>>> {
+) -> Result<TgResponse<Vec<serde_json::Value>>> {
     let url: String = format!("{base_url}/getUpdates");
     let resp: reqwest::Response = client
         .post(&url)
         .json(&serde_json::json!({
             "timeout": 60,
             "offset": offset,
-            "allowed_updates": ["message"],
+            "allowed_updates": ALLOWED_UPDATES,
         }))
         .send()
         .await?;
@@ -105,6 +557,6 @@ pub async fn get_updates(
         anyhow::bail!("getUpdates HTTP {}: {}", resp.status(), resp.text().await.unwrap_or_default());
     }
 
-    let parsed: TgResponse<Vec<TgUpdate>> = resp.json().await?;
+    let parsed: TgResponse<Vec<serde_json::Value>> = resp.json().await?;
     Ok(parsed)
 }
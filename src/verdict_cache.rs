@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{
+    actions::unix_now,
+    normalize::is_invisible,
+    spam_checker::{ClassifyInput, SpamClassifier, SpamVerdict},
+};
+
+/// Как часто изменённый кэш сохраняется на диск
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Сохранённая оценка
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedVerdict {
+    spam_score: u8,
+    notes: String,
+    /// Версия шаблона промпта, по которому получена оценка
+    #[serde(default)]
+    prompt_version: Option<String>,
+    /// Когда оценка получена; по нему считается срок жизни
+    stored_at: i64,
+    /// Когда оценка использовалась последней; по нему вытесняются старые записи
+    used_at: i64,
+}
+
+/// Кэш оценок классификаторов по нормализованному тексту с вытеснением давно не использованных
+/// записей и сроком жизни. С файлом переживает перезапуск бота.
+pub struct VerdictCache {
+    entries: Mutex<HashMap<String, CachedVerdict>>,
+    capacity: usize,
+    ttl_secs: i64,
+    path: Option<PathBuf>,
+    /// Есть несохранённые изменения
+    dirty: AtomicBool,
+}
+
+impl VerdictCache {
+    /// Создаёт кэш и загружает сохранённые оценки из файла, если он задан.
+    /// Повреждённый файл не мешает запуску: кэш начинается пустым.
+    pub async fn load(capacity: usize, ttl_minutes: u32, path: Option<PathBuf>) -> Self {
+        let mut entries: HashMap<String, CachedVerdict> = HashMap::new();
+        if let Some(path) = path.as_ref()
+            && let Ok(content) = fs::read_to_string(path).await
+        {
+            entries = serde_json::from_str(&content).unwrap_or_else(|e| {
+                log::warn!("Не удалось разобрать кэш оценок {}: {}. Начинаю с пустого.", path.display(), e);
+                HashMap::new()
+            });
+        }
+        let ttl_secs: i64 = i64::from(ttl_minutes) * 60;
+        let now: i64 = unix_now();
+        entries.retain(|_, v| now - v.stored_at < ttl_secs);
+        log::info!("Кэш оценок: {} записей", entries.len());
+
+        Self {
+            entries: Mutex::new(entries),
+            capacity,
+            ttl_secs,
+            path,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<CachedVerdict> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let now: i64 = unix_now();
+        let entry: &mut CachedVerdict = entries.get_mut(key)?;
+        if now - entry.stored_at >= self.ttl_secs {
+            entries.remove(key);
+            return None;
+        }
+        entry.used_at = now;
+        Some(entry.clone())
+    }
+
+    fn insert(&self, key: String, verdict: &SpamVerdict) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        // Полный перебор при вытеснении дешевле отдельной очереди при размерах кэша в тысячи записей
+        if entries.len() >= self.capacity
+            && !entries.contains_key(&key)
+            && let Some(oldest) = entries.iter().min_by_key(|(_, v)| v.used_at).map(|(k, _)| k.clone())
+        {
+            entries.remove(&oldest);
+        }
+        let now: i64 = unix_now();
+        entries.insert(key, CachedVerdict {
+            spam_score: verdict.spam_score,
+            notes: verdict.notes.clone(),
+            prompt_version: verdict.prompt_version.clone(),
+            stored_at: now,
+            used_at: now,
+        });
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Сохраняет кэш на диск, если он изменился и файл задан
+    pub async fn save(&self) -> Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let serialized: String = {
+            let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            serde_json::to_string(&*entries)?
+        };
+
+        // Пишем во временный файл и переименовываем, чтобы не оставить повреждённый кэш
+        let tmp: PathBuf = path.with_extension("tmp");
+        fs::write(&tmp, serialized).await?;
+        fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+}
+
+/// Фоновая задача: периодически сохраняет кэш оценок на диск
+pub async fn run_flush_loop(cache: Arc<VerdictCache>) {
+    loop {
+        tokio::time::sleep(FLUSH_INTERVAL).await;
+        if let Err(err) = cache.save().await {
+            log::warn!("Не удалось сохранить кэш оценок: {err:?}");
+        }
+    }
+}
+
+/// Нормализует текст для ключа кэша: регистр, пробелы, невидимые символы и эмодзи не учитываются
+pub fn normalize_for_cache(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .filter(|c| !is_invisible(*c))
+        .map(|c| if c.is_alphanumeric() || c.is_ascii_punctuation() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Классификатор с кэшем оценок. Одинаковые после нормализации сообщения с теми же настройками
+/// чата и разметкой оцениваются один раз — это разгружает LLM во время рейдов.
+pub struct CachedClassifier {
+    pub inner: Box<dyn SpamClassifier>,
+    pub cache: Arc<VerdictCache>,
+}
+
+impl CachedClassifier {
+    /// Ключ включает всё, от чего зависит ответ LLM, кроме самого отправителя
+    fn key(&self, input: &ClassifyInput) -> String {
+        [
+            self.inner.name(),
+            input.model.as_deref().unwrap_or_default(),
+            &input.template.version,
+            input.topic.as_deref().unwrap_or_default(),
+            input.prompt.as_deref().unwrap_or_default(),
+            input.language.as_deref().unwrap_or_default(),
+            &input.features.describe(),
+            &input.obfuscation.describe(),
+            &normalize_for_cache(&input.text),
+        ]
+        .join("\u{1F}")
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for CachedClassifier {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn classify(&self, input: &ClassifyInput) -> Result<SpamVerdict> {
+        let key: String = self.key(input);
+        if let Some(cached) = self.cache.get(&key) {
+            log::debug!("{}: оценка из кэша", self.name());
+            return Ok(SpamVerdict {
+                spam_score: cached.spam_score,
+                notes: cached.notes,
+                classifier: self.name().to_string(),
+                prompt_version: cached.prompt_version,
+            });
+        }
+        let verdict: SpamVerdict = self.inner.classify(input).await?;
+        self.cache.insert(key, &verdict);
+        Ok(verdict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verdict(score: u8) -> SpamVerdict {
+        SpamVerdict { spam_score: score, notes: "реклама".to_string(), classifier: "llm".to_string(), prompt_version: None }
+    }
+
+    #[test]
+    fn normalize_for_cache_ignores_case_spacing_and_emoji() {
+        assert_eq!(normalize_for_cache("  Пишите   В ЛС!\n"), "пишите в лс!");
+        assert_eq!(normalize_for_cache("🔥Заработок🔥 от 100к 💰"), "заработок от 100к");
+        assert_eq!(normalize_for_cache("за\u{200B}ра\u{00AD}боток"), "заработок");
+        assert_ne!(normalize_for_cache("пишите в лс"), normalize_for_cache("пишите в лс?"));
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_entry() {
+        let cache: VerdictCache = VerdictCache::load(2, 60, None).await;
+        cache.insert("a".to_string(), &verdict(10));
+        cache.insert("b".to_string(), &verdict(20));
+        cache.get("a");
+        cache.entries.lock().unwrap().get_mut("b").unwrap().used_at -= 20;
+        cache.insert("c".to_string(), &verdict(30));
+        assert_eq!(cache.get("a").map(|v| v.spam_score), Some(10));
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("c").map(|v| v.spam_score), Some(30));
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_dropped() {
+        let cache: VerdictCache = VerdictCache::load(10, 1, None).await;
+        cache.insert("a".to_string(), &verdict(10));
+        cache.entries.lock().unwrap().get_mut("a").unwrap().stored_at -= 60;
+        assert!(cache.get("a").is_none());
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use crate::{
+    dispatcher::Dispatcher,
+    telegram_api::{delete_webhook, set_webhook},
+};
+
+/// Заголовок, в котором Telegram передаёт секрет вебхука
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Всё, что нужно обработчику HTTP-запроса
+struct WebhookContext {
+    dispatcher: Arc<Dispatcher>,
+    secret: String,
+}
+
+/// Режим вебхука: поднимает HTTP-сервер (без TLS, за обратным прокси) и принимает обновления от Telegram.
+/// Вебхук регистрируется при старте, если задан `WEBHOOK_URL`, и снимается при остановке,
+/// если не выключен `WEBHOOK_DELETE_ON_SHUTDOWN` (другие экземпляры бота за прокси продолжают его обслуживать).
+/// Без `WEBHOOK_URL` сервер просто слушает порт, и обновления можно отправлять вручную.
+pub async fn run_webhook(dispatcher: Arc<Dispatcher>) -> Result<()> {
+    let client: reqwest::Client = dispatcher.client().clone();
+    let base_url: String = dispatcher.base_url().to_string();
+    let config = dispatcher.config();
+    let listen: SocketAddr = config.webhook_listen;
+    let path: String = config.webhook_path.clone();
+    let secret: String = config
+        .webhook_secret
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("Для режима вебхука нужен WEBHOOK_SECRET"))?;
+
+    let delete_on_shutdown: bool = config.webhook_delete_on_shutdown;
+
+    dispatcher.identify().await;
+    dispatcher.resume_pending().await;
+
+    let public_url: Option<String> = config.webhook_url.clone();
+    if let Some(url) = public_url.as_deref() {
+        set_webhook(&client, &base_url, url, &secret).await?;
+        log::info!("Вебхук зарегистрирован: {url}");
+    } else {
+        log::warn!("WEBHOOK_URL не задан: вебхук не регистрируется, ожидаю ручные запросы");
+    }
+
+    let app: Router = router(dispatcher, secret, &path);
+
+    let listener: tokio::net::TcpListener = tokio::net::TcpListener::bind(listen).await?;
+    log::info!("Слушаю вебхук на http://{listen}{path}");
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    if public_url.is_some() && delete_on_shutdown {
+        delete_webhook(&client, &base_url).await?;
+        log::info!("Вебхук снят");
+    }
+
+    Ok(())
+}
+
+/// HTTP-маршрут вебхука
+fn router(dispatcher: Arc<Dispatcher>, secret: String, path: &str) -> Router {
+    let ctx: Arc<WebhookContext> = Arc::new(WebhookContext { dispatcher, secret });
+    Router::new()
+        .route(path, post(receive_update))
+        .with_state(ctx)
+}
+
+/// Принимает одно обновление. Секрет проверяется до разбора тела. Обновление сохраняется в базе
+/// и ставится в очередь, после чего Telegram сразу получает 200: долгая обработка не держит
+/// соединение и не вызывает повторную доставку. Если сохранить обновление не удалось, ответ 500
+/// заставит Telegram доставить его снова.
+async fn receive_update(
+    State(ctx): State<Arc<WebhookContext>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let secret: &[u8] = headers.get(SECRET_HEADER).map(|v| v.as_bytes()).unwrap_or_default();
+    if !constant_time_eq(secret, ctx.secret.as_bytes()) {
+        log::warn!("Запрос к вебхуку с неверным секретом отклонён");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let Ok(payload) = serde_json::from_slice::<serde_json::Value>(&body) else {
+        log::warn!("Тело запроса к вебхуку не JSON");
+        return StatusCode::BAD_REQUEST;
+    };
+
+    // Порядок внутри чата и лимит параллельности обеспечивает диспетчер
+    match ctx.dispatcher.accept(payload).await {
+        Ok(()) => StatusCode::OK,
+        Err(err) => {
+            log::error!("{err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Сравнение, время которого не зависит от того, в каком байте строки различаются
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Завершается по Ctrl+C или SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        if let Ok(mut sig) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            sig.recv().await;
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    log::info!("Получен сигнал остановки");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::{config::Config, state::AppState};
+
+    /// Поднимает вебхук на свободном порту с базой `database` и возвращает его адрес
+    async fn serve(database: &Path) -> (String, Arc<Dispatcher>) {
+        let config: Config = Config::from_toml("telegram_bot_token = \"x\"").unwrap();
+        let state: AppState = AppState::for_tests(database).await;
+        let dispatcher: Arc<Dispatcher> =
+            Dispatcher::new(reqwest::Client::new(), "http://127.0.0.1:9".to_string(), state, config);
+
+        let listener: tokio::net::TcpListener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: String = format!("http://{}/telegram", listener.local_addr().unwrap());
+        let app: Router = router(dispatcher.clone(), "s3cret".to_string(), "/telegram");
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, dispatcher)
+    }
+
+    async fn post(url: &str, secret: Option<&str>, body: &str) -> u16 {
+        let mut request: reqwest::RequestBuilder = reqwest::Client::new().post(url).body(body.to_string());
+        if let Some(secret) = secret {
+            request = request.header(SECRET_HEADER, secret);
+        }
+        request.send().await.unwrap().status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_without_valid_secret() {
+        let (url, dispatcher) = serve(Path::new(":memory:")).await;
+        let update: &str = r#"{"update_id": 1}"#;
+        assert_eq!(post(&url, None, update).await, 401);
+        assert_eq!(post(&url, Some("wrong"), update).await, 401);
+        assert_eq!(post(&url, Some("s3cret!"), update).await, 401);
+        // Секрет проверяется раньше тела
+        assert_eq!(post(&url, Some("wrong"), "не JSON").await, 401);
+        assert!(dispatcher.state().storage.load_pending_updates().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_body() {
+        let (url, dispatcher) = serve(Path::new(":memory:")).await;
+        assert_eq!(post(&url, Some("s3cret"), "не JSON").await, 400);
+        assert!(dispatcher.state().storage.load_pending_updates().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn accepts_update_with_valid_secret() {
+        let (url, dispatcher) = serve(Path::new(":memory:")).await;
+        let update: &str = r#"{"update_id": 7}"#;
+        assert_eq!(post(&url, Some("s3cret"), update).await, 200);
+        // После обработки сохранённое обновление удаляется
+        for _ in 0..50 {
+            if dispatcher.state().storage.load_pending_updates().await.unwrap().is_empty() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("обновление не обработано");
+    }
+
+    #[tokio::test]
+    async fn refuses_update_that_could_not_be_saved() {
+        let database: PathBuf = std::env::temp_dir().join(format!("tg_anti_spam_webhook_{}.db", std::process::id()));
+        let (url, _dispatcher) = serve(&database).await;
+        rusqlite::Connection::open(&database).unwrap().execute_batch("DROP TABLE pending_updates").unwrap();
+        assert_eq!(post(&url, Some("s3cret"), r#"{"update_id": 7}"#).await, 500);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{suffix}", database.display())).ok();
+        }
+    }
+
+    #[test]
+    fn compares_secrets_fully() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cret "));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+    }
+}